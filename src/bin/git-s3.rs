@@ -0,0 +1,681 @@
+use anyhow::{anyhow, Result};
+use aws_sdk_s3::Client;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    io::{self, IsTerminal, Write},
+    path::Path,
+    process,
+    time::UNIX_EPOCH,
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::{error, info};
+
+use git_remote_s3::cache::BundleCache;
+use git_remote_s3::gc::{gc, parse_duration, prune, GcOptions, GcReport, DEFAULT_GRACE_PERIOD};
+use git_remote_s3::git_s3::{
+    fetch_from_s3, layout, list_ref_history, list_refs, read_reflog, resolve_heads, restore_ref,
+    write_head, GitRef, GitS3Settings, Layout, ZERO_SHA,
+};
+use git_remote_s3::s3::{self, create_client};
+use git_remote_s3::verify::{verify, Status, VerifyOptions};
+use git_remote_s3::{fastexport, git, log, packs};
+
+// Administration commands for S3 remotes, invoked as `git s3 <command>`.
+
+const USAGE: &str = "\
+usage: git s3 <command> [<args>]
+
+commands:
+    ls <remote>                                   list the remote refs with every head
+    heads <remote> <ref>                          list the heads of a remote ref, newest first
+    du <remote>                                   show the storage used by each ref
+    prune <remote> [--dry-run]                    delete stale heads that a branch includes
+    resolve <remote> <ref> [--merge | --pick=<sha> | --discard]
+                                                  merge the heads of a remote ref, or keep one
+    history <remote> [<ref>]                      list previous versions of the remote refs
+    restore <remote> <ref> <sha> [<version-id>]   restore a previous version as the current head
+    reflog <remote> <ref>                         show who updated a remote ref, and when
+    set-head <remote> <branch>                    set the default branch of the remote
+    cache [clear]                                 show or clear the local bundle cache
+    gc <remote> [--dry-run] [--grace-period=<t>]  delete objects the remote refs no longer need
+    verify <remote> [--sample=<n>]                check that every object downloads, decrypts and is valid
+";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    log::init_file_logging()?;
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    info!(?args, "Starting git-s3");
+
+    let result = match args.as_slice() {
+        ["ls", remote] => cmd_ls(remote).await,
+        ["heads", remote, name] => cmd_heads(remote, name).await,
+        ["du", remote] => cmd_du(remote).await,
+        ["prune", remote] => cmd_prune(remote, false).await,
+        ["prune", remote, "--dry-run"] => cmd_prune(remote, true).await,
+        ["resolve", remote, name] => cmd_resolve(remote, name, None).await,
+        ["resolve", remote, name, option] => match Resolution::parse(option) {
+            Some(resolution) => cmd_resolve(remote, name, Some(resolution)).await,
+            None => Err(anyhow!("unknown resolve option: {}", option)),
+        },
+        ["history", remote] => cmd_history(remote, None).await,
+        ["history", remote, name] => cmd_history(remote, Some(name)).await,
+        ["restore", remote, name, sha] => cmd_restore(remote, name, sha, None).await,
+        ["restore", remote, name, sha, version_id] => {
+            cmd_restore(remote, name, sha, Some(version_id)).await
+        }
+        ["reflog", remote, name] => cmd_reflog(remote, name).await,
+        ["set-head", remote, branch] => cmd_set_head(remote, branch).await,
+        ["cache"] => cmd_cache(false),
+        ["cache", "clear"] => cmd_cache(true),
+        ["gc", remote, options @ ..] => cmd_gc(remote, options).await,
+        ["verify", remote] => cmd_verify(remote, None).await,
+        ["verify", remote, sample] => match sample.strip_prefix("--sample=") {
+            Some(n) => match n.parse() {
+                Ok(n) => cmd_verify(remote, Some(n)).await,
+                Err(_) => Err(anyhow!("invalid sample size: {}", n)),
+            },
+            None => Err(anyhow!("unknown verify option: {}", sample)),
+        },
+        _ => {
+            eprint!("{}", USAGE);
+            process::exit(129);
+        }
+    };
+
+    if let Err(e) = &result {
+        error!(?e, "Command failed");
+    }
+    result
+}
+
+async fn connect(remote: &str) -> Result<(Client, GitS3Settings)> {
+    let settings = GitS3Settings::from_remote(remote)?;
+    let s3 = create_client(
+        settings.region().map(String::from),
+        settings.endpoint().map(String::from),
+    )
+    .await?;
+    Ok((s3, settings))
+}
+
+fn short_sha(sha: &str) -> &str {
+    &sha[..sha.len().min(7)]
+}
+
+fn format_time(nanos: i128) -> String {
+    OffsetDateTime::from_unix_timestamp_nanos(nanos)
+        .ok()
+        .and_then(|t| t.format(&Rfc3339).ok())
+        .unwrap_or_else(|| "?".to_string())
+}
+
+/// A head of a remote ref, as shown by `ls` and `heads`
+struct Head {
+    reference: GitRef,
+    /// Whether this is the newest head of the ref
+    latest: bool,
+    /// When the head was pushed, in nanoseconds since epoch
+    updated: i128,
+    /// Bytes stored for the head: its bundle and the objects next to it, or its pack
+    size: i64,
+    pusher: String,
+}
+
+/// Every head of every ref of the remote, newest first within each ref. With the
+/// pack layout, each ref has a single head, described by its pack, and with the
+/// fast-export layout by the last stream that updated it.
+async fn list_heads(s3: &Client, settings: &GitS3Settings) -> Result<BTreeMap<String, Vec<Head>>> {
+    let prefix = settings.key();
+    // Heads in order, with the object whose metadata names the pusher
+    let mut found: Vec<(Head, String)> = Vec::new();
+    let head = |reference: GitRef, latest, updated, size| Head {
+        reference,
+        latest,
+        updated,
+        size,
+        pusher: String::new(),
+    };
+
+    match layout(s3, settings).await? {
+        Layout::FastExport => {
+            let manifest = fastexport::read_manifest(s3, settings)
+                .await?
+                .unwrap_or_default();
+            let listing =
+                s3::list(s3, settings.bucket(), &format!("{}/fast-export/", prefix)).await?;
+            let objects: HashMap<&str, &s3::ObjectInfo> =
+                listing.iter().map(|o| (o.key.as_str(), o)).collect();
+
+            for (name, sha) in manifest.refs.iter() {
+                // The last stream that moved the ref
+                let Some(stream) = manifest.streams.iter().rev().find(|s| s.tips.contains(sha))
+                else {
+                    continue;
+                };
+                let key = fastexport::stream_path(prefix, &stream.name);
+                let (updated, size) = objects
+                    .get(key.as_str())
+                    .map_or((0, 0), |o| (o.last_modified, o.size));
+                let reference = GitRef {
+                    name: name.clone(),
+                    sha: sha.clone(),
+                };
+                found.push((head(reference, true, updated, size), key));
+            }
+        }
+        Layout::Packs => {
+            let manifest = packs::read_manifest(s3, settings)
+                .await?
+                .unwrap_or_default();
+            let listing = s3::list(s3, settings.bucket(), &format!("{}/packs/", prefix)).await?;
+            let objects: HashMap<&str, &s3::ObjectInfo> =
+                listing.iter().map(|o| (o.key.as_str(), o)).collect();
+
+            for m in manifest.refs.values() {
+                let key = packs::pack_path(prefix, &m.pack);
+                let (updated, size) = objects
+                    .get(key.as_str())
+                    .map_or((0, 0), |o| (o.last_modified, o.size));
+                let reference = GitRef {
+                    name: m.name.clone(),
+                    sha: m.sha.clone(),
+                };
+                found.push((head(reference, true, updated, size), key));
+            }
+        }
+        Layout::Bundles => {
+            let refs = list_refs(s3, settings).await?;
+            let listing = s3::list(s3, settings.bucket(), &format!("{}/refs/", prefix)).await?;
+            let sizes: HashMap<&str, i64> =
+                listing.iter().map(|o| (o.key.as_str(), o.size)).collect();
+            for remote_refs in refs.values() {
+                for (i, r) in remote_refs.all_refs().enumerate() {
+                    let size = r
+                        .object_keys(prefix)
+                        .iter()
+                        .filter_map(|key| sizes.get(key.as_str()))
+                        .sum();
+                    let key = r.reference.bundle_path(prefix);
+                    found.push((head(r.reference.clone(), i == 0, r.updated, size), key));
+                }
+            }
+        }
+    }
+
+    // One HEAD request per head, sent concurrently like the downloads of a fetch
+    let pushers: Vec<String> = stream::iter(found.iter().map(|(_, key)| key.clone()))
+        .map(|key| async move {
+            let o = s3::Key {
+                bucket: settings.bucket().to_owned(),
+                key,
+            };
+            let metadata = s3::metadata(s3, &o, None, settings.sse()?).await?;
+            Ok::<_, anyhow::Error>(metadata.get("pusher").cloned().unwrap_or("?".to_string()))
+        })
+        .buffered(settings.fetch_jobs()?)
+        .try_collect()
+        .await?;
+
+    let mut heads: BTreeMap<String, Vec<Head>> = BTreeMap::new();
+    for ((mut head, _), pusher) in found.into_iter().zip(pushers) {
+        head.pusher = pusher;
+        heads
+            .entry(head.reference.name.clone())
+            .or_default()
+            .push(head);
+    }
+    Ok(heads)
+}
+
+/// ls <remote>
+/// Lists every ref of the remote with all of its heads, the newest marked with
+/// `*`, with the time each was pushed, the bytes it takes and who pushed it.
+async fn cmd_ls(remote: &str) -> Result<()> {
+    let (s3, settings) = connect(remote).await?;
+    let heads = list_heads(&s3, &settings).await?;
+
+    for (name, ref_heads) in heads.iter() {
+        println!("{}", name);
+        for head in ref_heads {
+            println!(
+                "  {} {} {} {:>10} {}",
+                if head.latest { "*" } else { " " },
+                short_sha(&head.reference.sha),
+                format_time(head.updated),
+                head.size,
+                head.pusher
+            );
+        }
+    }
+    Ok(())
+}
+
+/// heads <remote> <ref>
+/// Lists the heads of a single ref, newest first, one per line with its full sha.
+async fn cmd_heads(remote: &str, name: &str) -> Result<()> {
+    let (s3, settings) = connect(remote).await?;
+    let mut heads = list_heads(&s3, &settings).await?;
+    let ref_heads = heads
+        .remove(name)
+        .ok_or_else(|| anyhow!("no such ref on the remote: {}", name))?;
+
+    for head in ref_heads {
+        println!(
+            "{} {} {:>10} {} {}",
+            head.reference.sha,
+            format_time(head.updated),
+            head.size,
+            head.pusher,
+            if head.latest { "latest" } else { "stale" }
+        );
+    }
+    Ok(())
+}
+
+/// du <remote>
+/// Shows the bytes stored for each ref, with all of its heads, and for the reflogs,
+/// the packs and everything else, then the total.
+async fn cmd_du(remote: &str) -> Result<()> {
+    let (s3, settings) = connect(remote).await?;
+    let prefix = format!("{}/", settings.key());
+    let objects = s3::list(&s3, settings.bucket(), &prefix).await?;
+
+    let mut usage: BTreeMap<String, i64> = BTreeMap::new();
+    for o in &objects {
+        let path = o.key.strip_prefix(&prefix).unwrap_or(&o.key);
+        let group = match path.split_once('/') {
+            // refs/<name>/<sha>.<suffix>
+            Some(("refs", _)) => path.rsplit_once('/').map_or(path, |(name, _)| name),
+            Some((dir, _)) => dir,
+            None => "other",
+        };
+        *usage.entry(group.to_string()).or_default() += o.size;
+    }
+
+    for (group, size) in usage.iter() {
+        println!("{:>10}  {}", size, group);
+    }
+    println!("{:>10}  total", usage.values().sum::<i64>());
+    Ok(())
+}
+
+/// prune <remote> [--dry-run]
+/// Deletes the stale heads that a current branch head includes, keeping those
+/// a tag bundle builds on or that can't be checked locally.
+async fn cmd_prune(remote: &str, dry_run: bool) -> Result<()> {
+    let (s3, settings) = connect(remote).await?;
+    let report = prune(&s3, &settings, dry_run).await?;
+    print_report(&report, dry_run);
+    Ok(())
+}
+
+/// How `resolve` leaves a ref with several heads
+enum Resolution {
+    /// Merge every head and push the merge as the only head
+    Merge,
+    /// Keep the head with the given sha, or sha prefix, and delete the others
+    Pick(String),
+    /// Keep the newest head and delete the stale ones
+    Discard,
+}
+
+impl Resolution {
+    fn parse(option: &str) -> Option<Resolution> {
+        match option {
+            "--merge" => Some(Resolution::Merge),
+            "--discard" => Some(Resolution::Discard),
+            _ => option
+                .strip_prefix("--pick=")
+                .map(|sha| Resolution::Pick(sha.to_string())),
+        }
+    }
+
+    /// Ask on the terminal, with the heads numbered from 1. `None` if the user quits.
+    fn prompt(heads: &[&GitRef]) -> Result<Option<Resolution>> {
+        loop {
+            print!(
+                "[m]erge them, keep only [1-{}], [d]iscard the stale heads or [q]uit? ",
+                heads.len()
+            );
+            io::stdout().flush()?;
+            let mut answer = String::new();
+            if io::stdin().read_line(&mut answer)? == 0 {
+                return Ok(None);
+            }
+            match answer.trim() {
+                "m" => return Ok(Some(Resolution::Merge)),
+                "d" => return Ok(Some(Resolution::Discard)),
+                "q" => return Ok(None),
+                n => match n.parse::<usize>() {
+                    Ok(n) if (1..=heads.len()).contains(&n) => {
+                        return Ok(Some(Resolution::Pick(heads[n - 1].sha.clone())))
+                    }
+                    _ => continue,
+                },
+            }
+        }
+    }
+}
+
+/// resolve <remote> <ref> [--merge | --pick=<sha> | --discard]
+/// Shows the heads of a ref with their commit summaries, fetching those missing
+/// locally, then merges them or keeps only one. Without an option, asks on the
+/// terminal. The merge is done without touching the working tree and fast-forwards
+/// the local branch, which must not have diverged from the heads.
+async fn cmd_resolve(remote: &str, name: &str, resolution: Option<Resolution>) -> Result<()> {
+    let (s3, settings) = connect(remote).await?;
+    let current_dir = env::current_dir()?;
+    let refs = list_refs(&s3, &settings).await?;
+    let remote_refs = refs
+        .get(name)
+        .ok_or_else(|| anyhow!("no such ref on the remote: {}", name))?;
+    let heads: Vec<&GitRef> = remote_refs.all_refs().map(|r| &r.reference).collect();
+    if heads.len() == 1 {
+        println!("{} has a single head", name);
+        return Ok(());
+    }
+
+    println!("{} has {} heads:", name, heads.len());
+    for (i, r) in remote_refs.all_refs().enumerate() {
+        if !git::object_exists(&r.reference.sha, &current_dir)? {
+            fetch_from_s3(&s3, &settings, &r.reference).await?;
+        }
+        println!(
+            "  {}) {} {} {}{}",
+            i + 1,
+            short_sha(&r.reference.sha),
+            format_time(r.updated),
+            git::commit_summary(&r.reference.sha, &current_dir)?,
+            if i == 0 { " (latest)" } else { "" }
+        );
+    }
+
+    let resolution = match resolution {
+        Some(resolution) => resolution,
+        None if io::stdin().is_terminal() => match Resolution::prompt(&heads)? {
+            Some(resolution) => resolution,
+            None => return Ok(()),
+        },
+        None => return Ok(()),
+    };
+
+    let keep = match resolution {
+        Resolution::Discard => heads[0].clone(),
+        Resolution::Pick(sha) => {
+            let mut matching = heads.iter().filter(|r| r.sha.starts_with(&sha));
+            match (matching.next(), matching.next()) {
+                (Some(r), None) => (*r).clone(),
+                (Some(_), Some(_)) => return Err(anyhow!("ambiguous head: {}", sha)),
+                (None, _) => return Err(anyhow!("no head of {} at {}", name, sha)),
+            }
+        }
+        Resolution::Merge => GitRef {
+            name: name.to_string(),
+            sha: merge_heads(name, &heads, &current_dir)?,
+        },
+    };
+
+    resolve_heads(&s3, &settings, remote_refs, &keep).await?;
+    // The ref is left with a single head, none of its heads is stale any more
+    for r in &heads {
+        git::delete_ref(&settings.stale_ref(name, &r.sha), &current_dir)?;
+    }
+    println!("{} is now {}", name, keep.sha);
+    if keep.sha != heads[0].sha {
+        println!(
+            "hint: run `git fetch {}` to update the remote-tracking branch",
+            remote
+        );
+    }
+    Ok(())
+}
+
+/// Merge the heads of a ref, newest first, into a single commit and fast-forward
+/// the local branch to it, so that it can be pushed.
+fn merge_heads(name: &str, heads: &[&GitRef], current_dir: &Path) -> Result<String> {
+    let branch = name.strip_prefix("refs/heads/").unwrap_or(name);
+    let mut merged = heads[0].sha.clone();
+    for head in &heads[1..] {
+        if git::is_ancestor(&head.sha, &merged, current_dir)? {
+            continue;
+        }
+        let tree = git::merge_tree(&merged, &head.sha, current_dir)?.ok_or_else(|| {
+            anyhow!(
+                "the heads of {} conflict, merge {} locally and push the result",
+                name,
+                short_sha(&head.sha)
+            )
+        })?;
+        let message = format!("Merge commit '{}' into {}", short_sha(&head.sha), branch);
+        merged = git::commit_tree(
+            &tree,
+            &[merged.clone(), head.sha.clone()],
+            &message,
+            current_dir,
+        )?;
+    }
+
+    match git::rev_parse(name, current_dir) {
+        Ok(local) if !git::is_ancestor(&local, &merged, current_dir)? => {
+            return Err(anyhow!(
+                "local {} has diverged from the remote heads, push or reset it first",
+                name
+            ));
+        }
+        _ => {}
+    }
+    if git::symbolic_ref("HEAD", current_dir).is_ok_and(|target| target == name) {
+        git::merge_ff_only(&merged, current_dir)?;
+    } else {
+        git::update_ref(name, &merged, current_dir)?;
+    }
+    Ok(merged)
+}
+
+/// history <remote> [<ref>]
+/// Lists every version of each ref's bundles on a versioned bucket, newest first,
+/// including versions left behind by force pushes and deletions.
+async fn cmd_history(remote: &str, name: Option<&str>) -> Result<()> {
+    let (s3, settings) = connect(remote).await?;
+    let history = list_ref_history(&s3, &settings).await?;
+
+    for (ref_name, versions) in history.iter() {
+        if name.is_some_and(|name| name != ref_name) {
+            continue;
+        }
+
+        println!("{}", ref_name);
+        for v in versions {
+            let state = match (v.is_deleted, v.is_latest) {
+                (true, _) => "deleted",
+                (false, true) => "current",
+                (false, false) => "",
+            };
+            println!(
+                "  {} {} {} {:>10} {}",
+                v.reference.sha,
+                v.version_id,
+                format_time(v.updated),
+                v.size,
+                state
+            );
+        }
+    }
+    Ok(())
+}
+
+/// restore <remote> <ref> <sha> [<version-id>]
+/// Makes a previous version of a ref's bundle the current head of the ref.
+async fn cmd_restore(remote: &str, name: &str, sha: &str, version_id: Option<&str>) -> Result<()> {
+    let (s3, settings) = connect(remote).await?;
+    let r = GitRef {
+        name: name.to_string(),
+        sha: sha.to_string(),
+    };
+
+    let restored = restore_ref(&s3, &settings, &r, version_id).await?;
+    println!("restored {} {} (version {})", r.name, r.sha, restored);
+    Ok(())
+}
+
+/// reflog <remote> <ref>
+/// Shows the remote reflog of a ref, newest first: one line per push or deletion
+/// with the pusher, time, and whether the update was forced.
+async fn cmd_reflog(remote: &str, name: &str) -> Result<()> {
+    let (s3, settings) = connect(remote).await?;
+    let entries = read_reflog(&s3, &settings, name).await?;
+
+    for entry in entries.iter().rev() {
+        let action = match (entry.old_sha.as_str(), entry.new_sha.as_str()) {
+            (ZERO_SHA, _) => "created",
+            (_, ZERO_SHA) => "deleted",
+            _ if entry.forced => "forced update",
+            _ => "fast-forward",
+        };
+        println!(
+            "{}..{} {} {} {}",
+            short_sha(&entry.old_sha),
+            short_sha(&entry.new_sha),
+            format_time(entry.timestamp as i128 * 1_000_000_000),
+            entry.pusher,
+            action
+        );
+    }
+    Ok(())
+}
+
+/// set-head <remote> <branch>
+/// Sets the default branch advertised as the remote's HEAD, used by `git clone`
+/// to pick the branch to check out.
+async fn cmd_set_head(remote: &str, branch: &str) -> Result<()> {
+    let (s3, settings) = connect(remote).await?;
+    let target = if branch.starts_with("refs/") {
+        branch.to_string()
+    } else {
+        format!("refs/heads/{}", branch)
+    };
+
+    let refs = list_refs(&s3, &settings).await?;
+    if !refs.contains_key(&target) {
+        return Err(anyhow!("no such branch on the remote: {}", target));
+    }
+
+    write_head(&s3, &settings, &target).await?;
+    println!("HEAD -> {}", target);
+    Ok(())
+}
+
+/// cache [clear]
+/// Shows the entries of the local bundle cache, most recently used first, or
+/// removes all of them.
+fn cmd_cache(clear: bool) -> Result<()> {
+    let Some(cache) = BundleCache::open(&env::current_dir()?)? else {
+        println!("bundle cache disabled (s3.cacheSize is 0)");
+        return Ok(());
+    };
+
+    if clear {
+        let reclaimed = cache.clear()?;
+        println!("removed {} bytes from {}", reclaimed, cache.dir().display());
+        return Ok(());
+    }
+
+    let entries = cache.entries()?;
+    let size: u64 = entries.iter().map(|e| e.size).sum();
+    println!("{}", cache.dir().display());
+    println!(
+        "{} entries, {} of {} bytes used",
+        entries.len(),
+        size,
+        cache.max_size()
+    );
+    for entry in entries {
+        let last_used = entry
+            .last_used
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as i128)
+            .unwrap_or_default();
+        let name = entry.path.file_name().unwrap_or_default().to_string_lossy();
+        println!("  {} {:>10} {}", name, entry.size, format_time(last_used));
+    }
+    Ok(())
+}
+
+/// gc <remote> [--dry-run] [--grace-period=<duration>]
+/// Consolidates the packs of the remote and deletes objects the current refs no
+/// longer need once they have been garbage for the grace period (default 1d).
+async fn cmd_gc(remote: &str, options: &[&str]) -> Result<()> {
+    let mut gc_options = GcOptions {
+        dry_run: false,
+        grace_period: DEFAULT_GRACE_PERIOD,
+    };
+    for option in options {
+        match option.split_once('=') {
+            None if *option == "--dry-run" => gc_options.dry_run = true,
+            Some(("--grace-period", value)) => {
+                gc_options.grace_period = parse_duration(value)
+                    .ok_or_else(|| anyhow!("invalid grace period: {}", value))?;
+            }
+            _ => return Err(anyhow!("unknown gc option: {}", option)),
+        }
+    }
+
+    let (s3, settings) = connect(remote).await?;
+    let report = gc(&s3, &settings, &gc_options).await?;
+    print_report(&report, gc_options.dry_run);
+    Ok(())
+}
+
+fn print_report(report: &GcReport, dry_run: bool) {
+    let verb = if dry_run {
+        ("would delete", "would reclaim")
+    } else {
+        ("deleted", "reclaimed")
+    };
+    if let Some((name, replaced)) = &report.consolidated {
+        println!("consolidated {} packs into {}", replaced, name);
+    }
+    for (r, reason) in &report.kept {
+        println!("kept {} {}: {}", r.name, short_sha(&r.sha), reason);
+    }
+    for garbage in &report.pending {
+        println!("pending {} {}", garbage.key, garbage.size);
+    }
+    for garbage in &report.deleted {
+        println!("{} {} {}", verb.0, garbage.key, garbage.size);
+    }
+    println!("{} {} bytes", verb.1, report.reclaimed());
+}
+
+/// verify <remote> [--sample=<n>]
+/// Downloads, decrypts and checks every object of the remote, or n of them picked
+/// at random. Prints one `<status>\t<key>\t<detail>` line per object and exits
+/// with status 1 if any object isn't ok.
+async fn cmd_verify(remote: &str, sample: Option<usize>) -> Result<()> {
+    let (s3, settings) = connect(remote).await?;
+    let findings = verify(&s3, &settings, &VerifyOptions { sample }).await?;
+
+    let mut problems = 0;
+    for finding in &findings {
+        if finding.status != Status::Ok {
+            problems += 1;
+        }
+        println!(
+            "{}\t{}\t{}",
+            finding.status.as_str(),
+            finding.key,
+            finding.detail
+        );
+    }
+    eprintln!("{} objects checked, {} problems", findings.len(), problems);
+
+    if problems > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
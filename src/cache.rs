@@ -0,0 +1,185 @@
+use anyhow::{anyhow, Context, Result};
+use md5::{Digest, Md5};
+use std::cmp::Reverse;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::{env, process};
+use tracing::{debug, instrument, warn};
+
+use crate::{git, pad, s3};
+
+/// Default size limit of the cache, `s3.cacheSize` overrides it.
+const DEFAULT_MAX_SIZE: u64 = 1 << 30;
+
+const ENTRY_EXTENSION: &str = "bundle";
+
+/// On-disk cache of downloaded bundles, shared by every clone on the host.
+///
+/// Entries are keyed by S3 key and ETag, so a rewritten object never hits a stale
+/// entry, and hold the object exactly as downloaded: still encrypted and padded.
+#[derive(Debug)]
+pub struct BundleCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+#[derive(Debug)]
+pub struct CacheEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub last_used: SystemTime,
+}
+
+impl BundleCache {
+    /// Open the cache under `$XDG_CACHE_HOME/git-remote-s3`, or `~/.cache` when
+    /// unset. Returns `None` when the cache is disabled with `s3.cacheSize 0`.
+    pub fn open(current_dir: &Path) -> Result<Option<BundleCache>> {
+        let max_size = match git::config("s3.cacheSize", current_dir) {
+            Ok(size) => {
+                pad::parse_size(&size).ok_or_else(|| anyhow!("invalid s3.cacheSize: {}", size))?
+            }
+            Err(_) => DEFAULT_MAX_SIZE,
+        };
+        if max_size == 0 {
+            return Ok(None);
+        }
+
+        let cache_home = env::var_os("XDG_CACHE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .ok_or_else(|| anyhow!("neither XDG_CACHE_HOME nor HOME is set"))?;
+
+        Ok(Some(BundleCache::new(
+            cache_home.join("git-remote-s3"),
+            max_size,
+        )))
+    }
+
+    /// Open the cache for a download like `open`, without failing the download
+    /// when the cache can't be used: it then goes straight to S3.
+    pub fn open_or_skip(current_dir: &Path) -> Option<BundleCache> {
+        BundleCache::open(current_dir).unwrap_or_else(|e| {
+            warn!(?e, "Bundle cache unavailable, downloading without it");
+            None
+        })
+    }
+
+    pub fn new(dir: PathBuf, max_size: u64) -> BundleCache {
+        BundleCache { dir, max_size }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    fn entry_path(&self, o: &s3::Key, etag: &str) -> PathBuf {
+        let mut hasher = Md5::new();
+        hasher.update(format!("{}/{}\n{}", o.bucket, o.key, etag));
+        let digest: String = hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        self.dir.join(digest).with_extension(ENTRY_EXTENSION)
+    }
+
+    /// Copy a cached object to `f`, returning whether it was cached.
+    #[instrument]
+    pub fn get(&self, o: &s3::Key, etag: &str, f: &Path) -> Result<bool> {
+        let entry = self.entry_path(o, etag);
+        if !entry.exists() {
+            return Ok(false);
+        }
+
+        fs::copy(&entry, f)
+            .with_context(|| format!("Failed to copy cached bundle: {}", entry.display()))?;
+        // The modification time records when the entry was last used
+        File::options()
+            .write(true)
+            .open(&entry)?
+            .set_modified(SystemTime::now())?;
+
+        debug!(?entry, "Cache hit");
+        Ok(true)
+    }
+
+    /// Store a downloaded object, then evict the least recently used entries to
+    /// stay within the size limit.
+    #[instrument]
+    pub fn put(&self, o: &s3::Key, etag: &str, f: &Path) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create cache dir: {}", self.dir.display()))?;
+
+        // Write to a temporary file first, so concurrent fetches never see a
+        // partial entry
+        let entry = self.entry_path(o, etag);
+        let tmp = entry.with_extension(format!("tmp.{}", process::id()));
+        fs::copy(f, &tmp)
+            .with_context(|| format!("Failed to write cache entry: {}", tmp.display()))?;
+        fs::rename(&tmp, &entry)?;
+
+        debug!(?entry, "Cached bundle");
+        self.evict()?;
+        Ok(())
+    }
+
+    /// Cached entries, most recently used first.
+    pub fn entries(&self) -> Result<Vec<CacheEntry>> {
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read cache dir: {}", self.dir.display()))
+            }
+        };
+
+        let mut entries = Vec::new();
+        for dir_entry in dir {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(ENTRY_EXTENSION) {
+                continue;
+            }
+            let metadata = fs::metadata(&path)?;
+            entries.push(CacheEntry {
+                path,
+                size: metadata.len(),
+                last_used: metadata.modified()?,
+            });
+        }
+        entries.sort_by_key(|entry| Reverse(entry.last_used));
+        Ok(entries)
+    }
+
+    /// Remove the least recently used entries until the cache fits within its
+    /// size limit, returning the number of bytes reclaimed.
+    pub fn evict(&self) -> Result<u64> {
+        let mut size = 0;
+        let mut reclaimed = 0;
+        for entry in self.entries()? {
+            size += entry.size;
+            if size > self.max_size {
+                debug!(?entry.path, "Evicting cache entry");
+                fs::remove_file(&entry.path)?;
+                reclaimed += entry.size;
+            }
+        }
+        Ok(reclaimed)
+    }
+
+    /// Remove every entry, returning the number of bytes reclaimed.
+    pub fn clear(&self) -> Result<u64> {
+        let mut reclaimed = 0;
+        for entry in self.entries()? {
+            fs::remove_file(&entry.path)?;
+            reclaimed += entry.size;
+        }
+        Ok(reclaimed)
+    }
+}
@@ -0,0 +1,769 @@
+use anyhow::{anyhow, Context, Result};
+use aws_sdk_s3::Client;
+use futures::stream::{self, StreamExt};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    env::current_dir,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+use tracing::{debug, info, instrument};
+
+use crate::cache::BundleCache;
+use crate::git_s3::{
+    download, encrypt_for_upload, pusher, write_layout, GitRef, GitS3Settings, Layout, RefClass,
+    RemoteRef, RemoteRefs,
+};
+use crate::{git, gpg, s3};
+
+/// Marks of a fast-import stream and the objects they stand for, as written by
+/// `--export-marks`
+pub type Marks = HashMap<u64, String>;
+
+pub fn parse_marks(contents: &str) -> Result<Marks> {
+    contents
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            line.split_once(' ')
+                .and_then(|(mark, sha)| {
+                    let mark = mark.strip_prefix(':')?.parse().ok()?;
+                    Some((mark, sha.to_string()))
+                })
+                .ok_or_else(|| anyhow!("invalid marks line: {}", line))
+        })
+        .collect()
+}
+
+fn read_marks(marks_file: &Path) -> Result<Marks> {
+    match std::fs::read_to_string(marks_file) {
+        Ok(contents) => parse_marks(&contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Marks::new()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", marks_file.display())),
+    }
+}
+
+/// What `rewrite_stream` saw of a stream
+#[derive(Debug, Default)]
+pub struct StreamSummary {
+    /// Whether the stream ended with `done`
+    pub done: bool,
+    /// The refs its `commit`, `reset` and `tag` commands update
+    pub refs: BTreeSet<String>,
+    /// The marks it defines
+    pub marks: BTreeSet<u64>,
+}
+
+/// Copy a fast-import stream up to its `done` command or its end, rewriting the
+/// marks it defines or refers to with `mark`, which is told whether the stream
+/// defined the mark. `feature done` and `done` are dropped, the contents of `data`
+/// commands are copied as they are.
+pub fn rewrite_stream(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    mark: &mut impl FnMut(u64, bool) -> Result<String>,
+) -> Result<StreamSummary> {
+    let mut summary = StreamSummary::default();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if input.read_until(b'\n', &mut line)? == 0 {
+            return Ok(summary);
+        }
+        let command = line.strip_suffix(b"\n").unwrap_or(&line);
+
+        if command == b"done" {
+            summary.done = true;
+            return Ok(summary);
+        }
+        if command == b"feature done" {
+            continue;
+        }
+        if let Some(len) = command.strip_prefix(b"data ") {
+            let len: u64 = std::str::from_utf8(len)
+                .ok()
+                .and_then(|len| len.parse().ok())
+                .ok_or_else(|| {
+                    anyhow!(
+                        "unsupported data command: {}",
+                        String::from_utf8_lossy(command)
+                    )
+                })?;
+            output.write_all(&line)?;
+            let copied = io::copy(&mut Read::by_ref(input).take(len), output)?;
+            if copied != len {
+                return Err(anyhow!("stream ended in the middle of a data command"));
+            }
+            continue;
+        }
+
+        for (keyword, namespace) in [("commit ", ""), ("reset ", ""), ("tag ", "refs/tags/")] {
+            if let Some(name) = command.strip_prefix(keyword.as_bytes()) {
+                let name = String::from_utf8_lossy(name);
+                summary.refs.insert(format!("{}{}", namespace, name));
+            }
+        }
+        let rewritten = rewrite_command(command, &mut summary.marks, mark)?;
+        output.write_all(&rewritten)?;
+        output.write_all(b"\n")?;
+    }
+}
+
+/// Rewrite the marks of a single command line. Paths are copied as bytes, they need
+/// not be UTF-8.
+fn rewrite_command(
+    command: &[u8],
+    defined: &mut BTreeSet<u64>,
+    mark: &mut impl FnMut(u64, bool) -> Result<String>,
+) -> Result<Vec<u8>> {
+    let words: Vec<&[u8]> = command.splitn(4, |b| *b == b' ').collect();
+    let rewritten = match words.as_slice() {
+        [b"mark", word] => {
+            let n = parse_mark(word)?;
+            defined.insert(n);
+            [b"mark ", mark(n, true)?.as_bytes()].concat()
+        }
+        [keyword @ (b"from" | b"merge" | b"to"), word] => {
+            [*keyword, b" ", &dataref(word, defined, mark)?].concat()
+        }
+        [b"M", mode, word, path] => [
+            b"M ".as_slice(),
+            mode,
+            b" ",
+            &dataref(word, defined, mark)?,
+            b" ",
+            path,
+        ]
+        .concat(),
+        [b"N", word, commit] => [
+            b"N ".as_slice(),
+            &dataref(word, defined, mark)?,
+            b" ",
+            &dataref(commit, defined, mark)?,
+        ]
+        .concat(),
+        _ => command.to_vec(),
+    };
+    Ok(rewritten)
+}
+
+/// Rewrite a reference to an object, if it is a mark rather than a sha or `inline`
+fn dataref(
+    word: &[u8],
+    defined: &BTreeSet<u64>,
+    mark: &mut impl FnMut(u64, bool) -> Result<String>,
+) -> Result<Vec<u8>> {
+    if !word.starts_with(b":") {
+        return Ok(word.to_vec());
+    }
+    let n = parse_mark(word)?;
+    Ok(mark(n, defined.contains(&n))?.into_bytes())
+}
+
+fn parse_mark(word: &[u8]) -> Result<u64> {
+    word.strip_prefix(b":")
+        .and_then(|n| std::str::from_utf8(n).ok())
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| anyhow!("invalid mark: {}", String::from_utf8_lossy(word)))
+}
+
+/// A stream of the remote
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestStream {
+    /// SHA-256 of the stream, before encryption
+    pub name: String,
+    /// The objects the refs the stream updated pointed to after it. Having them
+    /// locally means having every object of the stream.
+    pub tips: Vec<String>,
+}
+
+/// The refs of a remote using the fast-export layout, and the streams that hold
+/// their history.
+///
+/// Stored encrypted in `<prefix>/fast-export/manifest`, one entry per line:
+/// `stream <name> <tip>[,<tip> …]`, in the order the streams were pushed, and
+/// `ref <name> <sha>`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub streams: Vec<ManifestStream>,
+    pub refs: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    pub fn parse(contents: &str) -> Result<Manifest> {
+        let mut manifest = Manifest::default();
+        for line in contents.lines() {
+            let mut parts = line.split_ascii_whitespace();
+            match parts.next() {
+                None => {}
+                Some(comment) if comment.starts_with('#') => {}
+                Some("stream") => {
+                    let Some(name) = parts.next() else {
+                        return Err(anyhow!("invalid manifest line: {}", line));
+                    };
+                    manifest.streams.push(ManifestStream {
+                        name: name.to_string(),
+                        tips: parts
+                            .next()
+                            .map(|tips| tips.split(',').map(|tip| tip.to_string()).collect())
+                            .unwrap_or_default(),
+                    });
+                }
+                Some("ref") => {
+                    let (Some(name), Some(sha)) = (parts.next(), parts.next()) else {
+                        return Err(anyhow!("invalid manifest line: {}", line));
+                    };
+                    manifest.refs.insert(name.to_string(), sha.to_string());
+                }
+                Some(_) => return Err(anyhow!("invalid manifest line: {}", line)),
+            }
+        }
+        Ok(manifest)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::from("# git-remote-s3 fast-export manifest\n");
+        for stream in &self.streams {
+            text += &format!("stream {} {}\n", stream.name, stream.tips.join(","));
+        }
+        for (name, sha) in &self.refs {
+            text += &format!("ref {} {}\n", name, sha);
+        }
+        text
+    }
+}
+
+pub fn manifest_path(prefix: &str) -> String {
+    format!("{}/fast-export/manifest", prefix)
+}
+
+pub fn stream_path(prefix: &str, name: &str) -> String {
+    format!("{}/fast-export/{}.fi", prefix, name)
+}
+
+pub fn marks_path(prefix: &str, name: &str) -> String {
+    format!("{}/fast-export/{}.marks", prefix, name)
+}
+
+/// Reads the manifest, `None` if nothing was pushed yet.
+pub async fn read_manifest(s3: &Client, settings: &GitS3Settings) -> Result<Option<Manifest>> {
+    Ok(read_manifest_etag(s3, settings)
+        .await?
+        .map(|(manifest, _)| manifest))
+}
+
+/// Read the manifest along with the ETag of the version read.
+async fn read_manifest_etag(
+    s3: &Client,
+    settings: &GitS3Settings,
+) -> Result<Option<(Manifest, String)>> {
+    let tmp_dir = tempfile::tempdir()?;
+    let manifest_file = tmp_dir.path().join("fast_export_manifest");
+    let enc_file = tmp_dir.path().join("fast_export_manifest_enc");
+
+    let o = s3::Key {
+        bucket: settings.bucket().to_owned(),
+        key: manifest_path(settings.key()),
+    };
+    let Some(etag) = s3::try_get_etag(s3, &enc_file, &o, settings.sse()?).await? else {
+        return Ok(None);
+    };
+    gpg::decrypt(&enc_file, &manifest_file)?;
+
+    let contents = std::fs::read_to_string(&manifest_file)?;
+    Ok(Some((Manifest::parse(&contents)?, etag)))
+}
+
+async fn write_manifest(s3: &Client, settings: &GitS3Settings, manifest: &Manifest) -> Result<()> {
+    let tmp_dir = tempfile::tempdir()?;
+    let manifest_file = tmp_dir.path().join("fast_export_manifest");
+    let enc_file = tmp_dir.path().join("fast_export_manifest_enc");
+
+    std::fs::write(&manifest_file, manifest.to_text())?;
+    gpg::encrypt(&settings.gpg_recipients()?, &manifest_file, &enc_file)?;
+
+    let o = s3::Key {
+        bucket: settings.bucket().to_owned(),
+        key: manifest_path(settings.key()),
+    };
+    s3::put(
+        s3,
+        &enc_file,
+        &o,
+        settings.sse()?,
+        &s3::Attributes::default(),
+    )
+    .await
+}
+
+/// The refs of the manifest. Each ref has a single head.
+pub async fn list_refs(
+    s3: &Client,
+    settings: &GitS3Settings,
+) -> Result<HashMap<String, RemoteRefs>> {
+    let manifest = read_manifest(s3, settings).await?.unwrap_or_default();
+
+    Ok(manifest
+        .refs
+        .into_iter()
+        .map(|(name, sha)| {
+            let mut refs = RemoteRefs::new();
+            refs.add_ref(RemoteRef {
+                updated: 0,
+                generation: 0,
+                reference: GitRef {
+                    name: name.clone(),
+                    sha,
+                },
+                storage_class: None,
+                peeled: None,
+                basis: Vec::new(),
+                shallow_depth: None,
+                blob_packs: false,
+            });
+            (name, refs)
+        })
+        .collect())
+}
+
+/// The ref a branch of the remote is imported to, in the private namespace
+/// `refs/s3/<alias>/heads/` of `GitS3Settings::refspec`. Other refs are imported as
+/// they are.
+pub fn private_ref(settings: &GitS3Settings, name: &str) -> String {
+    match name.strip_prefix("refs/heads/") {
+        Some(branch) => format!("{}/heads/{}", settings.private_namespace(), branch),
+        None => name.to_string(),
+    }
+}
+
+/// Directory of the remote's local state, `$GIT_DIR/s3/<alias>`
+fn state_dir(settings: &GitS3Settings) -> Result<PathBuf> {
+    let dir = git::git_path(&format!("s3/{}", settings.safe_alias()), &current_dir()?)?;
+    std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    Ok(dir)
+}
+
+/// The marks git's fast-export and fast-import share for the remote, naming the
+/// commits already exported to or imported from it. Created empty, as fast-export
+/// fails on a missing marks file.
+pub fn marks_file(settings: &GitS3Settings) -> Result<PathBuf> {
+    let marks_file = state_dir(settings)?.join("git.marks");
+    if !marks_file.exists() {
+        File::create(&marks_file)
+            .with_context(|| format!("Failed to create {}", marks_file.display()))?;
+    }
+    Ok(marks_file)
+}
+
+/// The streams whose objects the marks file covers: those pushed from or imported
+/// into this repository
+fn known_streams(settings: &GitS3Settings) -> Result<HashSet<String>> {
+    let streams_file = state_dir(settings)?.join("streams");
+    match std::fs::read_to_string(&streams_file) {
+        Ok(contents) => Ok(contents.lines().map(|line| line.to_string()).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", streams_file.display())),
+    }
+}
+
+/// Remove marks from the marks file. git has fast-export write it to a `.tmp`
+/// file, renamed over the marks file once the helper answers.
+fn forget_marks(settings: &GitS3Settings, marks: &BTreeSet<u64>) -> Result<()> {
+    let mut marks_file = marks_file(settings)?;
+    let pending = marks_file.with_extension("marks.tmp");
+    if pending.exists() {
+        marks_file = pending;
+    }
+    let contents: String = read_marks(&marks_file)?
+        .into_iter()
+        .filter(|(n, _)| !marks.contains(n))
+        .map(|(n, sha)| format!(":{} {}\n", n, sha))
+        .collect();
+    std::fs::write(&marks_file, contents)
+        .with_context(|| format!("Failed to write {}", marks_file.display()))
+}
+
+fn add_known_streams<'a>(
+    settings: &GitS3Settings,
+    names: impl IntoIterator<Item = &'a str>,
+) -> Result<()> {
+    let streams_file = state_dir(settings)?.join("streams");
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&streams_file)
+        .with_context(|| format!("Failed to open {}", streams_file.display()))?;
+    for name in names {
+        writeln!(f, "{}", name)?;
+    }
+    Ok(())
+}
+
+/// A ref updated by a pushed stream
+#[derive(Debug)]
+pub struct Update {
+    pub name: String,
+    /// Where the ref pointed before, `None` for a new ref
+    pub old_sha: Option<String>,
+    /// Where it points now, `None` for a deleted ref
+    pub new_sha: Option<String>,
+    /// Whether the update drops history the ref pointed to
+    pub forced: bool,
+    /// Why the update was refused, if it was
+    pub rejected: Option<String>,
+}
+
+impl Update {
+    /// Why the update would need to be forced, if it would, checked in the local
+    /// repository. Tags only ever fast-forward to themselves.
+    fn refusal(&self, current_dir: &Path) -> Result<Option<&'static str>> {
+        let (Some(old_sha), Some(new_sha)) = (&self.old_sha, &self.new_sha) else {
+            return Ok(None);
+        };
+        if RefClass::of(&self.name) == RefClass::Tags {
+            return Ok(Some("already exists").filter(|_| old_sha != new_sha));
+        }
+        if !git::object_exists(old_sha, current_dir)? {
+            return Ok(Some("fetch first"));
+        }
+        let fast_forward = git::is_ancestor(old_sha, new_sha, current_dir)?;
+        Ok(Some("non-fast-forward").filter(|_| !fast_forward))
+    }
+}
+
+/// Store the fast-export stream git sends for the `export` command, up to its
+/// `done`, and record the refs it updates in the manifest.
+///
+/// Marks the stream refers to without defining them name objects exported by
+/// earlier pushes from this repository: they are replaced with their shas, so that
+/// the stored stream only depends on the objects of earlier streams. The stream is
+/// imported into a scratch repository to learn the shas of the refs, and the
+/// objects its own marks stand for, stored next to it as its marks file.
+///
+/// git doesn't hold back the refs it would reject from fast-export, so updates
+/// that aren't fast-forwards are checked here. Unless `force` is set, the whole
+/// stream is refused if any of them isn't. git only sets it for `--force`: a `+`
+/// on a refspec never reaches export helpers.
+#[instrument(skip(s3, input))]
+pub async fn export(
+    s3: &Client,
+    settings: &GitS3Settings,
+    input: &mut impl BufRead,
+    force: bool,
+) -> Result<Vec<Update>> {
+    let tmp_dir = tempfile::tempdir()?;
+    let raw_file = tmp_dir.path().join("export_raw");
+
+    // fast-export writes the marks file before done, read it once the whole
+    // stream is in
+    let mut raw = BufWriter::new(File::create(&raw_file)?);
+    let summary = rewrite_stream(input, &mut raw, &mut |n, _| Ok(format!(":{}", n)))?;
+    raw.flush()?;
+    if !summary.done {
+        return Err(anyhow!("fast-export stream ended before done"));
+    }
+
+    let result = store(s3, settings, &raw_file, &summary, force).await;
+    if !result
+        .as_ref()
+        .is_ok_and(|updates| updates.iter().all(|update| update.rejected.is_none()))
+    {
+        // fast-export recorded the commits of the stream as exported
+        forget_marks(settings, &summary.marks)?;
+    }
+    result
+}
+
+/// Store a stream read by `export`, unless its updates are rejected.
+async fn store(
+    s3: &Client,
+    settings: &GitS3Settings,
+    raw_file: &Path,
+    summary: &StreamSummary,
+    force: bool,
+) -> Result<Vec<Update>> {
+    let tmp_dir = tempfile::tempdir()?;
+    let stream_file = tmp_dir.path().join("export.fi");
+    let marks_file = tmp_dir.path().join("export.marks");
+    let current_dir = current_dir()?;
+
+    let known = read_marks(&self::marks_file(settings)?)?;
+    let mut stream = BufWriter::new(File::create(&stream_file)?);
+    rewrite_stream(
+        &mut BufReader::new(File::open(raw_file)?),
+        &mut stream,
+        &mut |n, defined| match (defined, known.get(&n)) {
+            (true, _) => Ok(format!(":{}", n)),
+            (false, Some(sha)) => Ok(sha.clone()),
+            (false, None) => Err(anyhow!("mark :{} is neither in the stream nor known", n)),
+        },
+    )?;
+    stream.flush()?;
+    drop(stream);
+
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(&stream_file)?, &mut hasher)?;
+    let name = format!("{:x}", hasher.finalize());
+
+    let scratch = tmp_dir.path().join("export_repo");
+    git::init_bare(&scratch)?;
+    borrow_objects(&scratch, &current_dir)?;
+    git::fast_import(&stream_file, Some(&marks_file), &scratch)?;
+    let scratch_refs: HashMap<String, String> = git::for_each_ref(&scratch)?.into_iter().collect();
+    std::fs::remove_dir_all(&scratch)?;
+
+    let (existing, etag) = read_manifest_etag(s3, settings).await?.unzip();
+    let manifest_key = s3::Key {
+        bucket: settings.bucket().to_owned(),
+        key: manifest_path(settings.key()),
+    };
+    let first_push = existing.is_none();
+    let mut manifest = existing.unwrap_or_default();
+
+    // Refs the stream resets without a commit are deleted
+    let mut updates = Vec::new();
+    for ref_name in &summary.refs {
+        let mut update = Update {
+            name: ref_name.clone(),
+            old_sha: manifest.refs.get(ref_name).cloned(),
+            new_sha: scratch_refs.get(ref_name).cloned(),
+            forced: false,
+            rejected: None,
+        };
+        let refusal = update.refusal(&current_dir)?;
+        update.forced = refusal.is_some();
+        if !force {
+            update.rejected = refusal.map(|reason| reason.to_string());
+        }
+        updates.push(update);
+    }
+    if updates.iter().any(|update| update.rejected.is_some()) {
+        info!(?updates, "Refusing stream");
+        for update in updates.iter_mut() {
+            update
+                .rejected
+                .get_or_insert("atomic push failed".to_string());
+        }
+        return Ok(updates);
+    }
+
+    let mut tips = Vec::new();
+    for update in &updates {
+        match &update.new_sha {
+            Some(sha) => {
+                manifest.refs.insert(update.name.clone(), sha.clone());
+                if !tips.contains(sha) {
+                    tips.push(sha.clone());
+                }
+            }
+            None => {
+                manifest.refs.remove(&update.name);
+            }
+        }
+    }
+
+    // The same stream pushed again creates the same objects, its entry only gains
+    // the tips refs now point to
+    if let Some(existing) = manifest.streams.iter_mut().find(|s| s.name == name) {
+        info!(?name, ?updates, "Stream already stored");
+        for tip in tips {
+            if !existing.tips.contains(&tip) {
+                existing.tips.push(tip);
+            }
+        }
+    } else {
+        info!(?name, ?updates, "Uploading stream");
+        let metadata = HashMap::from([("pusher".to_string(), pusher(&current_dir))]);
+        let attrs = s3::Attributes {
+            storage_class: None,
+            ..settings.attributes(RefClass::Heads, metadata)?
+        };
+        upload(
+            s3,
+            settings,
+            &stream_file,
+            &stream_path(settings.key(), &name),
+            &attrs,
+        )
+        .await?;
+        upload(
+            s3,
+            settings,
+            &marks_file,
+            &marks_path(settings.key(), &name),
+            &attrs,
+        )
+        .await?;
+        manifest.streams.push(ManifestStream {
+            name: name.clone(),
+            tips,
+        });
+    }
+
+    // Without conditional writes, this narrows the window in which a concurrent
+    // push could be lost. On the first push, the manifest must still be missing.
+    if s3::etag(s3, &manifest_key, settings.sse()?).await? != etag {
+        return Err(anyhow!(
+            "the remote was pushed to concurrently, fetch and push again"
+        ));
+    }
+    // Written first, so that a manifest is never read as part of a bundle remote
+    if first_push {
+        write_layout(s3, settings, Layout::FastExport).await?;
+    }
+    write_manifest(s3, settings, &manifest).await?;
+    add_known_streams(settings, [name.as_str()])?;
+
+    Ok(updates)
+}
+
+/// Encrypt and upload a stream or its marks file.
+async fn upload(
+    s3: &Client,
+    settings: &GitS3Settings,
+    file: &Path,
+    key: &str,
+    attrs: &s3::Attributes,
+) -> Result<()> {
+    let tmp_dir = tempfile::tempdir()?;
+    let enc_file = tmp_dir.path().join("fast_export_enc");
+    encrypt_for_upload(settings, file, &enc_file)?;
+
+    let o = s3::Key {
+        bucket: settings.bucket().to_owned(),
+        key: key.to_string(),
+    };
+    s3::put(s3, &enc_file, &o, settings.sse()?, attrs).await
+}
+
+/// Let the scratch repository `git_dir` read the objects of the repository.
+/// `git::add_alternate` would find the repository through `GIT_DIR` instead.
+fn borrow_objects(git_dir: &Path, current_dir: &Path) -> Result<()> {
+    let objects = std::fs::canonicalize(git::git_path("objects", current_dir)?)?;
+    let alternates = git_dir.join("objects/info/alternates");
+    std::fs::write(&alternates, format!("{}\n", objects.display()))
+        .with_context(|| format!("Failed to write {}", alternates.display()))
+}
+
+/// Answer git's `import` commands for `refs`, writing a fast-import stream that
+/// points their private refs at their commits in the manifest.
+///
+/// The streams whose objects the marks file doesn't cover are replayed first, in
+/// the order they were pushed, into a scratch repository borrowing the objects of
+/// this one: their marks are moved past those git already uses, and a checkpoint
+/// after each one lets later streams refer to its objects by sha. The packs this
+/// creates are then indexed into the repository. Refs aren't imported from the
+/// streams themselves, as annotated tags can only be imported to `refs/tags`.
+#[instrument(skip(s3, output))]
+pub async fn import(
+    s3: &Client,
+    settings: &GitS3Settings,
+    refs: &[String],
+    output: &mut impl Write,
+) -> Result<()> {
+    let manifest = read_manifest(s3, settings)
+        .await?
+        .ok_or_else(|| anyhow!("remote has no manifest"))?;
+    let current_dir = current_dir()?;
+    let known = known_streams(settings)?;
+
+    let mut needed = Vec::new();
+    for stream in &manifest.streams {
+        let mut present = known.contains(&stream.name);
+        for tip in &stream.tips {
+            present = present && git::object_exists(tip, &current_dir)?;
+        }
+        if !present {
+            needed.push(stream);
+        }
+    }
+    if !needed.is_empty() {
+        replay(s3, settings, &needed, &current_dir).await?;
+        add_known_streams(settings, needed.iter().map(|stream| stream.name.as_str()))?;
+    }
+
+    // Private refs follow the remote through forced pushes
+    writeln!(output, "feature done")?;
+    writeln!(output, "feature force")?;
+    for name in refs {
+        let private = private_ref(settings, name);
+        if let (true, Some(sha)) = (private != *name, manifest.refs.get(name)) {
+            writeln!(output, "reset {}\nfrom {}\n", private, sha)?;
+        }
+    }
+    writeln!(output, "done")?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Download the streams and import them into the repository, updating the marks
+/// file once their objects are in.
+async fn replay(
+    s3: &Client,
+    settings: &GitS3Settings,
+    streams: &[&ManifestStream],
+    current_dir: &Path,
+) -> Result<()> {
+    let jobs = settings.fetch_jobs()?;
+    info!(count = streams.len(), jobs, "Importing streams from S3");
+
+    let tmp_dir = tempfile::tempdir()?;
+    let cache = BundleCache::open_or_skip(current_dir);
+    let cache = cache.as_ref();
+    let mut downloads = stream::iter(streams.iter().enumerate())
+        .map(|(i, stream)| {
+            let o = s3::Key {
+                bucket: settings.bucket().to_owned(),
+                key: stream_path(settings.key(), &stream.name),
+            };
+            let enc_file = tmp_dir.path().join(format!("stream_enc_{}", i));
+            let stream_file = tmp_dir.path().join(format!("stream_{}", i));
+            async move {
+                download(s3, settings, cache, &o, &enc_file, &stream_file).await?;
+                Ok::<_, anyhow::Error>(stream_file)
+            }
+        })
+        .buffered(jobs);
+
+    let marks_file = marks_file(settings)?;
+    let import_file = tmp_dir.path().join("import.fi");
+    let mut import = BufWriter::new(File::create(&import_file)?);
+    let mut offset = read_marks(&marks_file)?.keys().max().copied().unwrap_or(0);
+    while let Some(stream_file) = downloads.next().await {
+        let stream_file = stream_file?;
+        debug!(?stream_file, offset, "Replaying stream");
+        let mut last = offset;
+        rewrite_stream(
+            &mut BufReader::new(File::open(&stream_file)?),
+            &mut import,
+            &mut |n, _| {
+                last = last.max(offset + n);
+                Ok(format!(":{}", offset + n))
+            },
+        )?;
+        writeln!(import, "checkpoint")?;
+        offset = last;
+    }
+    import.flush()?;
+    drop(import);
+
+    let scratch = tmp_dir.path().join("import_repo");
+    git::init_bare(&scratch)?;
+    borrow_objects(&scratch, current_dir)?;
+    let new_marks = tmp_dir.path().join("import.marks");
+    std::fs::copy(&marks_file, &new_marks)?;
+    git::fast_import(&import_file, Some(&new_marks), &scratch)?;
+
+    for entry in std::fs::read_dir(scratch.join("objects/pack"))? {
+        let pack = entry?.path();
+        if pack.extension().is_some_and(|ext| ext == "pack") {
+            git::index_pack(&pack, false, current_dir)?;
+        }
+    }
+    std::fs::rename(&new_marks, &marks_file)
+        .or_else(|_| std::fs::copy(&new_marks, &marks_file).map(|_| ()))
+        .with_context(|| format!("Failed to write {}", marks_file.display()))?;
+    Ok(())
+}
@@ -0,0 +1,401 @@
+use anyhow::{anyhow, Result};
+use aws_sdk_s3::Client;
+use std::{
+    collections::{HashMap, HashSet},
+    env::current_dir,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::info;
+
+use crate::git_s3::{
+    layout, list_refs, pusher, GitRef, GitS3Settings, Layout, RefClass, RemoteRef, RemoteRefs,
+};
+use crate::packs::{self, ManifestPack};
+use crate::{git, s3};
+
+/// How long garbage is kept by default, so that fetches that listed the remote
+/// before `gc` ran can still download what they need.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug)]
+pub struct GcOptions {
+    /// Report what would be done without changing the remote
+    pub dry_run: bool,
+    /// How long an object must have been garbage before it is deleted
+    pub grace_period: Duration,
+}
+
+/// An object that is no longer needed
+#[derive(Debug)]
+pub struct Garbage {
+    pub key: String,
+    pub size: i64,
+}
+
+#[derive(Debug, Default)]
+pub struct GcReport {
+    /// Garbage deleted, or that a dry run would delete
+    pub deleted: Vec<Garbage>,
+    /// Garbage still within the grace period
+    pub pending: Vec<Garbage>,
+    /// Stale heads that were kept, and why
+    pub kept: Vec<(GitRef, String)>,
+    /// The pack the refs were consolidated into, and how many packs it replaces
+    pub consolidated: Option<(String, usize)>,
+}
+
+impl GcReport {
+    /// Bytes reclaimed by deleting garbage
+    pub fn reclaimed(&self) -> i64 {
+        self.deleted.iter().map(|g| g.size).sum()
+    }
+}
+
+/// Parse a duration such as `90`, `30s`, `15m`, `12h` or `7d`.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    value
+        .parse::<u64>()
+        .ok()?
+        .checked_mul(secs)
+        .map(Duration::from_secs)
+}
+
+/// Remove what the current refs of the remote don't need.
+///
+/// With bundles, stale heads that a branch includes are deleted once they have
+/// been superseded for the grace period, unless a thin tag bundle may need them.
+/// Ancestry is checked in the local repository, heads it doesn't have are kept.
+/// Objects left behind by deleted refs or interrupted pushes are deleted once
+/// older than the grace period.
+///
+/// With packs, the packs the refs need are consolidated into a single pack built
+/// from the local repository, and the manifest is updated in a single put. The
+/// replaced packs are marked as garbage and deleted by a later `gc` once the
+/// grace period has passed.
+///
+/// With fast-export streams, each stream builds on the ones before it, so there
+/// is nothing to remove.
+pub async fn gc(s3: &Client, settings: &GitS3Settings, options: &GcOptions) -> Result<GcReport> {
+    match layout(s3, settings).await? {
+        Layout::Bundles => gc_bundles(s3, settings, options).await,
+        Layout::Packs => gc_packs(s3, settings, options).await,
+        Layout::FastExport => Ok(GcReport::default()),
+    }
+}
+
+fn unix_now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+async fn gc_bundles(
+    s3: &Client,
+    settings: &GitS3Settings,
+    options: &GcOptions,
+) -> Result<GcReport> {
+    let current_dir = current_dir()?;
+    let objects = s3::list(s3, settings.bucket(), &format!("{}/refs/", settings.key())).await?;
+    let refs = list_refs(s3, settings).await?;
+    let mut report = GcReport::default();
+
+    let mut garbage = Vec::new();
+    for (head, since) in superseded_heads(&refs, &current_dir, &mut report)? {
+        let keys = head.object_keys(settings.key());
+        garbage.extend(keys.into_iter().map(|key| (key, since)));
+    }
+
+    // Leftovers of deleted refs and interrupted pushes
+    let live: HashSet<String> = refs
+        .values()
+        .flat_map(|remote_refs| remote_refs.all_refs())
+        .flat_map(|head| head.object_keys(settings.key()))
+        .collect();
+    for obj in &objects {
+        if !live.contains(&obj.key) {
+            garbage.push((obj.key.clone(), obj.last_modified));
+        }
+    }
+
+    delete_garbage(s3, settings, &objects, garbage, options, &mut report).await?;
+    Ok(report)
+}
+
+/// Delete the stale heads of the remote that a current branch head includes,
+/// without waiting for a grace period. Unlike `gc`, objects that no head needs
+/// are left alone. The pack and fast-export layouts have no stale heads.
+pub async fn prune(s3: &Client, settings: &GitS3Settings, dry_run: bool) -> Result<GcReport> {
+    let mut report = GcReport::default();
+    if layout(s3, settings).await? != Layout::Bundles {
+        return Ok(report);
+    }
+
+    let current_dir = current_dir()?;
+    let objects = s3::list(s3, settings.bucket(), &format!("{}/refs/", settings.key())).await?;
+    let refs = list_refs(s3, settings).await?;
+
+    let mut garbage = Vec::new();
+    for (head, _) in superseded_heads(&refs, &current_dir, &mut report)? {
+        let keys = head.object_keys(settings.key());
+        garbage.extend(keys.into_iter().map(|key| (key, i128::MIN)));
+    }
+
+    let options = GcOptions {
+        dry_run,
+        grace_period: Duration::ZERO,
+    };
+    delete_garbage(s3, settings, &objects, garbage, &options, &mut report).await?;
+    Ok(report)
+}
+
+/// The stale heads that may be deleted, with the time they were superseded: when
+/// the latest head of their ref was pushed. The others are added to the report,
+/// with the reason they are kept.
+fn superseded_heads<'a>(
+    refs: &'a HashMap<String, RemoteRefs>,
+    current_dir: &Path,
+    report: &mut GcReport,
+) -> Result<Vec<(&'a RemoteRef, i128)>> {
+    let branch_heads: Vec<&str> = refs
+        .iter()
+        .filter(|(name, _)| RefClass::of(name) == RefClass::Heads)
+        .map(|(_, refs)| refs.latest_ref().reference.sha.as_str())
+        .collect();
+    let tags: Vec<&RemoteRef> = refs
+        .iter()
+        .filter(|(name, _)| RefClass::of(name) == RefClass::Tags)
+        .map(|(_, refs)| refs.latest_ref())
+        .collect();
+
+    let mut superseded = Vec::new();
+    for remote_refs in refs.values() {
+        let latest = remote_refs.latest_ref();
+        for stale in remote_refs.stale_refs() {
+            match keep_reason(stale, &branch_heads, &tags, current_dir)? {
+                Some(reason) => report.kept.push((stale.reference.clone(), reason)),
+                None => superseded.push((stale, latest.updated)),
+            }
+        }
+    }
+    Ok(superseded)
+}
+
+/// Delete the garbage keys that have been garbage since longer than the grace
+/// period, adding them to the report with their size as listed.
+async fn delete_garbage(
+    s3: &Client,
+    settings: &GitS3Settings,
+    objects: &[s3::ObjectInfo],
+    garbage: Vec<(String, i128)>,
+    options: &GcOptions,
+    report: &mut GcReport,
+) -> Result<()> {
+    let now = unix_now().as_nanos() as i128;
+    let grace_period = options.grace_period.as_nanos() as i128;
+    let sizes: HashMap<&str, i64> = objects
+        .iter()
+        .map(|obj| (obj.key.as_str(), obj.size))
+        .collect();
+
+    for (key, since) in garbage {
+        let Some(size) = sizes.get(key.as_str()).copied() else {
+            continue;
+        };
+        if now.saturating_sub(since) < grace_period {
+            report.pending.push(Garbage { key, size });
+            continue;
+        }
+
+        if !options.dry_run {
+            info!(?key, "Deleting garbage");
+            let o = s3::Key {
+                bucket: settings.bucket().to_owned(),
+                key: key.clone(),
+            };
+            s3::del(s3, &o).await?;
+        }
+        report.deleted.push(Garbage { key, size });
+    }
+    Ok(())
+}
+
+/// Why a stale head must be kept, if it must. The basis of a thin tag bundle is
+/// kept, as fetching the tag may need to download it first. Otherwise a head
+/// that a branch includes is superseded, unless only a thin tag bundle, which
+/// needs the commits it was built on, includes it. Heads that nothing includes
+/// diverged and are kept.
+fn keep_reason(
+    stale: &RemoteRef,
+    branch_heads: &[&str],
+    tags: &[&RemoteRef],
+    current_dir: &Path,
+) -> Result<Option<String>> {
+    let sha = &stale.reference.sha;
+    if let Some(tag) = tags.iter().find(|tag| tag.basis.contains(sha)) {
+        return Ok(Some(format!("basis of {}", tag.reference.name)));
+    }
+    if !git::object_exists(sha, current_dir)? {
+        return Ok(Some("not present locally, fetch it first".to_string()));
+    }
+
+    for head in branch_heads {
+        if git::is_ancestor(sha, head, current_dir)? {
+            return Ok(None);
+        }
+    }
+    for tag in tags {
+        let commit = tag.peeled.as_ref().unwrap_or(&tag.reference.sha);
+        if !git::object_exists(commit, current_dir)? {
+            return Ok(Some(format!(
+                "{} not present locally, fetch it first",
+                tag.reference.name
+            )));
+        }
+        if git::is_ancestor(sha, commit, current_dir)? {
+            return Ok(Some(format!("needed by {}", tag.reference.name)));
+        }
+    }
+    Ok(Some("not included by any branch".to_string()))
+}
+
+async fn gc_packs(s3: &Client, settings: &GitS3Settings, options: &GcOptions) -> Result<GcReport> {
+    let mut report = GcReport::default();
+    let Some((original, etag)) = packs::read_manifest_etag(s3, settings).await? else {
+        return Ok(report);
+    };
+    let manifest_key = s3::Key {
+        bucket: settings.bucket().to_owned(),
+        key: packs::manifest_path(settings.key()),
+    };
+
+    let current_dir = current_dir()?;
+    let now = unix_now().as_secs() as i64;
+    let grace_period = options.grace_period.as_secs() as i64;
+    let objects = s3::list(s3, settings.bucket(), &format!("{}/packs/", settings.key())).await?;
+    let sizes: HashMap<String, i64> = objects
+        .iter()
+        .filter_map(|obj| {
+            let name = obj.key.rsplit('/').next()?.strip_suffix(".pack")?;
+            Some((name.to_string(), obj.size))
+        })
+        .collect();
+
+    let mut manifest = original.clone();
+    let mut live = HashSet::new();
+    for m in manifest.refs.values() {
+        for pack in manifest.needed_packs(&m.pack, &mut |_| Ok(false))? {
+            live.insert(pack.name.clone());
+        }
+    }
+
+    if live.len() > 1 {
+        let mut tips: Vec<String> = manifest.refs.values().map(|m| m.sha.clone()).collect();
+        tips.sort();
+        tips.dedup();
+        for tip in &tips {
+            if !git::object_exists(tip, &current_dir)? {
+                return Err(anyhow!(
+                    "{} is not present locally, fetch the remote before running gc",
+                    tip
+                ));
+            }
+        }
+        if !git::read_shallow(&current_dir)?.is_empty() {
+            return Err(anyhow!("gc needs a complete clone, this one is shallow"));
+        }
+
+        let tmp_dir = tempfile::tempdir()?;
+        let pack_file = tmp_dir.path().join("gc_pack");
+        git::pack_objects_revs(&pack_file, &tips, &current_dir)?;
+        let name = packs::pack_checksum(&pack_file)?;
+        info!(?name, replaced = live.len(), "Consolidating packs");
+        if !options.dry_run {
+            let metadata = HashMap::from([
+                ("pusher".to_string(), pusher(&current_dir)),
+                (
+                    "tool-version".to_string(),
+                    format!("git-remote-s3/{}", env!("CARGO_PKG_VERSION")),
+                ),
+            ]);
+            let attrs = settings.attributes(RefClass::Heads, metadata)?;
+            packs::upload_pack(s3, settings, &pack_file, &name, &attrs).await?;
+        }
+
+        for m in manifest.refs.values_mut() {
+            m.pack = name.clone();
+        }
+        manifest.packs.insert(
+            name.clone(),
+            ManifestPack {
+                name: name.clone(),
+                tips,
+                deps: Vec::new(),
+            },
+        );
+        report.consolidated = Some((name.clone(), live.len()));
+        live = HashSet::from([name]);
+    }
+
+    // Mark every pack the refs don't need, whether the manifest still lists it
+    // or not. A pack an earlier gc marked may be needed again since.
+    let stored: Vec<String> = manifest.packs.keys().chain(sizes.keys()).cloned().collect();
+    for name in stored {
+        if !live.contains(&name) {
+            manifest.garbage.entry(name).or_insert(now);
+        }
+    }
+    manifest.garbage.retain(|name, _| !live.contains(name));
+
+    let mut deleted = Vec::new();
+    for (name, since) in manifest.garbage.clone() {
+        let garbage = Garbage {
+            key: packs::pack_path(settings.key(), &name),
+            size: sizes.get(&name).copied().unwrap_or_default(),
+        };
+        if now - since < grace_period {
+            report.pending.push(garbage);
+            continue;
+        }
+        manifest.garbage.remove(&name);
+        manifest.packs.remove(&name);
+        if sizes.contains_key(&name) {
+            deleted.push(garbage);
+        }
+    }
+
+    if !options.dry_run && manifest != original {
+        // Without conditional writes, this narrows the window in which a
+        // concurrent push could be lost
+        if s3::etag(s3, &manifest_key, settings.sse()?).await? != Some(etag) {
+            return Err(anyhow!("the remote was pushed to during gc, run it again"));
+        }
+        packs::write_manifest(s3, settings, &manifest).await?;
+    }
+
+    // Packs are deleted once the manifest no longer mentions them
+    for garbage in deleted {
+        if !options.dry_run {
+            info!(?garbage.key, "Deleting garbage");
+            let o = s3::Key {
+                bucket: settings.bucket().to_owned(),
+                key: garbage.key.clone(),
+            };
+            s3::del(s3, &o).await?;
+        }
+        report.deleted.push(garbage);
+    }
+
+    Ok(report)
+}
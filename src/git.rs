@@ -1,10 +1,22 @@
 use anyhow::{anyhow, Context, Result};
-use std::path::Path;
-use std::process::Command;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use tracing::{error, instrument};
 
+#[cfg(feature = "gix")]
+mod gitoxide;
+
+/// Create a bundle for a ref. Revisions in `basis` are excluded from the bundle
+/// and become its prerequisites.
 #[instrument]
-pub fn bundle_create(bundle: &Path, ref_name: &str, current_dir: &Path) -> Result<()> {
+pub fn bundle_create(
+    bundle: &Path,
+    ref_name: &str,
+    basis: &[String],
+    current_dir: &Path,
+) -> Result<()> {
     let mut cmd = Command::new("git");
     cmd.arg("bundle")
         .arg("create")
@@ -13,8 +25,11 @@ pub fn bundle_create(bundle: &Path, ref_name: &str, current_dir: &Path) -> Resul
             anyhow!("bundle path invalid")
         })?)
         .arg(ref_name);
+    for rev in basis {
+        cmd.arg(format!("^{}", rev));
+    }
 
-    let output = cmd.current_dir(&current_dir).output()?;
+    let output = cmd.current_dir(current_dir).output()?;
     if !output.status.success() {
         error!(?bundle, ?ref_name, "Git bundle create command failed");
         return Err(anyhow!("git bundle create failed"));
@@ -23,6 +38,53 @@ pub fn bundle_create(bundle: &Path, ref_name: &str, current_dir: &Path) -> Resul
     Ok(())
 }
 
+/// Create a bundle holding only the last `depth` commits of `sha`, without
+/// prerequisites, by way of a shallow fetch into a scratch repository.
+#[instrument]
+pub fn shallow_bundle_create(
+    bundle: &Path,
+    ref_name: &str,
+    sha: &str,
+    depth: u32,
+    current_dir: &Path,
+) -> Result<()> {
+    // Removed when dropped, also when a step fails
+    let scratch = tempfile::tempdir()?;
+
+    let run = |args: &[&str]| -> Result<()> {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(scratch.path())
+            .output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            error!(?args, ?stderr, "Git command failed");
+            return Err(anyhow!("git {} failed", args[0]));
+        }
+        Ok(())
+    };
+
+    run(&["init", "--quiet", "--bare"])?;
+    let source = current_dir
+        .to_str()
+        .ok_or_else(|| anyhow!("repo path invalid"))?;
+    run(&[
+        "fetch",
+        "--quiet",
+        "--no-tags",
+        &format!("--depth={}", depth),
+        // The commit may not be the tip of any local branch
+        "--upload-pack=git -c uploadpack.allowAnySHA1InWant=true upload-pack",
+        source,
+        sha,
+    ])?;
+    run(&["update-ref", ref_name, sha])?;
+    let bundle = bundle
+        .to_str()
+        .ok_or_else(|| anyhow!("bundle path invalid"))?;
+    run(&["bundle", "create", "--quiet", bundle, ref_name])
+}
+
 #[instrument]
 pub fn bundle_unbundle(bundle: &Path, ref_name: &str, current_dir: &Path) -> Result<()> {
     let mut cmd = Command::new("git");
@@ -32,7 +94,7 @@ pub fn bundle_unbundle(bundle: &Path, ref_name: &str, current_dir: &Path) -> Res
         cmd.arg(ref_name);
     }
 
-    let output = cmd.current_dir(&current_dir).output()?;
+    let output = cmd.current_dir(current_dir).output()?;
     if !output.status.success() {
         let stderr = String::from_utf8(output.stderr.clone()).unwrap_or_default();
         error!(
@@ -47,12 +109,176 @@ pub fn bundle_unbundle(bundle: &Path, ref_name: &str, current_dir: &Path) -> Res
     Ok(())
 }
 
+/// Outcome of `git bundle verify`
+#[derive(Debug, PartialEq, Eq)]
+pub enum BundleCheck {
+    Valid,
+    /// The prerequisite commits missing from the repository
+    MissingPrerequisites(Vec<String>),
+    /// Not a bundle git can read, with git's reason
+    Invalid(String),
+}
+
+/// Check that a bundle is readable and that the repository has its prerequisites.
+/// This only reads the bundle header, the pack is checked when it is unbundled.
+#[instrument]
+pub fn bundle_verify(bundle: &Path, current_dir: &Path) -> Result<BundleCheck> {
+    let mut cmd = Command::new("git");
+    // Not --quiet, which leaves out the missing prerequisites
+    cmd.args(["bundle", "verify"]).arg(bundle);
+
+    let output = cmd.current_dir(current_dir).output()?;
+    if output.status.success() {
+        return Ok(BundleCheck::Valid);
+    }
+
+    // error: Repository lacks these prerequisite commits:
+    // error: <sha> <subject>
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.contains("lacks these prerequisite commits") {
+        let missing = stderr
+            .lines()
+            .filter_map(|line| line.strip_prefix("error: ")?.split(' ').next())
+            .filter(|word| !word.is_empty() && word.chars().all(|c| c.is_ascii_hexdigit()))
+            .map(|sha| sha.to_string())
+            .collect();
+        return Ok(BundleCheck::MissingPrerequisites(missing));
+    }
+
+    let reason = stderr
+        .lines()
+        .next()
+        .unwrap_or("git bundle verify failed")
+        .trim_start_matches("error: ")
+        .trim_start_matches("fatal: ");
+    Ok(BundleCheck::Invalid(reason.to_string()))
+}
+
+/// The refs recorded in a bundle, as (sha, name) pairs
+#[instrument]
+pub fn bundle_list_heads(bundle: &Path, current_dir: &Path) -> Result<Vec<(String, String)>> {
+    let mut cmd = Command::new("git");
+    cmd.args(["bundle", "list-heads"]).arg(bundle);
+
+    let output = cmd.current_dir(current_dir).output()?;
+    if !output.status.success() {
+        error!(?bundle, "Git bundle list-heads command failed");
+        return Err(anyhow!("git bundle list-heads failed"));
+    }
+
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|e| anyhow!("git bundle list-heads output not utf8: {}", e))?;
+    Ok(stdout
+        .lines()
+        .filter_map(|line| {
+            let (sha, name) = line.split_once(' ')?;
+            Some((sha.to_string(), name.to_string()))
+        })
+        .collect())
+}
+
+/// Create an empty bare repository, replacing whatever is at `dir`
+#[instrument]
+pub fn init_bare(dir: &Path) -> Result<()> {
+    if dir.exists() {
+        std::fs::remove_dir_all(dir)?;
+    }
+    std::fs::create_dir_all(dir)?;
+
+    // The helper runs with GIT_DIR set to the repository, which would be
+    // initialized again instead
+    let output = Command::new("git")
+        .args(["init", "--quiet", "--bare"])
+        .env("GIT_DIR", dir)
+        .current_dir(dir)
+        .output()?;
+    if !output.status.success() {
+        error!(?dir, "Git init command failed");
+        return Err(anyhow!("git init failed"));
+    }
+
+    Ok(())
+}
+
+/// Import a fast-import stream into the bare repository at `git_dir`. The marks in
+/// `marks` are loaded if it exists, and it is then written with those of the stream
+/// added. Branches are updated even if that loses commits, as streams may record
+/// forced pushes. Objects always go to packs, even a few, so that they can be
+/// indexed into another repository.
+#[instrument]
+pub fn fast_import(stream: &Path, marks: Option<&Path>, git_dir: &Path) -> Result<()> {
+    let mut cmd = Command::new("git");
+    cmd.args(["-c", "fastimport.unpackLimit=0"]);
+    cmd.args(["fast-import", "--quiet", "--force"]);
+    if let Some(marks) = marks {
+        cmd.arg(format!("--import-marks-if-exists={}", marks.display()));
+        cmd.arg(format!("--export-marks={}", marks.display()));
+    }
+
+    let output = cmd
+        .env("GIT_DIR", git_dir)
+        .stdin(File::open(stream)?)
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8(output.stderr).unwrap_or_default();
+        error!(?stream, ?stderr, "Git fast-import command failed");
+        return Err(anyhow!("git fast-import failed: {}", stderr.trim()));
+    }
+    Ok(())
+}
+
+/// The refs of the bare repository at `git_dir` and their shas
+#[instrument]
+pub fn for_each_ref(git_dir: &Path) -> Result<Vec<(String, String)>> {
+    let mut cmd = Command::new("git");
+    cmd.args(["for-each-ref", "--format=%(refname) %(objectname)"]);
+
+    let output = cmd.env("GIT_DIR", git_dir).output()?;
+    if !output.status.success() {
+        error!(?git_dir, "Git for-each-ref command failed");
+        return Err(anyhow!("git for-each-ref failed"));
+    }
+
+    let output = String::from_utf8(output.stdout)
+        .map_err(|e| anyhow!("git for-each-ref output not utf8: {}", e))?;
+    Ok(output
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(name, sha)| (name.to_string(), sha.to_string()))
+        .collect())
+}
+
+/// Let the repository at `current_dir` read objects from the repository at
+/// `source`, without copying them.
+#[instrument]
+pub fn add_alternate(source: &Path, current_dir: &Path) -> Result<()> {
+    let objects = std::fs::canonicalize(git_path("objects", source)?)?;
+    let alternates = git_path("objects/info/alternates", current_dir)?;
+    if let Some(parent) = alternates.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&alternates)
+        .with_context(|| format!("Failed to open {}", alternates.display()))?;
+    writeln!(f, "{}", objects.display())?;
+    Ok(())
+}
+
 #[instrument]
 pub fn is_ancestor(base_ref: &str, remote_ref: &str, current_dir: &Path) -> Result<bool> {
+    #[cfg(feature = "gix")]
+    match gitoxide::is_ancestor(base_ref, remote_ref, current_dir) {
+        Ok(is_ancestor) => return Ok(is_ancestor),
+        Err(e) => tracing::debug!(?e, "gix ancestry check failed, falling back to git"),
+    }
+
     let mut cmd = Command::new("git");
     cmd.args(["merge-base", "--is-ancestor", base_ref, remote_ref]);
 
-    cmd.current_dir(&current_dir)
+    cmd.current_dir(current_dir)
         .output()
         .with_context(|| format!("git merge-base --is-ancestor {} {}", base_ref, remote_ref))
         .map(|output| output.status.success() && output.status.code() == Some(0))
@@ -64,10 +290,16 @@ pub fn is_ancestor(base_ref: &str, remote_ref: &str, current_dir: &Path) -> Resu
 
 #[instrument]
 pub fn rev_parse(rev: &str, current_dir: &Path) -> Result<String> {
+    #[cfg(feature = "gix")]
+    match gitoxide::rev_parse(rev, current_dir) {
+        Ok(sha) => return Ok(sha),
+        Err(e) => tracing::debug!(?e, "gix rev-parse failed, falling back to git"),
+    }
+
     let mut cmd = Command::new("git");
     cmd.arg("rev-parse").arg(rev);
 
-    let output = cmd.current_dir(&current_dir).output()?;
+    let output = cmd.current_dir(current_dir).output()?;
     if !output.status.success() {
         error!(?rev, "Git rev-parse command failed");
         return Err(anyhow!("git rev-parse failed"));
@@ -78,13 +310,488 @@ pub fn rev_parse(rev: &str, current_dir: &Path) -> Result<String> {
         .map(|s| s.trim().to_string())
 }
 
+/// Check whether an object is present in the local object database. Unlike
+/// `git cat-file -e`, this never fetches a missing object from a promisor remote,
+/// which would run this helper again from within itself.
+#[instrument]
+pub fn object_exists(sha: &str, current_dir: &Path) -> Result<bool> {
+    let mut cmd = Command::new("git");
+    cmd.args([
+        "rev-list",
+        "--no-walk",
+        "--objects",
+        "--missing=allow-any",
+        "--filter=tree:0",
+        sha,
+    ]);
+
+    cmd.current_dir(current_dir)
+        .stderr(Stdio::null())
+        .output()
+        .with_context(|| format!("git rev-list {}", sha))
+        .map(|output| output.status.success())
+}
+
+/// Write a pack of the objects reachable from `sha`, leaving out those excluded
+/// by `filter` (e.g. `blob:none`).
+#[instrument]
+pub fn pack_objects_filtered(
+    pack: &Path,
+    sha: &str,
+    filter: &str,
+    current_dir: &Path,
+) -> Result<()> {
+    pack_objects_from_stdin(
+        pack,
+        &["--revs", &format!("--filter={}", filter)],
+        &[sha.to_string()],
+        current_dir,
+    )
+}
+
+/// Write a pack of the objects reachable from the revisions in `revs`, excluding
+/// those reachable from the revisions prefixed with `^`. The pack isn't thin: it
+/// doesn't delta against the excluded objects, so it can be indexed on its own.
+#[instrument]
+pub fn pack_objects_revs(pack: &Path, revs: &[String], current_dir: &Path) -> Result<()> {
+    pack_objects_from_stdin(pack, &["--revs"], revs, current_dir)
+}
+
+/// Write a pack of exactly the given objects.
+#[instrument(skip(oids), fields(count = oids.len()))]
+pub fn pack_objects(pack: &Path, oids: &[String], current_dir: &Path) -> Result<()> {
+    pack_objects_from_stdin(pack, &[], oids, current_dir)
+}
+
+fn pack_objects_from_stdin(
+    pack: &Path,
+    args: &[&str],
+    input: &[String],
+    current_dir: &Path,
+) -> Result<()> {
+    let out =
+        File::create(pack).with_context(|| format!("Failed to create pack: {}", pack.display()))?;
+    let mut child = Command::new("git")
+        .args(["pack-objects", "--quiet", "--stdout"])
+        .args(args)
+        .current_dir(current_dir)
+        .stdin(Stdio::piped())
+        .stdout(out)
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // pack-objects reads all of its input before writing the pack to a file, so
+    // writing the whole input up front can't deadlock
+    let mut stdin = child.stdin.take().ok_or_else(|| anyhow!("no stdin"))?;
+    for line in input {
+        writeln!(stdin, "{}", line)?;
+    }
+    drop(stdin);
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        error!(?pack, ?stderr, "Git pack-objects command failed");
+        return Err(anyhow!("git pack-objects failed"));
+    }
+
+    Ok(())
+}
+
+/// Add a pack to the object database, returning its name. A pack from a promisor
+/// remote is marked as such, so that the objects it refers to but doesn't hold may
+/// be fetched later.
+#[instrument]
+pub fn index_pack(pack: &Path, promisor: bool, current_dir: &Path) -> Result<String> {
+    let input =
+        File::open(pack).with_context(|| format!("Failed to open pack: {}", pack.display()))?;
+    let mut cmd = Command::new("git");
+    cmd.args(["index-pack", "--stdin"]);
+    if promisor {
+        cmd.arg("--promisor");
+    }
+
+    let output = cmd.current_dir(current_dir).stdin(input).output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        error!(?pack, ?stderr, "Git index-pack command failed");
+        return Err(anyhow!("git index-pack failed"));
+    }
+
+    // pack TAB <name>
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|e| anyhow!("git index-pack output not utf8: {}", e))?;
+    stdout
+        .split_whitespace()
+        .nth(1)
+        .map(|name| name.to_string())
+        .ok_or_else(|| anyhow!("git index-pack printed no pack name"))
+}
+
+/// The objects held by a pack of the object database, named as by `index_pack`.
+#[instrument]
+pub fn pack_contents(name: &str, current_dir: &Path) -> Result<Vec<String>> {
+    let idx = git_path(&format!("objects/pack/pack-{}.idx", name), current_dir)?;
+    show_index(&idx, current_dir)
+}
+
+/// The objects held by a pack file, which is checked by writing its index next to
+/// it, outside the object database.
+#[instrument]
+pub fn pack_file_contents(pack: &Path, current_dir: &Path) -> Result<Vec<String>> {
+    let idx = pack.with_extension("idx");
+    let output = Command::new("git")
+        .args(["index-pack", "-o"])
+        .arg(&idx)
+        .arg(pack)
+        .current_dir(current_dir)
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        error!(?pack, ?stderr, "Git index-pack command failed");
+        let reason = stderr
+            .lines()
+            .next()
+            .unwrap_or("git index-pack failed")
+            .trim_start_matches("error: ")
+            .trim_start_matches("fatal: ");
+        return Err(anyhow!("{}", reason));
+    }
+    show_index(&idx, current_dir)
+}
+
+fn show_index(idx: &Path, current_dir: &Path) -> Result<Vec<String>> {
+    let input = File::open(idx).with_context(|| format!("Failed to open {}", idx.display()))?;
+
+    let output = Command::new("git")
+        .arg("show-index")
+        .current_dir(current_dir)
+        .stdin(input)
+        .output()?;
+    if !output.status.success() {
+        error!(?idx, "Git show-index command failed");
+        return Err(anyhow!("git show-index failed"));
+    }
+
+    // <offset> SP <oid> SP (<crc32>)
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|e| anyhow!("git show-index output not utf8: {}", e))?;
+    Ok(stdout
+        .lines()
+        .filter_map(|line| line.split(' ').nth(1))
+        .map(|oid| oid.to_string())
+        .collect())
+}
+
+/// The blobs in the tree of a commit, i.e. the files of a checkout. Only the trees
+/// need to be present.
+#[instrument]
+pub fn tree_blobs(sha: &str, current_dir: &Path) -> Result<Vec<String>> {
+    let mut cmd = Command::new("git");
+    cmd.args(["ls-tree", "-r", sha]);
+
+    let output = cmd.current_dir(current_dir).output()?;
+    if !output.status.success() {
+        error!(?sha, "Git ls-tree command failed");
+        return Err(anyhow!("git ls-tree failed"));
+    }
+
+    // <mode> SP <type> SP <oid> TAB <path>, where submodules have type commit
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|e| anyhow!("git ls-tree output not utf8: {}", e))?;
+    Ok(stdout
+        .lines()
+        .filter_map(|line| {
+            let (info, _path) = line.split_once('\t')?;
+            let mut fields = info.split(' ').skip(1);
+            match (fields.next()?, fields.next()?) {
+                ("blob", oid) => Some(oid.to_string()),
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+/// Objects reachable from `sha` that are missing from a partial clone, found
+/// without fetching them.
+#[instrument]
+pub fn missing_objects(sha: &str, current_dir: &Path) -> Result<Vec<String>> {
+    let mut cmd = Command::new("git");
+    cmd.args([
+        "rev-list",
+        "--objects",
+        "--no-object-names",
+        "--missing=print",
+        sha,
+    ]);
+
+    let output = cmd.current_dir(current_dir).output()?;
+    if !output.status.success() {
+        error!(?sha, "Git rev-list command failed");
+        return Err(anyhow!("git rev-list failed"));
+    }
+
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|e| anyhow!("git rev-list output not utf8: {}", e))?;
+    Ok(stdout
+        .lines()
+        .filter_map(|line| line.strip_prefix('?'))
+        .map(|oid| oid.to_string())
+        .collect())
+}
+
+/// The parents of a commit, read from the `parent` lines of the commit object.
+/// Unlike `<sha>^@`, this ignores the shallow file, which hides the parents of
+/// commits on the shallow boundary.
+#[instrument]
+pub fn commit_parents(sha: &str, current_dir: &Path) -> Result<Vec<String>> {
+    let mut cmd = Command::new("git");
+    cmd.args(["cat-file", "commit", sha]);
+
+    let output = cmd.current_dir(current_dir).output()?;
+    if !output.status.success() {
+        error!(?sha, "Git cat-file command failed");
+        return Err(anyhow!("git cat-file failed"));
+    }
+
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|e| anyhow!("git cat-file output not utf8: {}", e))?;
+    Ok(stdout
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.strip_prefix("parent "))
+        .map(|parent| parent.to_string())
+        .collect())
+}
+
+/// Path of a file in the git directory, e.g. `shallow` or `objects`
+pub fn git_path(name: &str, current_dir: &Path) -> Result<PathBuf> {
+    let mut cmd = Command::new("git");
+    cmd.args(["rev-parse", "--git-path", name]);
+
+    let output = cmd.current_dir(current_dir).output()?;
+    if !output.status.success() {
+        error!("Git rev-parse --git-path command failed");
+        return Err(anyhow!("git rev-parse failed"));
+    }
+
+    let path = String::from_utf8(output.stdout)
+        .map_err(|e| anyhow!("git rev-parse output not utf8: {}", e))?;
+    Ok(current_dir.join(path.trim()))
+}
+
+/// The shallow boundary of the repository: commits whose parents are missing.
+#[instrument]
+pub fn read_shallow(current_dir: &Path) -> Result<Vec<String>> {
+    let shallow_file = git_path("shallow", current_dir)?;
+    match std::fs::read_to_string(&shallow_file) {
+        Ok(contents) => Ok(contents.lines().map(|line| line.to_string()).collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", shallow_file.display())),
+    }
+}
+
+/// Replace the shallow boundary of the repository. An empty boundary makes the
+/// repository complete again.
+#[instrument]
+pub fn write_shallow(shas: &[String], current_dir: &Path) -> Result<()> {
+    let shallow_file = git_path("shallow", current_dir)?;
+    if shas.is_empty() {
+        if shallow_file.exists() {
+            std::fs::remove_file(&shallow_file)?;
+        }
+        return Ok(());
+    }
+
+    let contents: String = shas.iter().map(|sha| format!("{}\n", sha)).collect();
+    std::fs::write(&shallow_file, contents)
+        .with_context(|| format!("Failed to write {}", shallow_file.display()))
+}
+
+/// The subject line of a commit's message
+#[instrument]
+pub fn commit_summary(sha: &str, current_dir: &Path) -> Result<String> {
+    let mut cmd = Command::new("git");
+    cmd.args(["log", "-1", "--format=%s", sha]);
+
+    let output = cmd.current_dir(current_dir).output()?;
+    if !output.status.success() {
+        error!(?sha, "Git log command failed");
+        return Err(anyhow!("git log failed"));
+    }
+
+    String::from_utf8(output.stdout)
+        .map_err(|e| anyhow!("git log output not utf8: {}", e))
+        .map(|s| s.trim().to_string())
+}
+
+/// Merge two commits without touching the index or the working tree, returning
+/// the tree of the merge, or `None` if they conflict.
+#[instrument]
+pub fn merge_tree(ours: &str, theirs: &str, current_dir: &Path) -> Result<Option<String>> {
+    let mut cmd = Command::new("git");
+    cmd.args(["merge-tree", "--write-tree", "--no-messages", ours, theirs]);
+
+    let output = cmd.current_dir(current_dir).output()?;
+    match output.status.code() {
+        Some(0) => String::from_utf8(output.stdout)
+            .map_err(|e| anyhow!("git merge-tree output not utf8: {}", e))
+            .map(|s| s.lines().next().map(|tree| tree.to_string())),
+        Some(1) => Ok(None),
+        _ => {
+            let stderr = String::from_utf8(output.stderr).unwrap_or_default();
+            error!(?ours, ?theirs, ?stderr, "Git merge-tree command failed");
+            Err(anyhow!("git merge-tree failed"))
+        }
+    }
+}
+
+/// Create a commit of `tree` with the given parents
+#[instrument]
+pub fn commit_tree(
+    tree: &str,
+    parents: &[String],
+    message: &str,
+    current_dir: &Path,
+) -> Result<String> {
+    let mut cmd = Command::new("git");
+    cmd.args(["commit-tree", tree, "-m", message]);
+    for parent in parents {
+        cmd.arg("-p").arg(parent);
+    }
+
+    let output = cmd.current_dir(current_dir).output()?;
+    if !output.status.success() {
+        error!(?tree, ?parents, "Git commit-tree command failed");
+        return Err(anyhow!("git commit-tree failed"));
+    }
+
+    String::from_utf8(output.stdout)
+        .map_err(|e| anyhow!("git commit-tree output not utf8: {}", e))
+        .map(|s| s.trim().to_string())
+}
+
+/// Point a ref at `sha`, creating it if needed
+#[instrument]
+pub fn update_ref(name: &str, sha: &str, current_dir: &Path) -> Result<()> {
+    let mut cmd = Command::new("git");
+    cmd.args(["update-ref", name, sha]);
+
+    let output = cmd.current_dir(current_dir).output()?;
+    if !output.status.success() {
+        error!(?name, ?sha, "Git update-ref command failed");
+        return Err(anyhow!("git update-ref failed"));
+    }
+    Ok(())
+}
+
+/// Delete a ref
+#[instrument]
+pub fn delete_ref(name: &str, current_dir: &Path) -> Result<()> {
+    let mut cmd = Command::new("git");
+    cmd.args(["update-ref", "-d", name]);
+
+    let output = cmd.current_dir(current_dir).output()?;
+    if !output.status.success() {
+        error!(?name, "Git update-ref command failed");
+        return Err(anyhow!("git update-ref -d failed"));
+    }
+    Ok(())
+}
+
+/// The refs under `prefix` and their shas
+#[instrument]
+pub fn refs_under(prefix: &str, current_dir: &Path) -> Result<Vec<(String, String)>> {
+    let mut cmd = Command::new("git");
+    cmd.args(["for-each-ref", "--format=%(refname) %(objectname)", prefix]);
+
+    let output = cmd.current_dir(current_dir).output()?;
+    if !output.status.success() {
+        error!(?prefix, "Git for-each-ref command failed");
+        return Err(anyhow!("git for-each-ref failed"));
+    }
+
+    let output = String::from_utf8(output.stdout)
+        .map_err(|e| anyhow!("git for-each-ref output not utf8: {}", e))?;
+    Ok(output
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(name, sha)| (name.to_string(), sha.to_string()))
+        .collect())
+}
+
+/// Fast-forward the checked out branch and the working tree to `sha`
+#[instrument]
+pub fn merge_ff_only(sha: &str, current_dir: &Path) -> Result<()> {
+    let mut cmd = Command::new("git");
+    cmd.args(["merge", "--ff-only", "--quiet", sha]);
+
+    let output = cmd.current_dir(current_dir).output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8(output.stderr).unwrap_or_default();
+        error!(?sha, ?stderr, "Git merge --ff-only command failed");
+        return Err(anyhow!("git merge --ff-only failed: {}", stderr.trim()));
+    }
+    Ok(())
+}
+
+/// Resolve the ref a symbolic ref such as `HEAD` points to
+#[instrument]
+pub fn symbolic_ref(name: &str, current_dir: &Path) -> Result<String> {
+    let mut cmd = Command::new("git");
+    cmd.args(["symbolic-ref", "--quiet", name]);
+
+    let output = cmd.current_dir(current_dir).output()?;
+    if !output.status.success() {
+        error!(?name, "Git symbolic-ref command failed");
+        return Err(anyhow!("git symbolic-ref failed"));
+    }
+
+    String::from_utf8(output.stdout)
+        .map_err(|e| anyhow!("git symbolic-ref output not utf8: {}", e))
+        .map(|s| s.trim().to_string())
+}
+
+/// Every git config setting whose name matches a regular expression, as name and
+/// value pairs. Names are lowercased by git, except for their subsection.
+#[instrument]
+pub fn config_regexp(pattern: &str, current_dir: &Path) -> Result<Vec<(String, String)>> {
+    let mut cmd = Command::new("git");
+    cmd.args(["config", "--get-regexp", pattern]);
+
+    let output = cmd.current_dir(current_dir).output()?;
+    // git config exits with 1 when nothing matches
+    if output.status.code() == Some(1) {
+        return Ok(Vec::new());
+    }
+    if !output.status.success() {
+        error!(?pattern, "Git config --get-regexp command failed");
+        return Err(anyhow!("git config failed"));
+    }
+
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|e| anyhow!("git config output not utf8: {}", e))?;
+    Ok(stdout
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect())
+}
+
 // Read a git config setting
 #[instrument]
 pub fn config(setting: &str, current_dir: &Path) -> Result<String> {
+    #[cfg(feature = "gix")]
+    match gitoxide::config(setting, current_dir) {
+        Ok(Some(value)) => return Ok(value),
+        Ok(None) => return Err(anyhow!("git config {} is not set", setting)),
+        Err(e) => tracing::debug!(?e, "gix config failed, falling back to git"),
+    }
+
     let mut cmd = Command::new("git");
     cmd.args(["config", setting]);
 
-    cmd.current_dir(&current_dir).output().map(|output| {
+    cmd.current_dir(current_dir).output().map(|output| {
         if !output.status.success() {
             error!(?cmd, ?output.stderr, "Command failed");
             return Err(anyhow!("git config failed"));
@@ -0,0 +1,39 @@
+//! In-process implementations of the git operations, backed by gitoxide. Each
+//! returns an error when gitoxide can't answer, and the caller falls back to the
+//! `git` subprocess.
+
+use anyhow::{anyhow, Result};
+use std::path::Path;
+
+fn open(current_dir: &Path) -> Result<gix::Repository> {
+    // Honours GIT_DIR and friends, like the git subprocess does
+    Ok(gix::discover_with_environment_overrides(current_dir)?)
+}
+
+/// The value of a config setting, `None` when it isn't set.
+pub fn config(setting: &str, current_dir: &Path) -> Result<Option<String>> {
+    let repo = open(current_dir)?;
+    let value = repo.config_snapshot().string(setting);
+    Ok(value.map(|v| v.to_string()))
+}
+
+pub fn rev_parse(rev: &str, current_dir: &Path) -> Result<String> {
+    let repo = open(current_dir)?;
+    let id = repo.rev_parse_single(rev)?;
+    Ok(id.detach().to_string())
+}
+
+pub fn is_ancestor(base_ref: &str, remote_ref: &str, current_dir: &Path) -> Result<bool> {
+    let repo = open(current_dir)?;
+    let base = repo.rev_parse_single(base_ref)?.detach();
+    let remote = repo.rev_parse_single(remote_ref)?.detach();
+    if base == remote {
+        return Ok(true);
+    }
+
+    match repo.merge_base(base, remote) {
+        Ok(merge_base) => Ok(merge_base.detach() == base),
+        Err(gix::repository::merge_base::Error::NotFound { .. }) => Ok(false),
+        Err(e) => Err(anyhow!(e)),
+    }
+}
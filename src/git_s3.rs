@@ -1,14 +1,22 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use aws_sdk_s3::Client;
+use futures::stream::{self, StreamExt, TryStreamExt};
 use once_cell::sync::OnceCell;
 use std::{
     cmp::Reverse,
-    collections::{BTreeMap, HashMap},
-    env::{current_dir, temp_dir},
+    collections::{BTreeMap, HashMap, HashSet},
+    env::current_dir,
+    path::Path,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
 };
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
-use crate::{git, gpg, s3};
+use crate::cache::BundleCache;
+use crate::git::BundleCheck;
+use crate::{fastexport, git, gpg, packs, pad, s3};
+
+const DEFAULT_FETCH_JOBS: usize = 4;
 
 #[derive(Debug)]
 pub struct GitS3Settings {
@@ -21,9 +29,26 @@ pub struct GitS3Settings {
     key: OnceCell<String>,
     endpoint: OnceCell<Option<String>>,
     region: OnceCell<Option<String>>,
+    padding: OnceCell<Option<pad::Padding>>,
+    sse: OnceCell<s3::Sse>,
+    layout: OnceCell<Layout>,
 }
 
 impl GitS3Settings {
+    /// Check that a URL has the `s3://<bucket>/<prefix>` form the settings expect.
+    pub fn validate_url(url: &str) -> Result<()> {
+        match url
+            .strip_prefix("s3://")
+            .and_then(|path| path.split_once('/'))
+        {
+            Some((bucket, prefix)) if !bucket.is_empty() && !prefix.is_empty() => Ok(()),
+            _ => Err(anyhow!(
+                "invalid url {}, expected s3://<bucket>/<prefix>",
+                url
+            )),
+        }
+    }
+
     pub fn new(remote_alias: String, url: String) -> Self {
         assert!(url.starts_with("s3://"));
         GitS3Settings {
@@ -33,7 +58,40 @@ impl GitS3Settings {
             key: OnceCell::new(),
             endpoint: OnceCell::new(),
             region: OnceCell::new(),
+            padding: OnceCell::new(),
+            sse: OnceCell::new(),
+            layout: OnceCell::new(),
+        }
+    }
+
+    /// Settings for a remote given by name (e.g. `origin`) or by `s3://` URL. A URL
+    /// uses the `remote.<alias>.*` settings of the configured remote with that URL,
+    /// if there is one.
+    pub fn from_remote(remote: &str) -> Result<Self> {
+        let current_dir = current_dir()?;
+        if remote.starts_with("s3://") {
+            Self::validate_url(remote)?;
+            let alias = git::config_regexp(r"^remote\..*\.url$", &current_dir)?
+                .into_iter()
+                .filter(|(_, url)| url == remote)
+                .find_map(|(key, _)| {
+                    Some(
+                        key.strip_prefix("remote.")?
+                            .strip_suffix(".url")?
+                            .to_string(),
+                    )
+                })
+                .unwrap_or_else(|| remote.to_string());
+            return Ok(GitS3Settings::new(alias, remote.to_string()));
+        }
+
+        let url = git::config(&format!("remote.{}.url", remote), &current_dir)
+            .map_err(|_| anyhow!("no such remote: {}", remote))?;
+        if !url.starts_with("s3://") {
+            return Err(anyhow!("not an s3 remote: {} ({})", remote, url));
         }
+        Self::validate_url(&url)?;
+        Ok(GitS3Settings::new(remote.to_string(), url))
     }
 
     pub fn bucket(&self) -> &str {
@@ -61,66 +119,565 @@ impl GitS3Settings {
             .get_or_init(|| std::env::var("AWS_REGION").ok())
             .as_deref()
     }
+
+    /// Padding applied to uploaded bundles, from `remote.<alias>.padding`.
+    pub fn padding(&self) -> Result<Option<pad::Padding>> {
+        self.padding
+            .get_or_try_init(|| self.remote_config("padding").map(|p| p.parse()).transpose())
+            .copied()
+    }
+
+    /// Server-side encryption for objects, from `remote.<alias>.sse` (`s3`, `kms`
+    /// or `customer`) and the related `sseKmsKeyId`, `sseKmsContext` and
+    /// `sseCustomerKeyFile` settings.
+    pub fn sse(&self) -> Result<&s3::Sse> {
+        self.sse
+            .get_or_try_init(|| match self.remote_config("sse").as_deref() {
+                None => Ok(s3::Sse::None),
+                Some("s3") => Ok(s3::Sse::S3),
+                Some("kms") => Ok(s3::Sse::Kms {
+                    key_id: self.remote_config("sseKmsKeyId"),
+                    context: self.remote_config("sseKmsContext"),
+                }),
+                Some("customer") => {
+                    let key_file = self
+                        .remote_config("sseCustomerKeyFile")
+                        .ok_or_else(|| anyhow!("sse=customer requires sseCustomerKeyFile"))?;
+                    s3::Sse::customer_from_file(Path::new(&key_file))
+                }
+                Some(other) => Err(anyhow!("unknown sse setting: {}", other)),
+            })
+    }
+
+    /// Storage class for objects of the given ref class, from
+    /// `remote.<alias>.<class>StorageClass`, falling back to `remote.<alias>.storageClass`.
+    pub fn storage_class(&self, class: RefClass) -> Option<String> {
+        self.class_config(class, "StorageClass")
+            .or_else(|| self.remote_config("storageClass"))
+    }
+
+    /// Tags for objects of the given ref class, URL query encoded (e.g.
+    /// `team=infra&env=prod`), from `remote.<alias>.<class>ObjectTags`, falling back
+    /// to `remote.<alias>.objectTags`.
+    pub fn object_tags(&self, class: RefClass) -> Option<String> {
+        self.class_config(class, "ObjectTags")
+            .or_else(|| self.remote_config("objectTags"))
+    }
+
+    /// Extra user metadata for objects of the given ref class, as `name=value`
+    /// pairs joined with `&`, from `remote.<alias>.<class>ObjectMetadata`, falling
+    /// back to `remote.<alias>.objectMetadata`.
+    pub fn object_metadata(&self, class: RefClass) -> Result<HashMap<String, String>> {
+        let Some(config) = self
+            .class_config(class, "ObjectMetadata")
+            .or_else(|| self.remote_config("objectMetadata"))
+        else {
+            return Ok(HashMap::new());
+        };
+        config
+            .split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((name, value)) if !name.is_empty() => {
+                    Ok((name.to_string(), value.to_string()))
+                }
+                _ => Err(anyhow!("invalid objectMetadata: {}", config)),
+            })
+            .collect()
+    }
+
+    /// Attributes for uploading an object of the given ref class, with `metadata`
+    /// on top of the configured metadata.
+    pub fn attributes(
+        &self,
+        class: RefClass,
+        metadata: HashMap<String, String>,
+    ) -> Result<s3::Attributes> {
+        let mut configured = self.object_metadata(class)?;
+        configured.extend(metadata);
+        Ok(s3::Attributes {
+            storage_class: self.storage_class(class),
+            tags: self.object_tags(class),
+            metadata: configured,
+        })
+    }
+
+    /// Whether stale heads are stored differently from the heads they were, from
+    /// any of the `stale*` settings.
+    pub fn demotes_stale_heads(&self) -> bool {
+        ["StorageClass", "ObjectTags", "ObjectMetadata"]
+            .iter()
+            .any(|name| self.class_config(RefClass::Stale, name).is_some())
+    }
+
+    /// A setting of a ref class, i.e. `remote.<alias>.<class><name>`
+    fn class_config(&self, class: RefClass, name: &str) -> Option<String> {
+        self.remote_config(&format!("{}{}", class.as_str(), name))
+    }
+
+    /// Number of bundles downloaded and decrypted concurrently during a fetch, and
+    /// of head generations read concurrently while listing, from
+    /// `remote.<alias>.fetchJobs`.
+    pub fn fetch_jobs(&self) -> Result<usize> {
+        match self.remote_config("fetchJobs") {
+            Some(jobs) => jobs
+                .parse()
+                .ok()
+                .filter(|jobs| *jobs > 0)
+                .ok_or_else(|| anyhow!("invalid fetchJobs: {}", jobs)),
+            None => Ok(DEFAULT_FETCH_JOBS),
+        }
+    }
+
+    /// Depth of the shallow snapshot uploaded next to each branch bundle, from
+    /// `remote.<alias>.shallowDepth`. Unset means no snapshots.
+    pub fn shallow_depth(&self) -> Result<Option<u32>> {
+        self.remote_config("shallowDepth")
+            .map(|depth| {
+                depth
+                    .parse()
+                    .ok()
+                    .filter(|depth| *depth > 0)
+                    .ok_or_else(|| anyhow!("invalid shallowDepth: {}", depth))
+            })
+            .transpose()
+    }
+
+    /// Whether refs are also uploaded as a pack of commits and trees and a pack of
+    /// the blobs in the tree of the ref's commit, for partial clones. From
+    /// `remote.<alias>.blobPacks`.
+    pub fn blob_packs(&self) -> bool {
+        self.remote_config("blobPacks")
+            .is_some_and(|value| matches!(value.as_str(), "true" | "yes" | "on" | "1"))
+    }
+
+    /// Whether fetches and pushes go through git's smart protocol against a local
+    /// mirror of the remote, instead of whole bundles. From `remote.<alias>.localMirror`.
+    pub fn local_mirror(&self) -> bool {
+        self.remote_config("localMirror")
+            .is_some_and(|value| matches!(value.as_str(), "true" | "yes" | "on" | "1"))
+    }
+
+    /// The alias with characters other than letters, digits, `-`, `_` and `.`
+    /// replaced, for naming files under `$GIT_DIR/s3` and private refs.
+    pub fn safe_alias(&self) -> String {
+        self.remote_alias
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+                _ => '_',
+            })
+            .collect()
+    }
+
+    /// The namespace of the remote's private refs, `refs/s3/<alias>`
+    pub fn private_namespace(&self) -> String {
+        format!("refs/s3/{}", self.safe_alias())
+    }
+
+    /// The `refspec` capability, mapping the branches of the remote to
+    /// `refs/s3/<alias>/heads/`
+    pub fn refspec(&self) -> String {
+        format!("refs/heads/*:{}/heads/*", self.private_namespace())
+    }
+
+    /// The hidden ref of a stale head of `name`: `refs/heads/main` at `sha` is kept
+    /// as `refs/s3/<alias>/stale/heads/main__<short sha>`
+    pub fn stale_ref(&self, name: &str, sha: &str) -> String {
+        format!(
+            "{}/stale/{}__{}",
+            self.private_namespace(),
+            name.strip_prefix("refs/").unwrap_or(name),
+            &sha[..7]
+        )
+    }
+
+    /// GPG recipients for uploaded objects, from `remote.<alias>.gpgRecipients`,
+    /// falling back to `user.email`.
+    pub fn gpg_recipients(&self) -> Result<Vec<String>> {
+        match self.remote_config("gpgRecipients") {
+            Some(config) => Ok(config
+                .split_ascii_whitespace()
+                .map(|s| s.to_string())
+                .collect()),
+            None => Ok(vec![git::config("user.email", &current_dir()?)?]),
+        }
+    }
+
+    /// Read a per-remote git config setting, i.e. `remote.<alias>.<name>`.
+    pub fn remote_config(&self, name: &str) -> Option<String> {
+        let current_dir = current_dir().ok()?;
+        git::config(
+            &format!("remote.{}.{}", self.remote_alias, name),
+            &current_dir,
+        )
+        .ok()
+    }
 }
 
-#[derive(Debug)]
+/// How refs and objects are stored under the prefix, recorded as a version number
+/// in the `LAYOUT` object at the prefix root. Remotes without it use bundles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// One full bundle per ref and sha: `<prefix>/<ref>/<sha>.bundle`
+    Bundles,
+    /// Content-addressed packs shared by all refs, and an encrypted manifest of
+    /// the refs and the packs they need
+    Packs,
+    /// Encrypted fast-export streams with their marks files, replayed in order by
+    /// the `import` and `export` commands, and an encrypted manifest of the refs
+    FastExport,
+}
+
+impl Layout {
+    pub fn version(&self) -> u32 {
+        match self {
+            Layout::Bundles => 1,
+            Layout::Packs => 2,
+            Layout::FastExport => 3,
+        }
+    }
+
+    pub fn from_version(version: u32) -> Option<Layout> {
+        match version {
+            1 => Some(Layout::Bundles),
+            2 => Some(Layout::Packs),
+            3 => Some(Layout::FastExport),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Layout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bundles" => Ok(Layout::Bundles),
+            "packs" => Ok(Layout::Packs),
+            "fast-export" => Ok(Layout::FastExport),
+            _ => Err(anyhow!("unknown layout: {}", s)),
+        }
+    }
+}
+
+fn layout_path(prefix: &str) -> String {
+    format!("{}/LAYOUT", prefix)
+}
+
+/// The layout of the remote, read from its version marker once per session. A
+/// remote without a marker uses bundles, unless it is still empty and
+/// `remote.<alias>.layout` asks for another layout, which its first push records.
+pub async fn layout(s3: &Client, settings: &GitS3Settings) -> Result<Layout> {
+    if let Some(layout) = settings.layout.get() {
+        return Ok(*layout);
+    }
+
+    let tmp_dir = tempfile::tempdir()?;
+    let marker_file = tmp_dir.path().join("LAYOUT");
+    let o = s3::Key {
+        bucket: settings.bucket().to_owned(),
+        key: layout_path(settings.key()),
+    };
+    let layout = if s3::try_get(s3, &marker_file, &o, settings.sse()?).await? {
+        let version = std::fs::read_to_string(&marker_file)?;
+        let version = version.trim();
+        version
+            .parse()
+            .ok()
+            .and_then(Layout::from_version)
+            .ok_or_else(|| anyhow!("unsupported layout version: {}", version))?
+    } else {
+        match settings.remote_config("layout") {
+            Some(config) => {
+                let configured: Layout = config.parse()?;
+                if configured != Layout::Bundles && !is_empty(s3, settings).await? {
+                    warn!(
+                        ?configured,
+                        "Remote already holds bundles, ignoring layout setting"
+                    );
+                    Layout::Bundles
+                } else {
+                    configured
+                }
+            }
+            None => Layout::Bundles,
+        }
+    };
+    debug!(?layout, "Remote layout");
+
+    Ok(*settings.layout.get_or_init(|| layout))
+}
+
+/// Records the layout of the remote in its version marker.
+pub async fn write_layout(s3: &Client, settings: &GitS3Settings, layout: Layout) -> Result<()> {
+    info!(?layout, "Writing layout version marker");
+    let tmp_dir = tempfile::tempdir()?;
+    let marker_file = tmp_dir.path().join("LAYOUT");
+    std::fs::write(&marker_file, format!("{}\n", layout.version()))?;
+
+    let o = s3::Key {
+        bucket: settings.bucket().to_owned(),
+        key: layout_path(settings.key()),
+    };
+    s3::put(
+        s3,
+        &marker_file,
+        &o,
+        settings.sse()?,
+        &s3::Attributes::default(),
+    )
+    .await
+}
+
+async fn is_empty(s3: &Client, settings: &GitS3Settings) -> Result<bool> {
+    let result = s3
+        .list_objects_v2()
+        .bucket(settings.bucket())
+        .prefix(format!("{}/", settings.key()))
+        .max_keys(1)
+        .send()
+        .await?;
+    Ok(result.key_count() == 0)
+}
+
+/// Classes of refs that can be stored with different object attributes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefClass {
+    Heads,
+    Tags,
+    /// Heads that have been superseded by a newer head for the same ref
+    Stale,
+}
+
+impl RefClass {
+    pub fn of(name: &str) -> RefClass {
+        if name.starts_with("refs/tags/") {
+            RefClass::Tags
+        } else {
+            RefClass::Heads
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RefClass::Heads => "heads",
+            RefClass::Tags => "tags",
+            RefClass::Stale => "stale",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct GitRef {
     pub name: String,
     pub sha: String,
 }
 
 impl GitRef {
-    fn bundle_path(&self, prefix: &str) -> String {
+    pub fn bundle_path(&self, prefix: &str) -> String {
         format!("{}/{}/{}.bundle", prefix, self.name, self.sha)
     }
+
+    /// Empty marker object recording what an annotated tag peels to, so that
+    /// listing the remote is enough to advertise `<tag>^{}` entries.
+    fn peeled_path(&self, prefix: &str, peeled: &str) -> String {
+        format!("{}/{}/{}.peeled.{}", prefix, self.name, self.sha, peeled)
+    }
+
+    /// Parse an S3 key produced by `peeled_path` back into a ref and its peeled sha.
+    fn from_peeled_path(prefix: &str, key: &str) -> Option<(GitRef, String)> {
+        let (path, peeled) = key.rsplit_once(".peeled.")?;
+        Some((GitRef::from_path(prefix, path)?, peeled.to_string()))
+    }
+
+    /// Empty marker object recording a branch head that a thin tag bundle was
+    /// built on, so that gc keeps the head and fetches can download it first.
+    fn basis_path(&self, prefix: &str, head: &str) -> String {
+        format!("{}/{}/{}.basis.{}", prefix, self.name, self.sha, head)
+    }
+
+    /// Parse an S3 key produced by `basis_path` back into a ref and the head.
+    fn from_basis_path(prefix: &str, key: &str) -> Option<(GitRef, String)> {
+        let (path, head) = key.rsplit_once(".basis.")?;
+        Some((GitRef::from_path(prefix, path)?, head.to_string()))
+    }
+
+    /// Bundle holding only the last `depth` commits of the ref, for shallow clones.
+    fn shallow_path(&self, prefix: &str, depth: u32) -> String {
+        format!("{}/{}/{}.shallow.{}", prefix, self.name, self.sha, depth)
+    }
+
+    /// Parse an S3 key produced by `shallow_path` back into a ref and its depth.
+    fn from_shallow_path(prefix: &str, key: &str) -> Option<(GitRef, u32)> {
+        let (path, depth) = key.rsplit_once(".shallow.")?;
+        Some((GitRef::from_path(prefix, path)?, depth.parse().ok()?))
+    }
+
+    /// Pack of the commits and trees reachable from the ref, for partial clones.
+    fn trees_path(&self, prefix: &str) -> String {
+        format!("{}/{}/{}.trees", prefix, self.name, self.sha)
+    }
+
+    /// Parse an S3 key produced by `trees_path` back into a ref.
+    fn from_trees_path(prefix: &str, key: &str) -> Option<GitRef> {
+        GitRef::from_path(prefix, key.strip_suffix(".trees")?)
+    }
+
+    /// Pack of the blobs in the tree of the ref's commit, fetched on demand by
+    /// partial clones.
+    fn blobs_path(&self, prefix: &str) -> String {
+        format!("{}/{}/{}.blobs", prefix, self.name, self.sha)
+    }
+
+    /// Parse `<prefix>/<name>/<sha>`, a key with its suffix removed.
+    fn from_path(prefix: &str, path: &str) -> Option<GitRef> {
+        let (name, sha) = path
+            .strip_prefix(prefix)?
+            .trim_start_matches('/')
+            .rsplit_once('/')?;
+
+        Some(GitRef {
+            name: name.to_string(),
+            sha: sha.to_string(),
+        })
+    }
+
+    /// Parse an S3 key produced by `bundle_path` back into a ref.
+    fn from_bundle_path(prefix: &str, key: &str) -> Option<GitRef> {
+        // key = project1.git/refs/heads/features/fXXX/99d98906d65894a9eac5fda27b0c41d2cf372dd6.bundle
+        let mut parts = key.strip_suffix(".bundle")?.rsplit('/');
+        let sha = parts.next()?; // sha = 99d98906d65894a9eac5fda27b0c41d2cf372dd6
+
+        // name = refs/heads/features/fXXX
+        let name = key
+            .strip_prefix(prefix)? // Remove prefix (e.g. "project1.git")
+            .trim_start_matches('/')
+            .strip_suffix(&format!("/{}.bundle", sha))? // Remove suffix (e.g. "/[sha].bundle")
+            .to_string();
+
+        Some(GitRef {
+            name,
+            sha: sha.to_string(),
+        })
+    }
 }
 
 #[derive(Debug)]
 pub struct RemoteRef {
-    /// Last modified timestamp of the S3 object, used for sorting refs by update time.
-    /// Stored as Unix timestamp in nanoseconds since epoch.
+    /// Last modified timestamp of the S3 object, used for sorting heads pushed with
+    /// the same generation. Stored as Unix timestamp in nanoseconds since epoch.
     ///
     /// # Example
     /// ```
     /// # use git_remote_s3::git_s3::{RemoteRef, GitRef};
     /// let remote_ref = RemoteRef {
-    ///     updated: 1701925200_000_000_000, // Dec 7, 2023 00:00:00.000000000 UTC
+    ///     updated: 1_701_925_200_000_000_000, // Dec 7, 2023 00:00:00.000000000 UTC
+    ///     generation: 1,
     ///     reference: GitRef {
     ///         name: "main".to_string(),
     ///         sha: "abc123".to_string(),
     ///     },
+    ///     storage_class: None,
+    ///     peeled: None,
+    ///     basis: Vec::new(),
+    ///     shallow_depth: None,
+    ///     blob_packs: false,
     /// };
     /// ```
     pub updated: i128,
+    /// Push sequence number of the head within its ref, stored in the bundle's
+    /// metadata. S3 timestamps have a one-second resolution and are reset by copies,
+    /// so heads are ordered by generation first. 0 for heads pushed before it was
+    /// recorded, and for single heads whose metadata wasn't read.
+    pub generation: u64,
     pub reference: GitRef,
+    /// Storage class of the S3 object, if reported by S3.
+    pub storage_class: Option<String>,
+    /// For annotated tags, the commit the tag object points to.
+    pub peeled: Option<String>,
+    /// For thin tag bundles, the branch heads whose commits the bundle leaves out.
+    pub basis: Vec<String>,
+    /// Depth of the shallow snapshot stored next to the bundle, if any.
+    pub shallow_depth: Option<u32>,
+    /// Whether tree and blob packs for partial clones are stored next to the bundle.
+    pub blob_packs: bool,
 }
 
-#[derive(Debug)]
+impl RemoteRef {
+    /// Keys of the objects stored for this head: its bundle, then its tag peel
+    /// and basis markers, shallow snapshot and packs for partial clones, if any.
+    pub fn object_keys(&self, prefix: &str) -> Vec<String> {
+        let mut keys = vec![self.reference.bundle_path(prefix)];
+        if let Some(peeled) = &self.peeled {
+            keys.push(self.reference.peeled_path(prefix, peeled));
+        }
+        for head in &self.basis {
+            keys.push(self.reference.basis_path(prefix, head));
+        }
+        if let Some(depth) = self.shallow_depth {
+            keys.push(self.reference.shallow_path(prefix, depth));
+        }
+        if self.blob_packs {
+            keys.push(self.reference.trees_path(prefix));
+            keys.push(self.reference.blobs_path(prefix));
+        }
+        keys
+    }
+}
+
+/// Sort key of a head: generation, then timestamp, then sha, so that heads pushed
+/// with the same generation in the same second are all kept, in a stable order.
+type HeadOrder = (u64, i128, String);
+
+#[derive(Debug, Default)]
 pub struct RemoteRefs {
-    // BTreeMap with Reverse ordering to sort timestamps in descending order (newest first)
-    by_update_time: BTreeMap<Reverse<i128>, RemoteRef>,
+    // BTreeMap with Reverse ordering to sort heads in descending order (newest first)
+    by_generation: BTreeMap<Reverse<HeadOrder>, RemoteRef>,
 }
 
 impl RemoteRefs {
     pub fn new() -> Self {
         RemoteRefs {
-            by_update_time: BTreeMap::new(),
+            by_generation: BTreeMap::new(),
         }
     }
 
     pub fn latest_ref(&self) -> &RemoteRef {
         // Get the first entry since we're using Reverse ordering
-        self.by_update_time.values().next().unwrap()
+        self.by_generation.values().next().unwrap()
     }
 
     pub fn add_ref(&mut self, remote_ref: RemoteRef) {
-        let timestamp = Reverse(remote_ref.updated);
-        self.by_update_time.insert(timestamp, remote_ref);
+        let order = (
+            remote_ref.generation,
+            remote_ref.updated,
+            remote_ref.reference.sha.clone(),
+        );
+        self.by_generation.insert(Reverse(order), remote_ref);
+    }
+
+    /// Record a head we just uploaded as the newest head of the ref, even if the
+    /// local clock is behind the S3 timestamps of the existing heads.
+    pub fn add_latest_ref(&mut self, mut remote_ref: RemoteRef) {
+        if let Some(latest) = self.by_generation.values().next() {
+            remote_ref.generation = remote_ref.generation.max(latest.generation);
+            remote_ref.updated = remote_ref.updated.max(latest.updated + 1);
+        }
+        self.add_ref(remote_ref);
+    }
+
+    /// Generation of the next head pushed to the ref
+    pub fn next_generation(&self) -> u64 {
+        self.latest_ref().generation + 1
     }
 
     pub fn stale_refs(&self) -> impl Iterator<Item = &RemoteRef> {
         // Skip the first entry (most recent) and return the rest
-        self.by_update_time.values().skip(1)
+        self.by_generation.values().skip(1)
+    }
+
+    pub fn all_refs(&self) -> impl Iterator<Item = &RemoteRef> {
+        self.by_generation.values()
     }
 }
 
@@ -129,70 +686,711 @@ impl RemoteRefs {
 /// retrieves all objects from the S3 bucket under the specified prefix and
 /// organizes them into a map of Git references. Each entry in the map
 /// represents a reference (e.g., "main", "feature/xyz") and contains all
-/// versions of that reference sorted by their last modified timestamp.
-
+/// versions of that reference sorted by their generation, then their last
+/// modified timestamp. Generations are only read for refs with several heads.
 pub async fn list_refs(
     s3: &Client,
     settings: &GitS3Settings,
 ) -> Result<HashMap<String, RemoteRefs>> {
-    let result = s3
-        .list_objects_v2()
-        .bucket(settings.bucket())
-        .prefix(settings.key())
-        .send()
-        .await?;
+    match layout(s3, settings).await? {
+        Layout::Bundles => {}
+        Layout::Packs => return packs::list_refs(s3, settings).await,
+        Layout::FastExport => return fastexport::list_refs(s3, settings).await,
+    }
 
-    let objects = result.contents().unwrap_or_default();
+    let objects = s3::list(s3, settings.bucket(), settings.key()).await?;
 
-    // Parse S3 keys into RemoteRefs
-    let refs_with_names = objects.iter().filter_map(|obj| {
-        // key = project1.git/refs/heads/features/fXXX/99d98906d65894a9eac5fda27b0c41d2cf372dd6.bundle
-        let key = obj.key()?;
-        let mut parts = key.trim_end_matches(".bundle").rsplit('/');
-        let sha = parts.next()?; // sha = 99d98906d65894a9eac5fda27b0c41d2cf372dd6
+    // Peel markers of annotated tags, keyed by (name, sha)
+    let peeled: HashMap<(String, String), String> = objects
+        .iter()
+        .filter_map(|obj| GitRef::from_peeled_path(settings.key(), &obj.key))
+        .map(|(r, peeled)| ((r.name, r.sha), peeled))
+        .collect();
 
-        // name = refs/heads/features/fXXX
-        let name = key
-            .strip_prefix(settings.key())? // Remove prefix (e.g. "project1.git")
-            .trim_start_matches('/')
-            .strip_suffix(&format!("/{}.bundle", sha))? // Remove suffix (e.g. "/[sha].bundle")
-            .to_string();
+    // Basis heads of thin tag bundles, keyed by (name, sha)
+    let mut basis: HashMap<(String, String), Vec<String>> = HashMap::new();
+    for (r, head) in objects
+        .iter()
+        .filter_map(|obj| GitRef::from_basis_path(settings.key(), &obj.key))
+    {
+        basis.entry((r.name, r.sha)).or_default().push(head);
+    }
+
+    // Shallow snapshots of branch heads, keyed by (name, sha)
+    let mut shallow: HashMap<(String, String), u32> = HashMap::new();
+    for (r, depth) in objects
+        .iter()
+        .filter_map(|obj| GitRef::from_shallow_path(settings.key(), &obj.key))
+    {
+        let deepest = shallow.entry((r.name, r.sha)).or_default();
+        *deepest = depth.max(*deepest);
+    }
+
+    // Heads with tree and blob packs, keyed by (name, sha)
+    let blob_packs: HashSet<(String, String)> = objects
+        .iter()
+        .filter_map(|obj| GitRef::from_trees_path(settings.key(), &obj.key))
+        .map(|r| (r.name, r.sha))
+        .collect();
 
+    // Parse S3 keys into RemoteRefs
+    let refs_with_names = objects.iter().filter_map(|obj| {
+        let reference = GitRef::from_bundle_path(settings.key(), &obj.key)?;
         Some((
-            name.clone(),
+            reference.name.clone(),
             RemoteRef {
-                updated: obj
-                    .last_modified()
-                    .map(|dt| dt.as_nanos())
+                updated: obj.last_modified,
+                generation: 0,
+                peeled: peeled
+                    .get(&(reference.name.clone(), reference.sha.clone()))
+                    .cloned(),
+                basis: basis
+                    .get(&(reference.name.clone(), reference.sha.clone()))
+                    .cloned()
                     .unwrap_or_default(),
-                reference: GitRef {
-                    name,
-                    sha: sha.to_string(),
-                },
+                shallow_depth: shallow
+                    .get(&(reference.name.clone(), reference.sha.clone()))
+                    .copied(),
+                blob_packs: blob_packs.contains(&(reference.name.clone(), reference.sha.clone())),
+                reference,
+                storage_class: obj.storage_class.clone(),
             },
         ))
     });
 
-    // Group refs by name into a HashMap
-    let refs_map = refs_with_names.fold(HashMap::new(), |mut acc, (name, remote_ref)| {
-        acc.entry(name)
-            .or_insert_with(RemoteRefs::new)
-            .add_ref(remote_ref);
-        acc
-    });
+    // Group heads by name, the order of several heads needs their generations
+    let mut heads_by_name: HashMap<String, Vec<RemoteRef>> = HashMap::new();
+    for (name, remote_ref) in refs_with_names {
+        heads_by_name.entry(name).or_default().push(remote_ref);
+    }
+
+    // Generations are read concurrently, like bundles during a fetch
+    let jobs = settings.fetch_jobs()?;
+    stream::iter(
+        heads_by_name
+            .values_mut()
+            .filter(|heads| heads.len() > 1)
+            .flatten(),
+    )
+    .map(|head| async move {
+        head.generation = read_generation(s3, settings, &head.reference).await?;
+        Ok::<_, anyhow::Error>(())
+    })
+    .buffer_unordered(jobs)
+    .try_collect::<Vec<_>>()
+    .await?;
+
+    let mut refs_map = HashMap::new();
+    for (name, heads) in heads_by_name {
+        let refs = refs_map.entry(name).or_insert_with(RemoteRefs::new);
+        for head in heads {
+            refs.add_ref(head);
+        }
+    }
 
     Ok(refs_map)
 }
 
+/// Whether the heads of a ref on S3 differ from `listed`, the heads `list_refs`
+/// found, listing the ref's own prefix only. Layouts with a manifest check it
+/// when they write it instead.
+pub async fn heads_changed(
+    s3: &Client,
+    settings: &GitS3Settings,
+    name: &str,
+    listed: Option<&RemoteRefs>,
+) -> Result<bool> {
+    if layout(s3, settings).await? != Layout::Bundles {
+        return Ok(false);
+    }
+
+    let prefix = format!("{}/{}/", settings.key(), name);
+    let current: HashSet<String> = s3::list(s3, settings.bucket(), &prefix)
+        .await?
+        .iter()
+        .filter_map(|obj| GitRef::from_bundle_path(settings.key(), &obj.key))
+        .filter(|r| r.name == name)
+        .map(|r| r.sha)
+        .collect();
+    let listed: HashSet<String> = listed
+        .into_iter()
+        .flat_map(|refs| refs.all_refs())
+        .map(|r| r.reference.sha.clone())
+        .collect();
+    Ok(current != listed)
+}
+
+const GENERATION_METADATA: &str = "generation";
+
+/// The generation recorded in the metadata of a head's bundle, 0 if there is none
+async fn read_generation(s3: &Client, settings: &GitS3Settings, r: &GitRef) -> Result<u64> {
+    let o = s3::Key {
+        bucket: settings.bucket().to_owned(),
+        key: r.bundle_path(settings.key()),
+    };
+    let metadata = s3::metadata(s3, &o, None, settings.sse()?).await?;
+    Ok(metadata
+        .get(GENERATION_METADATA)
+        .and_then(|generation| generation.parse().ok())
+        .unwrap_or_default())
+}
+
+/// Generation of the next head pushed to a ref. A single head's generation isn't
+/// read by `list_refs`, it is read here.
+pub async fn next_generation(
+    s3: &Client,
+    settings: &GitS3Settings,
+    remote_refs: Option<&RemoteRefs>,
+) -> Result<u64> {
+    match remote_refs {
+        None => Ok(1),
+        Some(refs) if refs.stale_refs().next().is_none() => {
+            let latest = refs.latest_ref();
+            if latest.generation > 0 || layout(s3, settings).await? != Layout::Bundles {
+                return Ok(refs.next_generation());
+            }
+            Ok(read_generation(s3, settings, &latest.reference).await? + 1)
+        }
+        Some(refs) => Ok(refs.next_generation()),
+    }
+}
+
+/// A version of a ref's bundle in a versioned bucket
+#[derive(Debug)]
+pub struct RefVersion {
+    pub reference: GitRef,
+    pub version_id: String,
+    /// Last modified timestamp of the version, in nanoseconds since epoch.
+    pub updated: i128,
+    pub size: i64,
+    /// Whether this is the current version of the object
+    pub is_latest: bool,
+    /// Whether this version records the deletion of the object
+    pub is_deleted: bool,
+}
+
+/// Lists every version of every ref's bundles, newest first, organized by
+/// reference name. This acts as a server-side reflog on versioned buckets: force
+/// pushes and prunes leave the older versions behind.
+pub async fn list_ref_history(
+    s3: &Client,
+    settings: &GitS3Settings,
+) -> Result<BTreeMap<String, Vec<RefVersion>>> {
+    let versions = s3::list_versions(s3, settings.bucket(), settings.key()).await?;
+
+    let mut history = versions
+        .into_iter()
+        .filter_map(|v| {
+            let reference = GitRef::from_bundle_path(settings.key(), &v.key)?;
+            Some(RefVersion {
+                reference,
+                version_id: v.version_id,
+                updated: v.last_modified,
+                size: v.size,
+                is_latest: v.is_latest,
+                is_deleted: v.is_delete_marker,
+            })
+        })
+        .fold(BTreeMap::new(), |mut acc: BTreeMap<_, Vec<_>>, v| {
+            acc.entry(v.reference.name.clone()).or_default().push(v);
+            acc
+        });
+
+    for versions in history.values_mut() {
+        versions.sort_by_key(|v| Reverse(v.updated));
+    }
+
+    Ok(history)
+}
+
+/// Restores a previous version of a ref's bundle as the current head of the ref.
+/// Without a version id, the most recent version that isn't a deletion is used.
+pub async fn restore_ref(
+    s3: &Client,
+    settings: &GitS3Settings,
+    r: &GitRef,
+    version_id: Option<&str>,
+) -> Result<String> {
+    let history = list_ref_history(s3, settings).await?;
+    let version = history
+        .get(&r.name)
+        .into_iter()
+        .flatten()
+        .filter(|v| v.reference.sha == r.sha && !v.is_deleted)
+        .find(|v| version_id.is_none_or(|id| id == v.version_id))
+        .ok_or_else(|| anyhow!("no version of {} at {} found", r.name, r.sha))?;
+
+    info!(?r, ?version.version_id, "Restoring ref");
+    let o = s3::Key {
+        bucket: settings.bucket().to_owned(),
+        key: r.bundle_path(settings.key()),
+    };
+    // The restored head becomes the newest one, whatever its generation was
+    let refs = list_refs(s3, settings).await?;
+    let generation = next_generation(s3, settings, refs.get(&r.name)).await?;
+    let mut metadata = s3::metadata(s3, &o, Some(&version.version_id), settings.sse()?).await?;
+    metadata.insert(GENERATION_METADATA.to_string(), generation.to_string());
+    let storage_class = settings.storage_class(RefClass::of(&r.name));
+    s3::restore_version(
+        s3,
+        &o,
+        &version.version_id,
+        settings.sse()?,
+        Some(metadata),
+        storage_class.as_deref(),
+    )
+    .await?;
+
+    Ok(version.version_id.clone())
+}
+
+/// Makes `keep` the only head of a ref. It is pushed first if it isn't one of the
+/// heads already, as for a merge of the heads, then every other head is deleted
+/// and the change of the ref, if any, is recorded in its reflog.
+pub async fn resolve_heads(
+    s3: &Client,
+    settings: &GitS3Settings,
+    remote_refs: &RemoteRefs,
+    keep: &GitRef,
+) -> Result<()> {
+    let current_dir = current_dir()?;
+    if !remote_refs.all_refs().any(|r| r.reference.sha == keep.sha) {
+        let generation = remote_refs.next_generation();
+        push_to_s3(s3, settings, keep, &[], generation).await?;
+    }
+
+    info!(?keep, "Resolving heads");
+    let others = remote_refs
+        .all_refs()
+        .filter(|r| r.reference.sha != keep.sha);
+    delete_from_s3(s3, settings, others).await?;
+
+    let latest = &remote_refs.latest_ref().reference.sha;
+    if *latest != keep.sha {
+        let entry = ReflogEntry {
+            old_sha: latest.clone(),
+            new_sha: keep.sha.clone(),
+            pusher: pusher(&current_dir),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or_default(),
+            forced: !git::is_ancestor(latest, &keep.sha, &current_dir)?,
+        };
+        append_reflog(s3, settings, &keep.name, &entry).await?;
+    }
+    Ok(())
+}
+
+/// Which of the objects stored for a ref a fetch downloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchKind {
+    /// The full bundle
+    Full,
+    /// The shallow snapshot of the given depth
+    Shallow(u32),
+    /// The pack of commits and trees, for partial clones
+    Trees,
+    /// The pack of the blobs in the tree of the ref's commit
+    Blobs,
+}
+
+/// A ref to fetch, and which of its objects is enough
+#[derive(Debug)]
+pub struct FetchRef {
+    pub reference: GitRef,
+    pub kind: FetchKind,
+}
+
+impl FetchRef {
+    fn path(&self, prefix: &str) -> String {
+        match self.kind {
+            FetchKind::Full => self.reference.bundle_path(prefix),
+            FetchKind::Shallow(depth) => self.reference.shallow_path(prefix, depth),
+            FetchKind::Trees => self.reference.trees_path(prefix),
+            FetchKind::Blobs => self.reference.blobs_path(prefix),
+        }
+    }
+}
+
 // Git bundle operations
 pub async fn fetch_from_s3(s3: &Client, settings: &GitS3Settings, r: &GitRef) -> Result<()> {
-    info!(?r, "Fetching from S3");
+    let r = FetchRef {
+        reference: r.clone(),
+        kind: FetchKind::Full,
+    };
+    fetch_all_from_s3(s3, settings, std::slice::from_ref(&r)).await
+}
 
-    let tmp_dir = temp_dir();
+/// Fetch a batch of refs. Bundles are downloaded and decrypted concurrently, up to
+/// `fetch_jobs` at a time, and unbundled in batch order so that the prerequisites
+/// of thin tag bundles are in place before the tags themselves. Packs for partial
+/// clones are added as promisor packs instead.
+pub async fn fetch_all_from_s3(
+    s3: &Client,
+    settings: &GitS3Settings,
+    refs: &[FetchRef],
+) -> Result<()> {
+    if refs.is_empty() {
+        return Ok(());
+    }
+    match layout(s3, settings).await? {
+        Layout::Bundles => {}
+        Layout::Packs => return packs::fetch(s3, settings, refs).await,
+        Layout::FastExport => return Err(anyhow!("fast-export remotes are fetched with import")),
+    }
+    let jobs = settings.fetch_jobs()?;
+    info!(count = refs.len(), jobs, "Fetching from S3");
+
+    let tmp_dir = tempfile::tempdir()?;
     debug!(?tmp_dir, "Created temporary directory");
 
-    let bundle_file = tmp_dir.join("bundle");
-    let enc_file = tmp_dir.join("bundle_enc");
+    let current_dir = current_dir()?;
+    let cache = BundleCache::open_or_skip(&current_dir);
+    let cache = cache.as_ref();
+    let mut downloads = stream::iter(refs.iter().enumerate())
+        .map(|(i, r)| {
+            let bundle_file = tmp_dir.path().join(format!("bundle_{}", i));
+            let enc_file = tmp_dir.path().join(format!("bundle_enc_{}", i));
+            async move {
+                download_bundle(s3, settings, cache, r, &enc_file, &bundle_file).await?;
+                Ok::<_, anyhow::Error>((r, bundle_file))
+            }
+        })
+        .buffered(jobs);
+
+    while let Some(download) = downloads.next().await {
+        let (r, bundle_file) = download?;
+        match r.kind {
+            FetchKind::Full | FetchKind::Shallow(_) => {
+                verify_bundle(&bundle_file, r, settings.key(), &current_dir)?;
+                info!(?r.reference.name, ?r.kind, "Unbundling Git bundle");
+                git::bundle_unbundle(&bundle_file, &r.reference.name, &current_dir)?;
+            }
+            FetchKind::Trees | FetchKind::Blobs => {
+                verify_pack(&bundle_file, r, settings.key(), &current_dir)?;
+                info!(?r.reference.name, ?r.kind, "Indexing promisor pack");
+                git::index_pack(&bundle_file, true, &current_dir)?;
+            }
+        }
+    }
+
+    update_shallow(refs, &current_dir)
+}
+
+/// Check a downloaded bundle before unbundling it, so that nothing from a corrupt
+/// bundle, or one that doesn't hold the requested head, lands in the repository.
+fn verify_bundle(bundle: &Path, r: &FetchRef, prefix: &str, current_dir: &Path) -> Result<()> {
+    let key = r.path(prefix);
+    match git::bundle_verify(bundle, current_dir)? {
+        BundleCheck::Valid => {}
+        BundleCheck::MissingPrerequisites(shas) => {
+            return Err(anyhow!(
+                "bundle {} needs commits missing from the repository: {}",
+                key,
+                shas.join(" ")
+            ));
+        }
+        BundleCheck::Invalid(reason) => {
+            return Err(anyhow!("bundle {} is corrupt: {}", key, reason));
+        }
+    }
+
+    let heads = git::bundle_list_heads(bundle, current_dir)?;
+    let (name, sha) = (&r.reference.name, &r.reference.sha);
+    if !heads.iter().any(|head| head.0 == *sha && head.1 == *name) {
+        let found: Vec<String> = heads
+            .iter()
+            .map(|(sha, name)| format!("{} {}", name, sha))
+            .collect();
+        return Err(anyhow!(
+            "bundle {} holds {} instead of {} {}",
+            key,
+            found.join(", "),
+            name,
+            sha
+        ));
+    }
+    Ok(())
+}
+
+/// Check a downloaded pack of a partial clone before adding it to the repository:
+/// it must be a valid pack, and the trees pack must hold the head it was stored for.
+fn verify_pack(pack: &Path, r: &FetchRef, prefix: &str, current_dir: &Path) -> Result<()> {
+    let key = r.path(prefix);
+    let contents = git::pack_file_contents(pack, current_dir)
+        .map_err(|e| anyhow!("pack {} is corrupt: {}", key, e))?;
+    if r.kind == FetchKind::Trees && !contents.contains(&r.reference.sha) {
+        return Err(anyhow!(
+            "pack {} doesn't hold {} {}",
+            key,
+            r.reference.name,
+            r.reference.sha
+        ));
+    }
+    Ok(())
+}
+
+/// Fetch objects missing from a partial clone, as requested by git when it needs
+/// a blob it was promised. The blob pack of a head is enough for blobs in the
+/// head's tree, anything else comes from the full bundle of a head that reaches it.
+pub async fn fetch_objects_from_s3(
+    s3: &Client,
+    settings: &GitS3Settings,
+    refs: &HashMap<String, RemoteRefs>,
+    oids: &[String],
+) -> Result<()> {
+    if oids.is_empty() {
+        return Ok(());
+    }
+    info!(count = oids.len(), "Fetching missing objects from S3");
+
+    let current_dir = current_dir()?;
+    // Branches before tags, whose bundles are thin
+    let mut heads: Vec<&RemoteRef> = refs.values().map(|refs| refs.latest_ref()).collect();
+    heads.sort_by_key(|head| RefClass::of(&head.reference.name) != RefClass::Heads);
+
+    let mut missing = oids.to_vec();
+    let mut batch = Vec::new();
+    for head in heads.iter().filter(|head| head.blob_packs) {
+        if missing.is_empty() {
+            break;
+        }
+        let blobs: HashSet<String> = git::tree_blobs(&head.reference.sha, &current_dir)?
+            .into_iter()
+            .collect();
+        let found = take_matching(&mut missing, &blobs);
+        if found > 0 {
+            debug!(?head.reference, found, "Blobs found in blob pack");
+            batch.push(FetchRef {
+                reference: head.reference.clone(),
+                kind: FetchKind::Blobs,
+            });
+        }
+    }
+    for head in heads {
+        if missing.is_empty() {
+            break;
+        }
+        let reachable: HashSet<String> = git::missing_objects(&head.reference.sha, &current_dir)?
+            .into_iter()
+            .collect();
+        let found = take_matching(&mut missing, &reachable);
+        if found > 0 {
+            debug!(?head.reference, found, "Objects found in full bundle");
+            batch.push(FetchRef {
+                reference: head.reference.clone(),
+                kind: FetchKind::Full,
+            });
+        }
+    }
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "objects not found on remote: {}",
+            missing.join(" ")
+        ));
+    }
+
+    fetch_all_from_s3(s3, settings, &batch).await
+}
+
+/// Remove the oids found in `found` from `oids`, returning how many there were.
+fn take_matching(oids: &mut Vec<String>, found: &HashSet<String>) -> usize {
+    let before = oids.len();
+    oids.retain(|oid| !found.contains(oid));
+    before - oids.len()
+}
+
+/// Record the boundary of the shallow snapshots just unbundled in the shallow
+/// file, and drop the boundary commits whose history has since been filled in by
+/// full bundles, so that deepening a shallow clone works.
+fn update_shallow(refs: &[FetchRef], current_dir: &Path) -> Result<()> {
+    let current = git::read_shallow(current_dir)?;
+    let mut candidates = current.clone();
+    for r in refs {
+        if let FetchKind::Shallow(depth) = r.kind {
+            candidates.extend(shallow_boundary(&r.reference.sha, depth, current_dir)?);
+        }
+    }
+
+    let mut boundary: Vec<String> = Vec::new();
+    for sha in candidates {
+        if !boundary.contains(&sha) && has_missing_parent(&sha, current_dir)? {
+            boundary.push(sha);
+        }
+    }
+
+    if boundary != current {
+        info!(?boundary, "Updating shallow boundary");
+        git::write_shallow(&boundary, current_dir)?;
+    }
+    Ok(())
+}
+
+/// Commits within `depth` of `tip` that have missing parents.
+fn shallow_boundary(tip: &str, depth: u32, current_dir: &Path) -> Result<Vec<String>> {
+    let mut boundary = Vec::new();
+    let mut seen = HashSet::new();
+    let mut level = vec![tip.to_string()];
+    for _ in 0..depth {
+        let mut next = Vec::new();
+        for sha in level {
+            let parents = git::commit_parents(&sha, current_dir)?;
+            if any_missing(&parents, current_dir)? {
+                boundary.push(sha);
+            } else {
+                next.extend(parents.into_iter().filter(|p| seen.insert(p.clone())));
+            }
+        }
+        level = next;
+    }
+    Ok(boundary)
+}
+
+fn has_missing_parent(sha: &str, current_dir: &Path) -> Result<bool> {
+    any_missing(&git::commit_parents(sha, current_dir)?, current_dir)
+}
+
+fn any_missing(shas: &[String], current_dir: &Path) -> Result<bool> {
+    for sha in shas {
+        if !git::object_exists(sha, current_dir)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+async fn download_bundle(
+    s3: &Client,
+    settings: &GitS3Settings,
+    cache: Option<&BundleCache>,
+    r: &FetchRef,
+    enc_file: &Path,
+    bundle_file: &Path,
+) -> Result<()> {
+    let o = s3::Key {
+        bucket: settings.bucket().to_owned(),
+        key: r.path(settings.key()),
+    };
+    download(s3, settings, cache, &o, enc_file, bundle_file).await
+}
+
+/// Download an object through the cache, then remove its padding and decrypt it.
+pub(crate) async fn download(
+    s3: &Client,
+    settings: &GitS3Settings,
+    cache: Option<&BundleCache>,
+    o: &s3::Key,
+    enc_file: &Path,
+    out_file: &Path,
+) -> Result<()> {
+    match cache {
+        Some(cache) => get_cached(s3, settings, cache, o, enc_file).await?,
+        None => {
+            debug!(?o, "Fetching bundle from S3");
+            s3::get(s3, enc_file, o, settings.sse()?).await?;
+        }
+    }
+
+    // gpg runs as a blocking subprocess, keep it off the async workers so that
+    // other downloads make progress meanwhile
+    debug!(?o.key, "Decrypting bundle");
+    let (enc_file, out_file) = (enc_file.to_owned(), out_file.to_owned());
+    tokio::task::spawn_blocking(move || {
+        gpg::decrypt(&enc_file, &out_file)?;
+        pad::unpad(&out_file)
+    })
+    .await??;
+
+    Ok(())
+}
+
+/// Encrypt a file for upload, padding it first when the remote pads its objects.
+/// The padding is encrypted along with the content, so only the padded size is
+/// visible on S3.
+pub(crate) fn encrypt_for_upload(
+    settings: &GitS3Settings,
+    input: &Path,
+    output: &Path,
+) -> Result<()> {
+    let recipients = settings.gpg_recipients()?;
+    let Some(padding) = settings.padding()? else {
+        return gpg::encrypt(&recipients, input, output);
+    };
+
+    // The input may still be needed, e.g. a pack that is indexed after upload
+    let tmp_dir = tempfile::tempdir()?;
+    let padded_file = tmp_dir.path().join("padded");
+    std::fs::copy(input, &padded_file).map_err(|e| anyhow!("failed to copy file: {}", e))?;
+    pad::pad(&padded_file, &padding)?;
+    gpg::encrypt_uncompressed(&recipients, &padded_file, output)
+}
+
+/// Get an object through the bundle cache. Cache failures only cost a download.
+async fn get_cached(
+    s3: &Client,
+    settings: &GitS3Settings,
+    cache: &BundleCache,
+    o: &s3::Key,
+    f: &Path,
+) -> Result<()> {
+    let etag = s3::etag(s3, o, settings.sse()?).await?;
+    if let Some(etag) = &etag {
+        match cache.get(o, etag, f) {
+            Ok(true) => return Ok(()),
+            Ok(false) => {}
+            Err(e) => warn!(?e, ?o, "Failed to read bundle cache"),
+        }
+    }
+
+    debug!(?o, "Fetching bundle from S3");
+    s3::get(s3, f, o, settings.sse()?).await?;
+
+    if let Some(etag) = &etag {
+        if let Err(e) = cache.put(o, etag, f) {
+            warn!(?e, ?o, "Failed to update bundle cache");
+        }
+    }
+    Ok(())
+}
+
+/// Upload a head of a ref, recording `generation` in its metadata to order it among
+/// the other heads of the ref. A tag bundle leaves out the commits of the `basis`
+/// branch heads, which are recorded next to it.
+pub async fn push_to_s3(
+    s3: &Client,
+    settings: &GitS3Settings,
+    r: &GitRef,
+    basis: &[String],
+    generation: u64,
+) -> Result<RemoteRef> {
+    match layout(s3, settings).await? {
+        Layout::Bundles => {}
+        Layout::Packs => return packs::push(s3, settings, r).await,
+        Layout::FastExport => return Err(anyhow!("fast-export remotes are pushed with export")),
+    }
+
+    let tmp_dir = tempfile::tempdir()?;
+    let bundle_file = tmp_dir.path().join("bundle");
+    let enc_file = tmp_dir.path().join("bundle_enc");
+
+    let current_dir = current_dir()?;
+    let peeled = git::rev_parse(&format!("{}^{{}}", r.sha), &current_dir)?;
+    let prerequisites: Vec<String> = basis
+        .iter()
+        .map(|head| {
+            if *head == peeled {
+                // A tag on a branch head: only exclude the parents, as git leaves
+                // out the commit without listing it as a prerequisite otherwise
+                format!("{}^@", head)
+            } else {
+                head.clone()
+            }
+        })
+        .collect();
+    git::bundle_create(&bundle_file, &r.name, &prerequisites, &current_dir)?;
+
+    encrypt_for_upload(settings, &bundle_file, &enc_file)?;
 
     let path = r.bundle_path(settings.key());
     let o = s3::Key {
@@ -200,48 +1398,421 @@ pub async fn fetch_from_s3(s3: &Client, settings: &GitS3Settings, r: &GitRef) ->
         key: path,
     };
 
-    debug!(?o, "Fetching bundle from S3");
-    s3::get(s3, &enc_file, &o).await?;
+    let mut metadata = bundle_metadata(r, &current_dir);
+    metadata.insert(GENERATION_METADATA.to_string(), generation.to_string());
+    let attrs = settings.attributes(RefClass::of(&r.name), metadata)?;
+    s3::put(s3, &enc_file, &o, settings.sse()?, &attrs).await?;
 
-    debug!("Decrypting bundle");
-    gpg::decrypt(&enc_file, &bundle_file)?;
+    for head in basis {
+        let o = s3::Key {
+            bucket: settings.bucket().to_owned(),
+            key: r.basis_path(settings.key(), head),
+        };
+        s3::touch(s3, &o, settings.sse()?).await?;
+    }
+
+    let peeled = if peeled != r.sha {
+        let o = s3::Key {
+            bucket: settings.bucket().to_owned(),
+            key: r.peeled_path(settings.key(), &peeled),
+        };
+        s3::touch(s3, &o, settings.sse()?).await?;
+        Some(peeled)
+    } else {
+        None
+    };
+
+    let shallow_depth = match settings.shallow_depth()? {
+        Some(depth) if RefClass::of(&r.name) == RefClass::Heads => {
+            push_shallow_snapshot(s3, settings, r, depth, &attrs).await?;
+            Some(depth)
+        }
+        _ => None,
+    };
+
+    let blob_packs = settings.blob_packs();
+    if blob_packs {
+        push_blob_packs(s3, settings, r, &attrs).await?;
+    }
 
-    info!(?r.name, "Unbundling Git bundle");
+    Ok(RemoteRef {
+        updated: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as i128)
+            .unwrap_or_default(),
+        generation,
+        reference: GitRef {
+            name: r.name.clone(),
+            sha: r.sha.clone(),
+        },
+        storage_class: attrs.storage_class,
+        peeled,
+        basis: basis.to_vec(),
+        shallow_depth,
+        blob_packs,
+    })
+}
+
+/// Upload a bundle of the last `depth` commits of a branch head next to its full
+/// bundle, so shallow clones don't download the full history.
+async fn push_shallow_snapshot(
+    s3: &Client,
+    settings: &GitS3Settings,
+    r: &GitRef,
+    depth: u32,
+    attrs: &s3::Attributes,
+) -> Result<()> {
+    let tmp_dir = tempfile::tempdir()?;
+    let bundle_file = tmp_dir.path().join("shallow_bundle");
+    let enc_file = tmp_dir.path().join("shallow_bundle_enc");
+
+    info!(?r, depth, "Creating shallow snapshot");
     let current_dir = current_dir()?;
-    git::bundle_unbundle(&bundle_file, &r.name, &current_dir)?;
+    git::shallow_bundle_create(&bundle_file, &r.name, &r.sha, depth, &current_dir)?;
 
-    Ok(())
+    encrypt_for_upload(settings, &bundle_file, &enc_file)?;
+
+    let o = s3::Key {
+        bucket: settings.bucket().to_owned(),
+        key: r.shallow_path(settings.key(), depth),
+    };
+    s3::put(s3, &enc_file, &o, settings.sse()?, attrs).await
 }
 
-pub async fn push_to_s3(s3: &Client, settings: &GitS3Settings, r: &GitRef) -> Result<()> {
-    let tmp_dir = std::env::temp_dir();
-    let bundle_file = tmp_dir.join("bundle");
-    let enc_file = tmp_dir.join("bundle_enc");
+/// Upload a pack of the commits and trees of a ref and a pack of the blobs in its
+/// tree next to its full bundle. Partial clones fetch the former and then
+/// the latter on checkout.
+async fn push_blob_packs(
+    s3: &Client,
+    settings: &GitS3Settings,
+    r: &GitRef,
+    attrs: &s3::Attributes,
+) -> Result<()> {
+    let tmp_dir = tempfile::tempdir()?;
+    let pack_file = tmp_dir.path().join("pack");
+    let enc_file = tmp_dir.path().join("pack_enc");
 
+    info!(?r, "Creating tree and blob packs");
     let current_dir = current_dir()?;
-    git::bundle_create(&bundle_file, &r.name, &current_dir)?;
+    for (path, blobs) in [
+        (r.trees_path(settings.key()), None),
+        (
+            r.blobs_path(settings.key()),
+            Some(git::tree_blobs(&r.sha, &current_dir)?),
+        ),
+    ] {
+        match blobs {
+            None => git::pack_objects_filtered(&pack_file, &r.sha, "blob:none", &current_dir)?,
+            Some(blobs) => git::pack_objects(&pack_file, &blobs, &current_dir)?,
+        }
+
+        encrypt_for_upload(settings, &pack_file, &enc_file)?;
 
-    let recipients = git::config(
-        &format!("remote.{}.gpgRecipients", settings.remote_alias),
-        &current_dir,
+        let o = s3::Key {
+            bucket: settings.bucket().to_owned(),
+            key: path,
+        };
+        s3::put(s3, &enc_file, &o, settings.sse()?, attrs).await?;
+    }
+
+    Ok(())
+}
+
+/// Metadata recorded on each uploaded bundle: who pushed it, which ref and commit
+/// it holds, the commit's parent, and the version of this tool.
+pub(crate) fn bundle_metadata(r: &GitRef, current_dir: &Path) -> HashMap<String, String> {
+    let mut metadata = HashMap::from([
+        ("ref".to_string(), r.name.clone()),
+        ("sha".to_string(), r.sha.clone()),
+        (
+            "tool-version".to_string(),
+            format!("git-remote-s3/{}", env!("CARGO_PKG_VERSION")),
+        ),
+    ]);
+    metadata.insert("pusher".to_string(), pusher(current_dir));
+    if let Ok(parent) = git::rev_parse(&format!("{}^", r.sha), current_dir) {
+        metadata.insert("parent".to_string(), parent);
+    }
+    metadata
+}
+
+/// Identity of the person pushing, from `user.email`.
+pub fn pusher(current_dir: &Path) -> String {
+    git::config("user.email", current_dir).unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Deletes the given heads from S3, along with their tag peel markers, shallow
+/// snapshots and packs.
+pub async fn delete_from_s3<'a>(
+    s3: &Client,
+    settings: &GitS3Settings,
+    remote_refs: impl IntoIterator<Item = &'a RemoteRef>,
+) -> Result<()> {
+    match layout(s3, settings).await? {
+        Layout::Bundles => {}
+        Layout::Packs => return packs::delete(s3, settings, remote_refs).await,
+        Layout::FastExport => {
+            return Err(anyhow!("refs can't be deleted from fast-export remotes"))
+        }
+    }
+
+    for remote_ref in remote_refs {
+        info!(?remote_ref.reference, "Deleting from S3");
+        for key in remote_ref.object_keys(settings.key()) {
+            let o = s3::Key {
+                bucket: settings.bucket().to_owned(),
+                key,
+            };
+            s3::del(s3, &o).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Basis of a thin tag bundle: the remote branch heads the tag's commit already
+/// builds on. Fetching tags then only downloads the tag objects and any commits
+/// not on a branch, rather than the full history once per tag.
+pub fn thin_basis(
+    refs: &HashMap<String, RemoteRefs>,
+    r: &GitRef,
+    current_dir: &Path,
+) -> Result<Vec<String>> {
+    let peeled = git::rev_parse(&format!("{}^{{}}", r.sha), current_dir)?;
+
+    let mut basis = Vec::new();
+    for (name, remote_refs) in refs.iter() {
+        let head = &remote_refs.latest_ref().reference.sha;
+        if RefClass::of(name) != RefClass::Heads
+            || basis.contains(head)
+            || !git::is_ancestor(head, &peeled, current_dir)?
+        {
+            continue;
+        }
+        basis.push(head.clone());
+    }
+    debug!(?r, ?basis, "Thin bundle basis");
+
+    Ok(basis)
+}
+
+fn head_path(prefix: &str) -> String {
+    format!("{}/HEAD", prefix)
+}
+
+/// Reads the remote's default branch, stored as `ref: <target>` in the HEAD object.
+pub async fn read_head(s3: &Client, settings: &GitS3Settings) -> Result<Option<String>> {
+    let tmp_dir = tempfile::tempdir()?;
+    let head_file = tmp_dir.path().join("HEAD");
+    let o = s3::Key {
+        bucket: settings.bucket().to_owned(),
+        key: head_path(settings.key()),
+    };
+    if !s3::try_get(s3, &head_file, &o, settings.sse()?).await? {
+        return Ok(None);
+    }
+
+    let contents = std::fs::read_to_string(&head_file)?;
+    Ok(contents
+        .trim()
+        .strip_prefix("ref: ")
+        .map(|target| target.to_string()))
+}
+
+/// Sets the remote's default branch.
+pub async fn write_head(s3: &Client, settings: &GitS3Settings, target: &str) -> Result<()> {
+    info!(?target, "Setting remote HEAD");
+    let tmp_dir = tempfile::tempdir()?;
+    let head_file = tmp_dir.path().join("HEAD");
+    std::fs::write(&head_file, format!("ref: {}\n", target))?;
+
+    let o = s3::Key {
+        bucket: settings.bucket().to_owned(),
+        key: head_path(settings.key()),
+    };
+    s3::put(
+        s3,
+        &head_file,
+        &o,
+        settings.sse()?,
+        &s3::Attributes::default(),
     )
-    .map(|config| {
-        config
-            .split_ascii_whitespace()
-            .map(|s| s.to_string())
-            .collect()
-    })
-    .or_else(|_| git::config("user.email", &current_dir).map(|recip| vec![recip]))?;
+    .await
+}
 
-    gpg::encrypt(&recipients, &bundle_file, &enc_file)?;
+/// Sha used in the reflog for a ref that doesn't exist, as in git's own reflog.
+pub const ZERO_SHA: &str = "0000000000000000000000000000000000000000";
+
+/// An update of a remote ref, as recorded in the remote reflog
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReflogEntry {
+    /// Previous head, or `ZERO_SHA` when the ref was created
+    pub old_sha: String,
+    /// New head, or `ZERO_SHA` when the ref was deleted
+    pub new_sha: String,
+    pub pusher: String,
+    /// Unix timestamp in seconds
+    pub timestamp: i64,
+    /// Whether the update discarded commits, i.e. was not a fast-forward
+    pub forced: bool,
+}
+
+impl ReflogEntry {
+    /// One line per entry: `<old-sha> <new-sha> <timestamp> <forced|-> <pusher>`
+    pub fn to_line(&self) -> String {
+        format!(
+            "{} {} {} {} {}",
+            self.old_sha,
+            self.new_sha,
+            self.timestamp,
+            if self.forced { "forced" } else { "-" },
+            self.pusher
+        )
+    }
+
+    pub fn parse(line: &str) -> Option<ReflogEntry> {
+        let mut parts = line.splitn(5, ' ');
+        Some(ReflogEntry {
+            old_sha: parts.next()?.to_string(),
+            new_sha: parts.next()?.to_string(),
+            timestamp: parts.next()?.parse().ok()?,
+            forced: parts.next()? == "forced",
+            pusher: parts.next()?.to_string(),
+        })
+    }
+}
+
+fn reflog_path(prefix: &str, name: &str) -> String {
+    format!("{}/logs/{}", prefix, name)
+}
+
+/// Reads the remote reflog of a ref, oldest entry first.
+pub async fn read_reflog(
+    s3: &Client,
+    settings: &GitS3Settings,
+    name: &str,
+) -> Result<Vec<ReflogEntry>> {
+    let tmp_dir = tempfile::tempdir()?;
+    let log_file = tmp_dir.path().join("reflog");
+    let enc_file = tmp_dir.path().join("reflog_enc");
 
-    let path = r.bundle_path(settings.key());
     let o = s3::Key {
         bucket: settings.bucket().to_owned(),
-        key: path,
+        key: reflog_path(settings.key(), name),
     };
+    if !s3::try_get(s3, &enc_file, &o, settings.sse()?).await? {
+        return Ok(Vec::new());
+    }
+    gpg::decrypt(&enc_file, &log_file)?;
 
-    s3::put(s3, &enc_file, &o).await?;
+    let contents = std::fs::read_to_string(&log_file)?;
+    Ok(contents.lines().filter_map(ReflogEntry::parse).collect())
+}
+
+/// Attempts at appending to a reflog that other pushes keep appending to
+const REFLOG_ATTEMPTS: usize = 3;
+
+/// Appends an entry to the remote reflog of a ref. The reflog is read again when
+/// its ETag changed in the meantime, so that a concurrent push's entry isn't
+/// overwritten.
+pub async fn append_reflog(
+    s3: &Client,
+    settings: &GitS3Settings,
+    name: &str,
+    entry: &ReflogEntry,
+) -> Result<()> {
+    debug!(?name, ?entry, "Appending to remote reflog");
+    let o = s3::Key {
+        bucket: settings.bucket().to_owned(),
+        key: reflog_path(settings.key(), name),
+    };
+    let tmp_dir = tempfile::tempdir()?;
+    let log_file = tmp_dir.path().join("reflog");
+    let enc_file = tmp_dir.path().join("reflog_enc");
+
+    for _ in 0..REFLOG_ATTEMPTS {
+        let etag = s3::etag(s3, &o, settings.sse()?).await?;
+        let mut entries = read_reflog(s3, settings, name).await?;
+        entries.push(entry.clone());
+
+        let contents: String = entries.iter().map(|e| e.to_line() + "\n").collect();
+        std::fs::write(&log_file, contents)?;
+        gpg::encrypt(&settings.gpg_recipients()?, &log_file, &enc_file)?;
+
+        // Without conditional writes, this narrows the window in which a
+        // concurrent entry could be lost
+        if s3::etag(s3, &o, settings.sse()?).await? != etag {
+            info!(
+                ?name,
+                "Remote reflog changed while appending, reading it again"
+            );
+            continue;
+        }
+        return s3::put(
+            s3,
+            &enc_file,
+            &o,
+            settings.sse()?,
+            &s3::Attributes::default(),
+        )
+        .await;
+    }
+
+    Err(anyhow!(
+        "the reflog of {} was appended to concurrently, push again",
+        name
+    ))
+}
+
+/// Gives the heads of a ref that a newly pushed head superseded the storage
+/// class, tags and metadata of stale heads, by copying them in place. Heads are
+/// ordered by generation, which the copy keeps in the metadata, so the copies
+/// don't become newer than the new head.
+pub async fn demote_stale_refs(
+    s3: &Client,
+    settings: &GitS3Settings,
+    refs: &mut RemoteRefs,
+    new_ref: &GitRef,
+) -> Result<()> {
+    if !settings.demotes_stale_heads() {
+        return Ok(());
+    }
+    if layout(s3, settings).await? != Layout::Bundles {
+        // Refs have a single head, and packs and streams are shared by refs
+        return Ok(());
+    }
+
+    let storage_class = settings.storage_class(RefClass::Stale);
+    let tags = settings.object_tags(RefClass::Stale);
+    let stale_metadata = settings.object_metadata(RefClass::Stale)?;
+    let latest = refs.latest_ref().reference.sha.clone();
+    for remote_ref in refs.by_generation.values_mut() {
+        // Heads are demoted once superseded, so only the latest one is left, unless
+        // the storage class shows that others were pushed before demoting
+        let demoted = match &storage_class {
+            Some(class) => remote_ref.storage_class.as_ref() == Some(class),
+            None => remote_ref.reference.sha != latest,
+        };
+        if remote_ref.reference.sha == new_ref.sha || demoted {
+            continue;
+        }
+
+        info!(?remote_ref.reference, ?storage_class, ?tags, "Demoting stale head");
+        let o = s3::Key {
+            bucket: settings.bucket().to_owned(),
+            key: remote_ref.reference.bundle_path(settings.key()),
+        };
+        let mut metadata = s3::metadata(s3, &o, None, settings.sse()?).await?;
+        metadata.extend(stale_metadata.clone());
+        let attrs = s3::Attributes {
+            storage_class: storage_class
+                .clone()
+                .or_else(|| remote_ref.storage_class.clone()),
+            tags: tags.clone(),
+            metadata,
+        };
+        s3::copy_in_place(s3, &o, settings.sse()?, &attrs).await?;
+        remote_ref.storage_class = attrs.storage_class;
+    }
 
     Ok(())
 }
@@ -256,30 +1827,255 @@ mod tests {
 
         // Add refs with different timestamps (nanoseconds)
         refs.add_ref(RemoteRef {
-            updated: 1701925200_000_000_000, // Dec 7, 2023 00:00:00.000000000 UTC
+            updated: 1_701_925_200_000_000_000, // Dec 7, 2023 00:00:00.000000000 UTC
+            generation: 0,
             reference: GitRef {
                 name: "main".to_string(),
                 sha: "abc123".to_string(),
             },
+            storage_class: None,
+            peeled: None,
+            basis: Vec::new(),
+            shallow_depth: None,
+            blob_packs: false,
         });
 
         refs.add_ref(RemoteRef {
-            updated: 1701838800_000_000_000, // Dec 6, 2023 00:00:00.000000000 UTC
+            updated: 1_701_838_800_000_000_000, // Dec 6, 2023 00:00:00.000000000 UTC
+            generation: 0,
             reference: GitRef {
                 name: "main".to_string(),
                 sha: "def456".to_string(),
             },
+            storage_class: None,
+            peeled: None,
+            basis: Vec::new(),
+            shallow_depth: None,
+            blob_packs: false,
         });
 
         // Verify that latest_ref returns the most recent ref
         let latest = refs.latest_ref();
-        assert_eq!(latest.updated, 1701925200_000_000_000);
+        assert_eq!(latest.updated, 1_701_925_200_000_000_000);
         assert_eq!(latest.reference.sha, "abc123");
 
         // Verify that stale_refs returns older refs in order
         let stale: Vec<_> = refs.stale_refs().collect();
         assert_eq!(stale.len(), 1);
-        assert_eq!(stale[0].updated, 1701838800_000_000_000);
+        assert_eq!(stale[0].updated, 1_701_838_800_000_000_000);
         assert_eq!(stale[0].reference.sha, "def456");
     }
+
+    #[test]
+    fn test_remote_refs_generations() {
+        let head = |generation, updated, sha: &str| RemoteRef {
+            updated,
+            generation,
+            reference: GitRef {
+                name: "main".to_string(),
+                sha: sha.to_string(),
+            },
+            storage_class: None,
+            peeled: None,
+            basis: Vec::new(),
+            shallow_depth: None,
+            blob_packs: false,
+        };
+        let mut refs = RemoteRefs::new();
+
+        // The generation wins over an older timestamp, e.g. after a copy
+        refs.add_ref(head(1, 1_701_925_200_000_000_000, "abc123"));
+        refs.add_ref(head(2, 1_701_838_800_000_000_000, "def456"));
+        assert_eq!(refs.latest_ref().reference.sha, "def456");
+
+        // Heads with the same generation and timestamp are all kept
+        refs.add_ref(head(2, 1_701_838_800_000_000_000, "fed789"));
+        let shas: Vec<_> = refs.all_refs().map(|r| r.reference.sha.as_str()).collect();
+        assert_eq!(shas, ["fed789", "def456", "abc123"]);
+        assert_eq!(refs.next_generation(), 3);
+
+        // A head we just pushed is the newest one, whatever the clocks say
+        refs.add_latest_ref(head(2, 0, "012abc"));
+        assert_eq!(refs.latest_ref().reference.sha, "012abc");
+        assert_eq!(refs.all_refs().count(), 4);
+    }
+
+    #[test]
+    fn test_bundle_paths() {
+        let r = GitRef {
+            name: "refs/tags/v1.0".to_string(),
+            sha: "abc123".to_string(),
+        };
+
+        let key = r.bundle_path("project1.git");
+        assert_eq!(key, "project1.git/refs/tags/v1.0/abc123.bundle");
+        let parsed = GitRef::from_bundle_path("project1.git", &key).unwrap();
+        assert_eq!((parsed.name, parsed.sha), (r.name.clone(), r.sha.clone()));
+
+        let key = r.peeled_path("project1.git", "def456");
+        assert_eq!(key, "project1.git/refs/tags/v1.0/abc123.peeled.def456");
+        assert!(GitRef::from_bundle_path("project1.git", &key).is_none());
+        let (parsed, peeled) = GitRef::from_peeled_path("project1.git", &key).unwrap();
+        assert_eq!((parsed.name, parsed.sha), (r.name.clone(), r.sha.clone()));
+        assert_eq!(peeled, "def456");
+
+        let key = r.basis_path("project1.git", "789abc");
+        assert_eq!(key, "project1.git/refs/tags/v1.0/abc123.basis.789abc");
+        assert!(GitRef::from_bundle_path("project1.git", &key).is_none());
+        assert!(GitRef::from_peeled_path("project1.git", &key).is_none());
+        let (parsed, head) = GitRef::from_basis_path("project1.git", &key).unwrap();
+        assert_eq!((parsed.name, parsed.sha), (r.name.clone(), r.sha.clone()));
+        assert_eq!(head, "789abc");
+
+        let key = r.shallow_path("project1.git", 10);
+        assert_eq!(key, "project1.git/refs/tags/v1.0/abc123.shallow.10");
+        assert!(GitRef::from_bundle_path("project1.git", &key).is_none());
+        let (parsed, depth) = GitRef::from_shallow_path("project1.git", &key).unwrap();
+        assert_eq!((parsed.name, parsed.sha), (r.name.clone(), r.sha.clone()));
+        assert_eq!(depth, 10);
+
+        let key = r.trees_path("project1.git");
+        assert_eq!(key, "project1.git/refs/tags/v1.0/abc123.trees");
+        assert!(GitRef::from_bundle_path("project1.git", &key).is_none());
+        let parsed = GitRef::from_trees_path("project1.git", &key).unwrap();
+        assert_eq!((parsed.name, parsed.sha), (r.name.clone(), r.sha.clone()));
+        assert!(GitRef::from_trees_path("project1.git", &r.blobs_path("project1.git")).is_none());
+    }
+
+    #[test]
+    fn test_reflog_entry_roundtrip() {
+        let entry = ReflogEntry {
+            old_sha: ZERO_SHA.to_string(),
+            new_sha: "abc123".to_string(),
+            pusher: "Test User <test@example.com>".to_string(),
+            timestamp: 1701925200,
+            forced: true,
+        };
+
+        let line = entry.to_line();
+        assert_eq!(
+            line,
+            format!(
+                "{} abc123 1701925200 forced Test User <test@example.com>",
+                ZERO_SHA
+            )
+        );
+        assert_eq!(ReflogEntry::parse(&line), Some(entry));
+        assert_eq!(ReflogEntry::parse("garbage"), None);
+    }
+
+    /// A repository with two commits on main, returning their shas
+    fn test_repo(dir: &Path) -> (String, String) {
+        let git = |args: &[&str]| {
+            let output = std::process::Command::new("git")
+                .args(["-c", "user.email=test@example.com", "-c", "user.name=Test"])
+                .args(args)
+                .current_dir(dir)
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "git {:?} failed", args);
+            String::from_utf8(output.stdout).unwrap().trim().to_string()
+        };
+        git(&["init", "--quiet", "-b", "main"]);
+        std::fs::write(dir.join("a.txt"), "a").unwrap();
+        git(&["add", "a.txt"]);
+        git(&["commit", "--quiet", "-m", "c1"]);
+        let first = git(&["rev-parse", "HEAD"]);
+        std::fs::write(dir.join("b.txt"), "b").unwrap();
+        git(&["add", "b.txt"]);
+        git(&["commit", "--quiet", "-m", "c2"]);
+        (first, git(&["rev-parse", "HEAD"]))
+    }
+
+    fn fetch_ref(sha: &str, kind: FetchKind) -> FetchRef {
+        FetchRef {
+            reference: GitRef {
+                name: "refs/heads/main".to_string(),
+                sha: sha.to_string(),
+            },
+            kind,
+        }
+    }
+
+    #[test]
+    fn test_verify_bundle() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, head) = test_repo(dir.path());
+        let bundle = dir.path().join("bundle");
+        git::bundle_create(&bundle, "refs/heads/main", &[], dir.path()).unwrap();
+
+        verify_bundle(&bundle, &fetch_ref(&head, FetchKind::Full), "p", dir.path()).unwrap();
+
+        let err = verify_bundle(
+            &bundle,
+            &fetch_ref(&first, FetchKind::Full),
+            "p",
+            dir.path(),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.contains(&format!("holds refs/heads/main {}", head)),
+            "{}",
+            err
+        );
+
+        let tampered = dir.path().join("tampered");
+        let mut contents = std::fs::read(&bundle).unwrap();
+        contents[2] = b'x';
+        std::fs::write(&tampered, contents).unwrap();
+        let err = verify_bundle(
+            &tampered,
+            &fetch_ref(&head, FetchKind::Full),
+            "p",
+            dir.path(),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.starts_with("bundle p/refs/heads/main/"), "{}", err);
+        assert!(err.contains("is corrupt"), "{}", err);
+    }
+
+    #[test]
+    fn test_verify_pack() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, head) = test_repo(dir.path());
+        let trees = dir.path().join("trees");
+        git::pack_objects_filtered(&trees, &head, "blob:none", dir.path()).unwrap();
+
+        verify_pack(&trees, &fetch_ref(&head, FetchKind::Trees), "p", dir.path()).unwrap();
+
+        // The pack of an older head
+        let older = dir.path().join("older");
+        git::pack_objects_filtered(&older, &first, "blob:none", dir.path()).unwrap();
+        let err = verify_pack(&older, &fetch_ref(&head, FetchKind::Trees), "p", dir.path())
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            format!(
+                "pack p/refs/heads/main/{}.trees doesn't hold refs/heads/main {}",
+                head, head
+            )
+        );
+
+        let tampered = dir.path().join("tampered");
+        let mut contents = std::fs::read(&trees).unwrap();
+        let middle = contents.len() / 2;
+        contents[middle] ^= 0xff;
+        std::fs::write(&tampered, contents).unwrap();
+        let err = verify_pack(
+            &tampered,
+            &fetch_ref(&head, FetchKind::Blobs),
+            "p",
+            dir.path(),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.starts_with(&format!("pack p/refs/heads/main/{}.blobs is corrupt", head)),
+            "{}",
+            err
+        );
+    }
 }
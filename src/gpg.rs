@@ -7,6 +7,17 @@ use tracing::{debug, error, instrument};
 /// Encrypt a file with GPG
 #[instrument]
 pub fn encrypt(recipients: &[String], input: &Path, output: &Path) -> Result<()> {
+    run_encrypt(recipients, input, output, &[])
+}
+
+/// Encrypt a file with GPG without compressing it first, so that the size of the
+/// output follows the size of the input
+#[instrument]
+pub fn encrypt_uncompressed(recipients: &[String], input: &Path, output: &Path) -> Result<()> {
+    run_encrypt(recipients, input, output, &["--compress-algo", "none"])
+}
+
+fn run_encrypt(recipients: &[String], input: &Path, output: &Path, args: &[&str]) -> Result<()> {
     if recipients.is_empty() {
         debug!("No GPG recipients specified, copying file without encryption");
         fs::copy(input, output).map_err(|e| anyhow!("failed to copy file: {}", e))?;
@@ -18,6 +29,7 @@ pub fn encrypt(recipients: &[String], input: &Path, output: &Path) -> Result<()>
         .arg("--yes")
         .arg("--output")
         .arg(output)
+        .args(args)
         .arg("--encrypt");
     for r in recipients {
         cmd.arg("--recipient").arg(r);
@@ -1,7 +1,15 @@
 // Internal modules only used within the crate
+pub mod cache; // Shared by the git-remote-s3 and git-s3 binaries
+pub mod fastexport; // Fast-export layout of git_s3, public for testing
+pub mod gc; // Used by the git-s3 binary
 pub mod git; // Make git module public for testing
+pub mod git_s3; // Shared by the git-remote-s3 and git-s3 binaries
 pub mod gpg; // Make gpg module public for testing
+pub mod mirror; // Used by the git-remote-s3 binary
+pub mod packs; // Pack layout of git_s3, public for testing
+pub mod pad; // Make pad module public for testing
 pub mod s3; // Make s3 module public for testing
+pub mod verify; // Used by the git-s3 binary
 
 // integration test is considered as external.
 pub mod log;
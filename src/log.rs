@@ -1,4 +1,5 @@
 use std::fmt;
+use std::fs::OpenOptions;
 use time::UtcOffset;
 use tracing::field::{Field, Visit};
 use tracing::Subscriber;
@@ -6,8 +7,29 @@ use tracing_subscriber::{
     field::RecordFields,
     fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
     registry::LookupSpan,
+    EnvFilter,
 };
 
+/// Initialize logging to /tmp/git-remote-s3.log, shared by the helper and `git-s3`
+pub fn init_file_logging() -> std::io::Result<()> {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new("error,git_remote_s3=info,git_s3=info"));
+
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open("/tmp/git-remote-s3.log")?;
+
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(move || file.try_clone().unwrap())
+        .event_format(GoogleEventFormat)
+        .fmt_fields(GoogleFormatFields)
+        .init();
+
+    Ok(())
+}
+
 /// Custom field formatter that disables ANSI colors
 pub struct GoogleFormatFields;
 
@@ -102,7 +124,7 @@ where
             write!(
                 writer,
                 "{}:{}] ",
-                file.split('/').last().unwrap_or(file),
+                file.split('/').next_back().unwrap_or(file),
                 event.metadata().line().unwrap_or(0)
             )?;
         }
@@ -1,38 +1,29 @@
 use anyhow::{anyhow, Result};
 use aws_sdk_s3::Client;
-use std::{env, io, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
+    io::{self, Write},
+    mem,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use tracing::{error, info, warn};
-use tracing_subscriber::EnvFilter;
 
-mod git;
-mod git_s3;
-mod gpg;
-mod log;
-mod s3;
-
-use crate::git_s3::{fetch_from_s3, list_refs, push_to_s3, GitRef, GitS3Settings};
-use crate::s3::create_client;
+use git_remote_s3::git_s3::{
+    append_reflog, delete_from_s3, demote_stale_refs, fetch_all_from_s3, fetch_objects_from_s3,
+    heads_changed, layout, list_refs, next_generation, push_to_s3, pusher, read_head, thin_basis,
+    write_head, FetchKind, FetchRef, GitRef, GitS3Settings, Layout, RefClass, ReflogEntry,
+    RemoteRefs, ZERO_SHA,
+};
+use git_remote_s3::mirror::{self, Mirror};
+use git_remote_s3::s3::create_client;
+use git_remote_s3::{fastexport, git, log};
 
 // implemented the git-remote-helpers protocol: https://git-scm.com/docs/gitremote-helpers
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let filter = EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| EnvFilter::new("error,git_remote_s3=info"));
-
-    // Initialize logging to /tmp/git-remote-s3.log
-    let log_path = PathBuf::from("/tmp/git-remote-s3.log");
-    let file = std::fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&log_path)?;
-
-    tracing_subscriber::fmt()
-        .with_env_filter(filter)
-        .with_writer(move || file.try_clone().unwrap())
-        .event_format(log::GoogleEventFormat)
-        .fmt_fields(log::GoogleFormatFields)
-        .init();
+    log::init_file_logging()?;
 
     info!(message = "-".repeat(80));
 
@@ -40,8 +31,13 @@ async fn main() -> Result<()> {
     let helper = args.next().unwrap();
     let alias = args.next().ok_or_else(|| anyhow!("must provide alias"))?;
     let url = args.next().ok_or_else(|| anyhow!("must provide url"))?;
+    // Only the proc-receive hook of a mirror passes a mode
+    let mode = args.next();
+    // Syncing a mirror runs us from the mirror by url, for the remote it mirrors
+    let alias = env::var(mirror::ALIAS_ENV).unwrap_or(alias);
     info!(?helper, ?alias, ?url, "Starting ");
 
+    GitS3Settings::validate_url(&url)?;
     let settings = GitS3Settings::new(alias, url);
     let s3 = create_client(
         settings.region().map(String::from),
@@ -50,6 +46,12 @@ async fn main() -> Result<()> {
     .await?;
     info!("S3 client initialized");
 
+    if mode.as_deref() == Some(mirror::PROC_RECEIVE_ARG) {
+        return mirror::proc_receive(&settings).inspect_err(|e| {
+            error!(?e, "proc-receive hook failed");
+        });
+    }
+
     match cmd_loop(&s3, &settings).await {
         Ok(_) => Ok(()),
         Err(e) => {
@@ -60,9 +62,31 @@ async fn main() -> Result<()> {
 }
 
 async fn cmd_loop(s3: &Client, settings: &GitS3Settings) -> Result<()> {
+    // fetch and push commands are sent in batches terminated by a blank line, and
+    // the whole batch is answered with a single blank line
+    let mut in_batch = false;
+    // refs to fetch in the current batch, downloaded together when the batch ends
+    let mut fetch_batch = Vec::new();
+    // objects a partial clone asks for in the current batch
+    let mut object_batch = Vec::new();
+    // refs to import in the current batch, with the fast-export layout
+    let mut import_batch = Vec::new();
+    // remote refs, listed once per session and kept up to date with our own writes
+    let mut remote_refs = None;
+    let mut fetch_options = FetchOptions::default();
+    // whether the export that follows was forced, with the fast-export layout
+    let mut force = false;
+    // whether stale heads were already warned about in this session
+    let mut warned = false;
+    // refs git asked for in the current batch, after which the private refs are
+    // updated
+    let mut fetched_refs = Vec::new();
     loop {
         let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
+        if io::stdin().read_line(&mut input)? == 0 {
+            warn_stale_heads(settings, remote_refs.as_ref(), &mut warned)?;
+            return Ok(());
+        }
 
         let mut iter = input.split_ascii_whitespace();
         let cmd = iter.next();
@@ -70,12 +94,71 @@ async fn cmd_loop(s3: &Client, settings: &GitS3Settings) -> Result<()> {
         let arg2 = iter.next();
 
         let result = match (cmd, arg1, arg2) {
-            (Some("push"), Some(ref_arg), None) => cmd_push(s3, settings, ref_arg).await,
-            (Some("fetch"), Some(sha), Some(name)) => cmd_fetch(s3, settings, sha, name).await,
-            (Some("capabilities"), None, None) => cmd_capabilities(),
-            (Some("list"), None, None) => cmd_list(s3, settings).await,
-            (Some("list"), Some("for-push"), None) => cmd_list(s3, settings).await,
-            (None, None, None) => return Ok(()),
+            (Some("push"), Some(ref_arg), None) => {
+                in_batch = true;
+                cmd_push(s3, settings, &mut remote_refs, ref_arg).await
+            }
+            (Some("fetch"), Some(sha), Some(name)) => {
+                in_batch = true;
+                fetched_refs.push(name.to_string());
+                cmd_fetch(
+                    sha,
+                    name,
+                    &fetch_options,
+                    remote_refs.as_ref(),
+                    &mut fetch_batch,
+                    &mut object_batch,
+                )
+            }
+            (Some("import"), Some(name), None) => {
+                in_batch = true;
+                import_batch.push(name.to_string());
+                Ok(())
+            }
+            (Some("export"), None, None) => cmd_export(s3, settings, force).await,
+            (Some("option"), Some(name), Some(value)) => {
+                cmd_option(name, value, &mut fetch_options, &mut force)
+            }
+            (Some("connect"), Some(service), None) => {
+                match cmd_connect(s3, settings, service).await {
+                    // The helper exits once the service is done
+                    Ok(true) => return Ok(()),
+                    result => result.map(|_| ()),
+                }
+            }
+            (Some("capabilities"), None, None) => cmd_capabilities(s3, settings).await,
+            (Some("list"), None, None) => cmd_list(s3, settings, &mut remote_refs).await,
+            (Some("list"), Some("for-push"), None) => {
+                cmd_list(s3, settings, &mut remote_refs).await
+            }
+            (None, None, None) if in_batch && !import_batch.is_empty() => {
+                // Answered by the stream itself, which ends with done
+                in_batch = false;
+                cmd_import(s3, settings, &mem::take(&mut import_batch)).await
+            }
+            (None, None, None) if in_batch => {
+                in_batch = false;
+                fetch_batch_from_s3(
+                    s3,
+                    settings,
+                    &mut remote_refs,
+                    &mem::take(&mut fetch_batch),
+                    &mem::take(&mut object_batch),
+                )
+                .await?;
+                // git runs helpers with GIT_DIR set, except outside a repository
+                let fetched_refs = mem::take(&mut fetched_refs);
+                if !fetched_refs.is_empty() && env::var_os("GIT_DIR").is_some() {
+                    let refs = session_refs(s3, settings, &mut remote_refs).await?;
+                    update_private_refs(s3, settings, refs, &fetched_refs, &fetch_options).await?;
+                }
+                println!();
+                warn_stale_heads(settings, remote_refs.as_ref(), &mut warned)
+            }
+            (None, None, None) => {
+                warn_stale_heads(settings, remote_refs.as_ref(), &mut warned)?;
+                return Ok(());
+            }
             _ => cmd_unknown(),
         };
 
@@ -86,6 +169,131 @@ async fn cmd_loop(s3: &Client, settings: &GitS3Settings) -> Result<()> {
     }
 }
 
+/// The remote refs of this session. The bucket is listed on first use only: later
+/// commands see the refs as updated by our own pushes and deletions.
+async fn session_refs<'a>(
+    s3: &Client,
+    settings: &GitS3Settings,
+    remote_refs: &'a mut Option<HashMap<String, RemoteRefs>>,
+) -> Result<&'a mut HashMap<String, RemoteRefs>> {
+    let refs = match remote_refs.take() {
+        Some(refs) => refs,
+        None => list_refs(s3, settings).await?,
+    };
+    Ok(remote_refs.insert(refs))
+}
+
+/// Download everything queued by the fetch commands of a batch.
+async fn fetch_batch_from_s3(
+    s3: &Client,
+    settings: &GitS3Settings,
+    remote_refs: &mut Option<HashMap<String, RemoteRefs>>,
+    fetch_batch: &[FetchRef],
+    object_batch: &[String],
+) -> Result<()> {
+    fetch_all_from_s3(s3, settings, fetch_batch).await?;
+    if !object_batch.is_empty() {
+        let refs = session_refs(s3, settings, remote_refs).await?;
+        fetch_objects_from_s3(s3, settings, refs, object_batch).await?;
+    }
+    Ok(())
+}
+
+/// Update the private refs of the remote after a fetch: `refs/s3/<alias>/heads/`
+/// mirrors its branches, as git does itself after a push, and the hidden
+/// `refs/s3/<alias>/stale/` holds the stale heads of its refs. `list` only shows
+/// git the newest head of each ref, so `git branch -r` stays clean, but the other
+/// heads can still be inspected.
+///
+/// Stale heads missing locally are downloaded for the refs of the batch only, and
+/// not at all for shallow or partial fetches, which would otherwise pull in the
+/// full history they left out. Those of other refs are recorded once present.
+async fn update_private_refs(
+    s3: &Client,
+    settings: &GitS3Settings,
+    remote_refs: &HashMap<String, RemoteRefs>,
+    fetched_refs: &[String],
+    fetch_options: &FetchOptions,
+) -> Result<()> {
+    let current_dir = env::current_dir()?;
+    let download = fetch_options.depth.is_none()
+        && !fetch_options.filter_blobs
+        && git::read_shallow(&current_dir)?.is_empty();
+    let namespace = settings.private_namespace();
+    let mut private = BTreeMap::new();
+    // git may have fetched only some of the branches, the others are left as they are
+    let mut unfetched = HashSet::new();
+    let mut missing: Vec<FetchRef> = Vec::new();
+    for (name, refs) in remote_refs.iter() {
+        if let Some(branch) = name.strip_prefix("refs/heads/") {
+            let private_name = format!("{}/heads/{}", namespace, branch);
+            let sha = &refs.latest_ref().reference.sha;
+            if git::object_exists(sha, &current_dir)? {
+                private.insert(private_name, sha.clone());
+            } else {
+                unfetched.insert(private_name);
+            }
+        }
+        for stale_ref in refs.stale_refs() {
+            let sha = &stale_ref.reference.sha;
+            if !missing.iter().any(|r| &r.reference.sha == sha)
+                && !git::object_exists(sha, &current_dir)?
+            {
+                if !download || !fetched_refs.contains(name) {
+                    continue;
+                }
+                missing.push(FetchRef {
+                    reference: stale_ref.reference.clone(),
+                    kind: FetchKind::Full,
+                });
+            }
+            private.insert(settings.stale_ref(name, sha), sha.clone());
+        }
+    }
+    if !missing.is_empty() {
+        info!(count = missing.len(), "Fetching stale heads");
+        fetch_all_from_s3(s3, settings, &missing).await?;
+    }
+
+    for (name, sha) in git::refs_under(&format!("{}/", namespace), &current_dir)? {
+        match private.remove(&name) {
+            Some(private_sha) if private_sha == sha => {}
+            Some(private_sha) => git::update_ref(&name, &private_sha, &current_dir)?,
+            None if unfetched.contains(&name) => {}
+            None => git::delete_ref(&name, &current_dir)?,
+        }
+    }
+    for (name, sha) in private {
+        git::update_ref(&name, &sha, &current_dir)?;
+    }
+    Ok(())
+}
+
+/// Options that change what a fetch downloads, set with the option command
+#[derive(Debug, Default)]
+struct FetchOptions {
+    /// Requested history depth, `None` for the full history
+    depth: Option<u32>,
+    /// Whether the fetch changes the shallow boundary of the repository
+    deepen: bool,
+    /// Whether the depth is relative to the current shallow boundary
+    deepen_relative: bool,
+    /// Whether a date boundary was requested, which git doesn't abort on itself
+    deepen_since: bool,
+    /// Whether blobs are left out, for a partial clone with `--filter=blob:none`
+    filter_blobs: bool,
+}
+
+// `git fetch --unshallow` asks for this depth
+const INFINITE_DEPTH: u32 = 0x7fffffff;
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
 fn cmd_unknown() -> Result<()> {
     println!("unknown command");
     println!();
@@ -99,11 +307,116 @@ fn cmd_unknown() -> Result<()> {
 /// is a fatal error.
 ///
 /// Support for this command is mandatory.
+///
+/// Remotes using the fast-export layout are fetched with import and pushed with
+/// export, sharing a marks file with git. The refspec maps the branches of the
+/// remote to the private namespace `refs/s3/<alias>/heads/`, which import writes
+/// and git updates after a push.
+async fn cmd_capabilities(s3: &Client, settings: &GitS3Settings) -> Result<()> {
+    if layout(s3, settings).await? == Layout::FastExport {
+        let marks_file = fastexport::marks_file(settings)?;
+        println!("import");
+        println!("export");
+        println!("*import-marks {}", marks_file.display());
+        println!("*export-marks {}", marks_file.display());
+    } else {
+        println!("*push");
+        println!("*fetch");
+    }
+    println!("refspec {}", settings.refspec());
+    println!("option");
+    if uses_mirror(settings) {
+        println!("connect");
+    }
+    println!();
+    Ok(())
+}
+
+/// Whether git talks to the remote through a local mirror. The helper runs that
+/// sync a mirror use bundles.
+fn uses_mirror(settings: &GitS3Settings) -> bool {
+    env::var_os(mirror::ALIAS_ENV).is_none() && settings.local_mirror()
+}
+
+/// connect <service>
+/// Connects to given service. Standard input and standard output of helper are
+/// connected to specified service (git prefix is included in service name so e.g.
+/// fetching uses git-upload-pack as service) on remote side. Valid replies to this
+/// command are empty line (connection established), fallback (no smart transport
+/// support, fall back to dumb transports) and just exiting with error message
+/// printed (can't connect, don't bother trying to fall back). After line feed
+/// terminating the positive (empty) response, the output of service starts. After
+/// the connection ends, the remote helper exits.
+///
+/// Supported if the helper has the "connect" capability.
+///
+/// The service runs against a local mirror of the remote, synced from S3 before
+/// the session. receive-pack pushes each ref update on to S3 before reporting it. Returns whether the session took place:
+/// if the mirror can't be synced, git falls back to fetch and push with bundles.
+async fn cmd_connect(s3: &Client, settings: &GitS3Settings, service: &str) -> Result<bool> {
+    if !uses_mirror(settings) || !matches!(service, "git-upload-pack" | "git-receive-pack") {
+        println!("fallback");
+        return Ok(false);
+    }
+
+    let mirror = Mirror::open(settings)?;
+    if let Err(e) = mirror.sync_from_s3(s3, settings).await {
+        warn!(?e, "Failed to sync mirror, falling back to bundles");
+        eprintln!("warning: {:#}, falling back to bundles", e);
+        println!("fallback");
+        return Ok(false);
+    }
 
-fn cmd_capabilities() -> Result<()> {
-    println!("*push");
-    println!("*fetch");
     println!();
+    io::stdout().flush()?;
+    mirror.serve(service)?;
+    Ok(true)
+}
+
+/// option <name> <value>
+/// Sets the transport helper option <name> to <value>. Outputs a single line
+/// containing one of ok (option successfully set), unsupported (option not
+/// recognized) or error <msg> (option <name> is supported but <value> is not valid
+/// for it). Options should be set before other commands, and may influence the
+/// behavior of those commands.
+///
+/// Supported if the helper has the "option" capability.
+fn cmd_option(
+    name: &str,
+    value: &str,
+    fetch_options: &mut FetchOptions,
+    force: &mut bool,
+) -> Result<()> {
+    match name {
+        "depth" => match value.parse::<u32>() {
+            Ok(depth) => {
+                fetch_options.depth = Some(depth).filter(|depth| *depth < INFINITE_DEPTH);
+                fetch_options.deepen = true;
+                println!("ok");
+            }
+            Err(_) => println!("error invalid depth: {}", value),
+        },
+        // Bundles are cut by depth, not by date, so the boundary can't be honoured
+        "deepen-since" => {
+            fetch_options.deepen_since = true;
+            println!("error deepen-since is not supported");
+        }
+        "deepen-relative" => {
+            fetch_options.deepen_relative = value == "true";
+            println!("ok");
+        }
+        // Other filters fall back to fetching everything, which git accepts
+        "filter" if value == "blob:none" => {
+            fetch_options.filter_blobs = true;
+            println!("ok");
+        }
+        // Sent before export for --force only, push commands mark forced refs with +
+        "force" => {
+            *force = value == "true";
+            println!("ok");
+        }
+        _ => println!("unsupported"),
+    }
     Ok(())
 }
 
@@ -127,33 +440,89 @@ fn cmd_capabilities() -> Result<()> {
 /// performed.
 ///
 /// Supported if the helper has the "push" or "export" capability.
-
-async fn cmd_list(s3: &Client, settings: &GitS3Settings) -> Result<()> {
-    let refs = list_refs(s3, settings).await?;
+async fn cmd_list(
+    s3: &Client,
+    settings: &GitS3Settings,
+    remote_refs: &mut Option<HashMap<String, RemoteRefs>>,
+) -> Result<()> {
+    let refs = session_refs(s3, settings, remote_refs).await?;
     if !refs.is_empty() {
         for (_, refs) in refs.iter() {
             let latest = refs.latest_ref();
             println!("{} {}", latest.reference.sha, latest.reference.name);
-
-            for stale_ref in refs.stale_refs() {
-                let short_sha = &stale_ref.reference.sha[..7];
-                println!(
-                    "{} {}__{}",
-                    stale_ref.reference.sha, stale_ref.reference.name, short_sha
-                );
+            if let Some(peeled) = &latest.peeled {
+                println!("{} {}^{{}}", peeled, latest.reference.name);
             }
         }
 
-        if refs.contains_key("refs/heads/main") {
-            println!("@refs/heads/main HEAD");
-        } else if refs.contains_key("refs/heads/master") {
-            println!("@refs/heads/master HEAD");
+        // Remotes pushed before HEAD was stored fall back to main or master
+        let head = read_head(s3, settings).await?;
+        let head = head
+            .as_deref()
+            .into_iter()
+            .chain(["refs/heads/main", "refs/heads/master"])
+            .find(|target| refs.contains_key(*target));
+        if let Some(target) = head {
+            println!("@{} HEAD", target);
         }
     }
     println!();
     Ok(())
 }
 
+/// Warns on stderr, once per session, about refs with stale heads the newest head
+/// doesn't include. git doesn't show them, they are only kept in the hidden
+/// `refs/s3/<alias>/stale/` namespace. Heads missing locally may have diverged, so
+/// this runs at the end of the first fetch or push batch, or of the session.
+fn warn_stale_heads(
+    settings: &GitS3Settings,
+    remote_refs: Option<&HashMap<String, RemoteRefs>>,
+    warned: &mut bool,
+) -> Result<()> {
+    let Some(refs) = remote_refs.filter(|_| !*warned) else {
+        return Ok(());
+    };
+    *warned = true;
+
+    let current_dir = env::current_dir()?;
+    let included = |stale: &str, latest: &str| -> Result<bool> {
+        Ok(git::object_exists(stale, &current_dir)?
+            && git::object_exists(latest, &current_dir)?
+            && git::is_ancestor(stale, latest, &current_dir)?)
+    };
+
+    let mut names: Vec<&String> = refs.keys().collect();
+    names.sort();
+    let mut any = false;
+    for name in names {
+        let latest = &refs[name].latest_ref().reference.sha;
+        let mut diverged = Vec::new();
+        for stale in refs[name].stale_refs() {
+            if !included(&stale.reference.sha, latest)? {
+                diverged.push(&stale.reference.sha[..7]);
+            }
+        }
+        if diverged.is_empty() {
+            continue;
+        }
+        warn!(?name, ?diverged, "Ref has diverged heads");
+        eprintln!(
+            "warning: {} has heads on the remote that its newest head {} doesn't include: {}",
+            name,
+            &latest[..7],
+            diverged.join(", ")
+        );
+        any = true;
+    }
+    if any {
+        eprintln!(
+            "hint: run `git s3 resolve {} <ref>` to merge them or keep one",
+            settings.remote_alias
+        );
+    }
+    Ok(())
+}
+
 /// fetch <sha1> <name>
 /// Fetches the given object, writing the necessary objects to the database. Fetch
 /// commands are sent in a batch, one per line, terminated with a blank line.
@@ -173,17 +542,173 @@ async fn cmd_list(s3: &Client, settings: &GitS3Settings) -> Result<()> {
 /// connectivity-ok if the clone is self-contained and connected.
 ///
 /// Supported if the helper has the "fetch" capability.
-
-async fn cmd_fetch(s3: &Client, settings: &GitS3Settings, sha: &str, name: &str) -> Result<()> {
-    if name == "HEAD" {
-        // Ignore head, as it's guaranteed to point to a ref we already downloaded
+///
+/// Refs are queued here and downloaded concurrently once the batch is complete.
+/// Partial clones fetching promised objects name them by their sha instead of a
+/// ref, these are queued separately.
+fn cmd_fetch(
+    sha: &str,
+    name: &str,
+    fetch_options: &FetchOptions,
+    remote_refs: Option<&HashMap<String, RemoteRefs>>,
+    fetch_batch: &mut Vec<FetchRef>,
+    object_batch: &mut Vec<String>,
+) -> Result<()> {
+    if fetch_options.deepen_since {
+        return Err(anyhow!("deepen-since is not supported"));
+    }
+    if name == sha {
+        // git only asks for objects it knows are missing
+        info!(?sha, "Queueing missing object");
+        object_batch.push(sha.to_string());
+        return Ok(());
+    }
+    if name == "HEAD" || name.ends_with("^{}") {
+        // Ignore head and peeled tags, as they're guaranteed to point to a ref we
+        // already downloaded
         return Ok(());
     }
     let git_ref = GitRef {
         name: name.to_string(),
         sha: sha.to_string(),
     };
-    fetch_from_s3(s3, settings, &git_ref).await?;
+    if fetch_batch.iter().any(|r| r.reference.sha == git_ref.sha) {
+        // The bundle of the earlier ref brings in the same objects
+        info!(?git_ref, "Object already fetched in this batch");
+        return Ok(());
+    }
+    if !fetch_options.deepen && git::object_exists(sha, &env::current_dir()?)? {
+        // Common after pushing from this repository, or when several refs point
+        // to the same commit. Deepening a shallow clone needs the bundle anyway.
+        info!(
+            ?git_ref,
+            "Object already present locally, skipping download"
+        );
+        return Ok(());
+    }
+
+    // A shallow snapshot at least as deep as requested saves downloading the full
+    // history, deeper history comes from the full bundle. Partial clones only need
+    // the pack of commits and trees.
+    let latest = remote_refs
+        .and_then(|refs| refs.get(name))
+        .map(|refs| refs.latest_ref())
+        .filter(|latest| latest.reference.sha == sha);
+    let snapshot_depth = latest.and_then(|latest| latest.shallow_depth);
+    let kind = match (fetch_options.depth, snapshot_depth) {
+        (Some(depth), Some(snapshot_depth))
+            if depth <= snapshot_depth && !fetch_options.deepen_relative =>
+        {
+            FetchKind::Shallow(snapshot_depth)
+        }
+        _ if fetch_options.filter_blobs && latest.is_some_and(|latest| latest.blob_packs) => {
+            FetchKind::Trees
+        }
+        _ => FetchKind::Full,
+    };
+
+    // A thin tag bundle needs the branch heads it was built on, which a fetch of
+    // the tag alone may not bring in: download the missing ones first
+    let current_dir = env::current_dir()?;
+    for head in latest.iter().flat_map(|latest| &latest.basis) {
+        if fetch_batch.iter().any(|r| r.reference.sha == *head)
+            || git::object_exists(head, &current_dir)?
+        {
+            continue;
+        }
+        let basis = remote_refs
+            .into_iter()
+            .flat_map(|refs| refs.values())
+            .flat_map(|refs| refs.all_refs())
+            .find(|r| r.reference.sha == *head);
+        if let Some(basis) = basis {
+            info!(?git_ref, ?basis.reference, "Queueing basis of thin tag");
+            fetch_batch.push(FetchRef {
+                reference: basis.reference.clone(),
+                kind: FetchKind::Full,
+            });
+        }
+    }
+
+    fetch_batch.push(FetchRef {
+        reference: git_ref,
+        kind,
+    });
+    Ok(())
+}
+
+/// import <name>
+/// Produces a fast-import stream which imports the current value of the named ref.
+/// It may additionally import other refs as needed to construct the history
+/// efficiently. The script writes to a helper-specific private namespace. The value
+/// of the named ref should be written to a location in this namespace derived by
+/// applying the refspecs from the "refspec" capability to the name of the ref.
+///
+/// Especially useful for interoperability with a foreign versioning system.
+///
+/// Just like push, a batch sequence of one or more import is terminated with a
+/// blank line. For each batch of import, the remote helper should produce a
+/// fast-import stream terminated by a done command.
+///
+/// Supported if the helper has the "import" capability.
+async fn cmd_import(s3: &Client, settings: &GitS3Settings, refs: &[String]) -> Result<()> {
+    fastexport::import(s3, settings, refs, &mut io::stdout().lock()).await
+}
+
+/// export
+/// Instructs the remote helper that any subsequent input is part of a fast-import
+/// stream (generated by git fast-export) containing objects which should be pushed
+/// to the remote.
+///
+/// Especially useful for interoperability with a foreign versioning system.
+///
+/// The export-marks and import-marks capabilities, if specified, affect this
+/// command in so far as they are passed on to git fast-export, which then will
+/// load/store a table of marks for local objects. This can be used to implement
+/// for incremental operations.
+///
+/// Supported if the helper has the "export" capability.
+///
+/// Outputs ok <dst> or error <dst> <why> for each ref the stream updated,
+/// terminated by a blank line, and records the updates in the remote reflog like
+/// push.
+async fn cmd_export(s3: &Client, settings: &GitS3Settings, force: bool) -> Result<()> {
+    let updates = fastexport::export(s3, settings, &mut io::stdin().lock(), force).await?;
+
+    let current_dir = env::current_dir()?;
+    for update in updates.iter().filter(|update| update.rejected.is_none()) {
+        let entry = ReflogEntry {
+            old_sha: update
+                .old_sha
+                .clone()
+                .unwrap_or_else(|| ZERO_SHA.to_string()),
+            new_sha: update
+                .new_sha
+                .clone()
+                .unwrap_or_else(|| ZERO_SHA.to_string()),
+            pusher: pusher(&current_dir),
+            timestamp: unix_now(),
+            forced: update.forced,
+        };
+        append_reflog(s3, settings, &update.name, &entry).await?;
+
+        // The first push of the local default branch makes it the remote default
+        if git::symbolic_ref("HEAD", &current_dir).is_ok_and(|target| target == update.name)
+            && read_head(s3, settings).await?.is_none()
+        {
+            write_head(s3, settings, &update.name).await?;
+        }
+    }
+
+    for update in &updates {
+        match &update.rejected {
+            Some(reason) => {
+                warn!(?update.name, ?reason, "Export rejected");
+                println!("error {} {}", update.name, reason);
+            }
+            None => println!("ok {}", update.name),
+        }
+    }
     println!();
     Ok(())
 }
@@ -211,8 +736,12 @@ async fn cmd_fetch(s3: &Client, settings: &GitS3Settings, sha: &str, name: &str)
 /// C style string if it contains an LF.
 ///
 /// Supported if the helper has the "push" capability.
-
-async fn cmd_push(s3: &Client, settings: &GitS3Settings, push_ref: &str) -> Result<()> {
+async fn cmd_push(
+    s3: &Client,
+    settings: &GitS3Settings,
+    remote_refs: &mut Option<HashMap<String, RemoteRefs>>,
+    push_ref: &str,
+) -> Result<()> {
     let force = push_ref.starts_with('+');
     let (src, dst) = match push_ref.trim_start_matches('+').split_once(':') {
         Some((src, dst)) => (src, dst),
@@ -223,27 +752,105 @@ async fn cmd_push(s3: &Client, settings: &GitS3Settings, push_ref: &str) -> Resu
     };
 
     let current_dir = env::current_dir()?;
+    let refs = session_refs(s3, settings, remote_refs).await?;
+    let prev_sha = refs
+        .get(dst)
+        .map(|prev_refs| prev_refs.latest_ref().reference.sha.clone());
+
+    // The refs were listed at the start of the session, a push from elsewhere since
+    // would be overwritten or deleted unseen
+    if heads_changed(s3, settings, dst, refs.get(dst)).await? {
+        warn!(?dst, "Remote changed since it was listed");
+        println!("error {} fetch first", dst);
+        // Later commands list the bucket again
+        *remote_refs = None;
+        return Ok(());
+    }
+
+    if src.is_empty() {
+        // push :<dst> deletes the remote ref
+        if let (Some(prev_refs), Some(prev_sha)) = (refs.remove(dst), prev_sha) {
+            delete_from_s3(s3, settings, prev_refs.all_refs()).await?;
+            let entry = ReflogEntry {
+                old_sha: prev_sha,
+                new_sha: ZERO_SHA.to_string(),
+                pusher: pusher(&current_dir),
+                timestamp: unix_now(),
+                forced: false,
+            };
+            append_reflog(s3, settings, dst, &entry).await?;
+        }
+        println!("ok {}", dst);
+        return Ok(());
+    }
+
     let local_ref = GitRef {
         name: dst.to_string(),
         sha: git::rev_parse(src, &current_dir)?,
     };
 
-    let refs = list_refs(s3, settings).await?;
-    if !force {
-        if let Some(prev_refs) = refs.get(&local_ref.name) {
-            let prev_ref = prev_refs.latest_ref();
-            if !prev_ref.reference.sha.is_empty() {
-                if !git::is_ancestor(&prev_ref.reference.sha, &local_ref.sha, &current_dir)? {
-                    warn!(?dst, "Remote changed - force push required");
-                    println!("error {} remote changed: force push required", dst);
-                    return Ok(());
-                }
-            }
+    let is_tag = RefClass::of(dst) == RefClass::Tags;
+    let fast_forward = match &prev_sha {
+        // Tags are immutable, any change to an existing tag is a forced update
+        Some(prev_sha) if is_tag => *prev_sha == local_ref.sha,
+        Some(prev_sha) if !prev_sha.is_empty() => {
+            git::is_ancestor(prev_sha, &local_ref.sha, &current_dir)?
+        }
+        _ => true,
+    };
+    if !force && !fast_forward {
+        if is_tag {
+            warn!(?dst, "Tag exists - force push required");
+            println!("error {} already exists", dst);
+        } else {
+            warn!(?dst, "Remote changed - force push required");
+            println!("error {} remote changed: force push required", dst);
+        }
+        return Ok(());
+    }
+
+    let basis = if is_tag {
+        thin_basis(refs, &local_ref, &current_dir)?
+    } else {
+        Vec::new()
+    };
+    let generation = next_generation(s3, settings, refs.get(&local_ref.name)).await?;
+    let pushed = push_to_s3(s3, settings, &local_ref, &basis, generation).await?;
+    if let Some(prev_refs) = refs.get_mut(&local_ref.name) {
+        if !is_tag {
+            demote_stale_refs(s3, settings, prev_refs, &local_ref).await?;
         }
     }
 
-    push_to_s3(s3, settings, &local_ref).await?;
+    // The first push of the local default branch makes it the remote default
+    if git::symbolic_ref("HEAD", &current_dir).is_ok_and(|target| target == dst)
+        && read_head(s3, settings).await?.is_none()
+    {
+        write_head(s3, settings, dst).await?;
+    }
+
+    if is_tag {
+        // A tag only ever has a single head, replace the previous one
+        if let Some(prev_refs) = refs.remove(&local_ref.name) {
+            let replaced = prev_refs
+                .all_refs()
+                .filter(|r| r.reference.sha != local_ref.sha);
+            delete_from_s3(s3, settings, replaced).await?;
+        }
+    }
+    refs.entry(local_ref.name.clone())
+        .or_default()
+        .add_latest_ref(pushed);
+
+    let entry = ReflogEntry {
+        old_sha: prev_sha.unwrap_or_else(|| ZERO_SHA.to_string()),
+        new_sha: local_ref.sha.clone(),
+        pusher: pusher(&current_dir),
+        timestamp: unix_now(),
+        forced: !fast_forward,
+    };
+    append_reflog(s3, settings, dst, &entry).await?;
+
     println!("ok {}", dst);
-    println!();
     Ok(())
 }
@@ -0,0 +1,461 @@
+use anyhow::{anyhow, Context, Result};
+use aws_sdk_s3::Client;
+use std::{
+    collections::HashMap,
+    env::{current_dir, current_exe},
+    io::{self, Read, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+use tracing::{error, info, instrument, warn};
+
+use crate::git;
+use crate::git_s3::{read_head, GitS3Settings, RefClass, ZERO_SHA};
+
+/// Set for the helper runs that sync a mirror with S3: they read the settings of
+/// the remote named by it, and never connect themselves.
+pub const ALIAS_ENV: &str = "GIT_S3_MIRROR_ALIAS";
+
+/// Extra argument the mirror's `proc-receive` hook runs the helper with
+pub const PROC_RECEIVE_ARG: &str = "proc-receive";
+
+/// A bare repository mirroring the branches and tags of an S3 remote, in
+/// `$GIT_DIR/s3/mirrors/<alias>`. git's own upload-pack and receive-pack run against
+/// it for `connect`, so fetches negotiate the missing objects and pushes send thin
+/// packs. It is synced with S3 by fetching into it and pushing from it with this
+/// helper, which receive-pack runs as its `proc-receive` hook so that the client
+/// learns whether S3 took the push.
+#[derive(Debug)]
+pub struct Mirror {
+    dir: PathBuf,
+}
+
+impl Mirror {
+    /// The mirror of a remote, created on first use. Its config includes the
+    /// repository's, for the settings of the remote and the pusher's identity.
+    pub fn open(settings: &GitS3Settings) -> Result<Mirror> {
+        let current_dir = current_dir()?;
+        let mirror = Mirror {
+            dir: git::git_path(
+                &format!("s3/mirrors/{}", settings.safe_alias()),
+                &current_dir,
+            )?,
+        };
+
+        if !mirror.dir.join("HEAD").exists() {
+            info!(?mirror.dir, "Creating mirror");
+            std::fs::create_dir_all(&mirror.dir)?;
+            mirror.run(&["init", "--quiet", "--bare"])?;
+            // Included first, so that the mirror's own core settings win
+            let included = git::git_path("config", &current_dir)?;
+            let config = mirror.dir.join("config");
+            let own = std::fs::read_to_string(&config)?;
+            std::fs::write(
+                &config,
+                format!("[include]\n\tpath = {}\n{}", included.display(), own),
+            )
+            .with_context(|| format!("Failed to write {}", config.display()))?;
+        }
+        mirror.install_hook(settings)?;
+        Ok(mirror)
+    }
+
+    /// The mirror a `proc-receive` hook runs in: receive-pack runs the hooks of a
+    /// bare repository from the repository itself.
+    fn hook() -> Result<Mirror> {
+        Ok(Mirror {
+            dir: current_dir()?,
+        })
+    }
+
+    /// Let receive-pack hand every ref update to this helper, and keep the private
+    /// refs the helper writes on fetch out of what the mirror advertises. Refreshed
+    /// on every open, as the helper may have moved.
+    fn install_hook(&self, settings: &GitS3Settings) -> Result<()> {
+        let quote = |arg: &str| format!("'{}'", arg.replace('\'', "'\\''"));
+        let hook = self.dir.join("hooks/proc-receive");
+        std::fs::create_dir_all(self.dir.join("hooks"))?;
+        std::fs::write(
+            &hook,
+            format!(
+                "#!/bin/sh\nexec {} {} {} {}\n",
+                quote(&current_exe()?.to_string_lossy()),
+                quote(&settings.remote_alias),
+                quote(&settings.url),
+                PROC_RECEIVE_ARG
+            ),
+        )
+        .with_context(|| format!("Failed to write {}", hook.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755))?;
+        }
+
+        self.run(&["config", "receive.procReceiveRefs", "refs"])?;
+        for hidden in ["uploadpack.hideRefs", "receive.hideRefs"] {
+            self.run(&["config", hidden, "refs/s3"])?;
+        }
+        Ok(())
+    }
+
+    /// A git command run in the mirror. The helper runs with `GIT_DIR` set to the
+    /// repository, which would take precedence over the working directory.
+    fn git(&self) -> Command {
+        let mut cmd = Command::new("git");
+        cmd.env("GIT_DIR", &self.dir);
+        cmd
+    }
+
+    fn run(&self, args: &[&str]) -> Result<String> {
+        let output = self.git().args(args).output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8(output.stderr).unwrap_or_default();
+            error!(?args, ?stderr, "Git command failed in mirror");
+            return Err(anyhow!("git {} failed in mirror", args[0]));
+        }
+        String::from_utf8(output.stdout).map_err(|e| anyhow!("git output not utf8: {}", e))
+    }
+
+    /// Run this helper from the mirror, with `args` for `git fetch` or `git push`.
+    /// Its stdout is the protocol channel of our own session, git's output is
+    /// dropped, warnings and errors go to stderr.
+    fn sync(&self, settings: &GitS3Settings, args: &[&str]) -> Result<bool> {
+        let status = self
+            .git()
+            .env(ALIAS_ENV, &settings.remote_alias)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .status()?;
+        Ok(status.success())
+    }
+
+    /// Update the mirror to the branches and tags on S3, and point its HEAD at the
+    /// remote's default branch.
+    #[instrument(skip(s3))]
+    pub async fn sync_from_s3(&self, s3: &Client, settings: &GitS3Settings) -> Result<()> {
+        // An empty refmap keeps fetch from creating remote-tracking refs
+        let args = [
+            "fetch",
+            "--quiet",
+            "--prune",
+            "--refmap=",
+            &settings.url,
+            "+refs/heads/*:refs/heads/*",
+            "+refs/tags/*:refs/tags/*",
+        ];
+        if !self.sync(settings, &args)? {
+            return Err(anyhow!("fetching {} into the mirror failed", settings.url));
+        }
+
+        let head = match read_head(s3, settings).await? {
+            Some(head) => head,
+            // Remotes pushed before HEAD was stored fall back to main or master, like
+            // `list`. Before the first push, receive-pack pushing the local default
+            // branch makes it the remote default, as without a mirror.
+            None => {
+                let local = git::symbolic_ref("HEAD", &current_dir()?)?;
+                let existing = [local.as_str(), "refs/heads/main", "refs/heads/master"]
+                    .into_iter()
+                    .find(|name| {
+                        self.run(&["rev-parse", "--verify", "--quiet", name])
+                            .is_ok()
+                    })
+                    .map(String::from);
+                existing.unwrap_or(local)
+            }
+        };
+        self.run(&["symbolic-ref", "HEAD", &head])?;
+        Ok(())
+    }
+
+    /// Serve a `connect` session: run `service` on the mirror, talking to git over
+    /// our stdin and stdout. receive-pack pushes the refs it changes to S3 with the
+    /// `proc-receive` hook.
+    #[instrument]
+    pub fn serve(&self, service: &str) -> Result<()> {
+        let status = Command::new("git")
+            .arg(service.trim_start_matches("git-"))
+            .arg(&self.dir)
+            .env_remove("GIT_DIR")
+            .status()?;
+        if !status.success() {
+            return Err(anyhow!("{} failed in the mirror", service));
+        }
+        Ok(())
+    }
+
+    /// Apply the updates receive-pack hands to the `proc-receive` hook to the
+    /// mirror, then push them to S3. Updates S3 doesn't take are rolled back, and
+    /// the reason is returned with them.
+    fn receive(&self, settings: &GitS3Settings, updates: &[Update]) -> Vec<Option<String>> {
+        let mut results: Vec<Option<String>> = updates
+            .iter()
+            .map(|update| {
+                let applied = if update.new == ZERO_SHA {
+                    self.run(&["update-ref", "-d", &update.name, &update.old])
+                } else {
+                    self.run(&["update-ref", &update.name, &update.new, &update.old])
+                };
+                applied
+                    .err()
+                    .map(|_| "failed to update the mirror".to_string())
+            })
+            .collect();
+
+        let mut refspecs = Vec::new();
+        for (update, _) in updates.iter().zip(&results).filter(|(_, r)| r.is_none()) {
+            refspecs.push(if update.new == ZERO_SHA {
+                format!(":{}", update.name)
+            } else if update.old == ZERO_SHA || self.is_fast_forward(update) {
+                format!("{}:{}", update.name, update.name)
+            } else {
+                // receive-pack has already accepted the forced update
+                format!("+{}:{}", update.name, update.name)
+            });
+        }
+        let pushed = if refspecs.is_empty() {
+            HashMap::new()
+        } else {
+            info!(?refspecs, "Pushing mirror to S3");
+            self.push(settings, &refspecs).unwrap_or_else(|e| {
+                warn!(?e, "Failed to push mirror to S3");
+                HashMap::new()
+            })
+        };
+
+        for (update, result) in updates.iter().zip(results.iter_mut()) {
+            if result.is_some() {
+                continue;
+            }
+            let rejected = match pushed.get(&update.name) {
+                Some(Ok(())) => continue,
+                Some(Err(reason)) => reason.clone(),
+                None => format!("pushing to {} failed", settings.url),
+            };
+            warn!(
+                ?update,
+                ?rejected,
+                "S3 refused update, rolling back the mirror"
+            );
+            let rollback = if update.old == ZERO_SHA {
+                self.run(&["update-ref", "-d", &update.name])
+            } else {
+                self.run(&["update-ref", &update.name, &update.old])
+            };
+            if let Err(e) = rollback {
+                // The next session resets the mirror to S3
+                error!(?e, ?update, "Failed to roll back the mirror");
+            }
+            *result = Some(rejected);
+        }
+        results
+    }
+
+    fn is_fast_forward(&self, update: &Update) -> bool {
+        RefClass::of(&update.name) != RefClass::Tags
+            && self
+                .git()
+                .args(["merge-base", "--is-ancestor", &update.old, &update.new])
+                .status()
+                .is_ok_and(|status| status.success())
+    }
+
+    /// Push refs of the mirror to S3 with this helper, and read which of them S3
+    /// took from git's porcelain output: `<flag>\t<from>:<to>\t<summary>` per ref,
+    /// `!` flagging a rejected one.
+    fn push(
+        &self,
+        settings: &GitS3Settings,
+        refspecs: &[String],
+    ) -> Result<HashMap<String, Result<(), String>>> {
+        let output = self
+            .git()
+            .env(ALIAS_ENV, &settings.remote_alias)
+            .args(["push", "--porcelain", &settings.url])
+            .args(refspecs)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()?;
+        let stdout = String::from_utf8(output.stdout)
+            .map_err(|e| anyhow!("git push output not utf8: {}", e))?;
+        Ok(stdout
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(3, '\t');
+                let (flag, refspec, summary) = (fields.next()?, fields.next()?, fields.next()?);
+                let name = refspec.rsplit_once(':')?.1.to_string();
+                let result = match flag {
+                    "!" => Err(summary.to_string()),
+                    _ => Ok(()),
+                };
+                Some((name, result))
+            })
+            .collect())
+    }
+}
+
+/// A ref update receive-pack hands to the `proc-receive` hook
+#[derive(Debug)]
+struct Update {
+    old: String,
+    new: String,
+    name: String,
+}
+
+/// Run as the mirror's `proc-receive` hook: read the ref updates of a push from
+/// receive-pack, push them to S3 through the mirror, and report for each whether
+/// it went through. See githooks(5) for the protocol, in pkt-lines.
+#[instrument]
+pub fn proc_receive(settings: &GitS3Settings) -> Result<()> {
+    let mut input = io::stdin().lock();
+    let mut output = io::stdout().lock();
+    run_proc_receive(&mut input, &mut output, |updates| {
+        Ok(Mirror::hook()?.receive(settings, updates))
+    })
+}
+
+/// The `proc-receive` protocol, with `receive` applying the updates and returning
+/// the reason each one was rejected, if it was.
+fn run_proc_receive(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    receive: impl FnOnce(&[Update]) -> Result<Vec<Option<String>>>,
+) -> Result<()> {
+    // Version negotiation, without push options
+    while read_pkt(input)?.is_some() {}
+    write_pkt(output, Some("version=1"))?;
+    write_pkt(output, None)?;
+    output.flush()?;
+
+    let mut updates = Vec::new();
+    while let Some(line) = read_pkt(input)? {
+        let mut words = line.split(' ');
+        match (words.next(), words.next(), words.next()) {
+            (Some(old), Some(new), Some(name)) => updates.push(Update {
+                old: old.to_string(),
+                new: new.to_string(),
+                name: name.to_string(),
+            }),
+            _ => return Err(anyhow!("invalid proc-receive command: {}", line)),
+        }
+    }
+
+    let results = receive(&updates)?;
+    for (update, result) in updates.iter().zip(results) {
+        match result {
+            None => write_pkt(output, Some(&format!("ok {}", update.name)))?,
+            Some(reason) => write_pkt(output, Some(&format!("ng {} {}", update.name, reason)))?,
+        }
+    }
+    write_pkt(output, None)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Read a pkt-line, `None` for a flush packet
+fn read_pkt(input: &mut impl Read) -> Result<Option<String>> {
+    let mut len = [0u8; 4];
+    input.read_exact(&mut len)?;
+    let len = std::str::from_utf8(&len)
+        .ok()
+        .and_then(|len| usize::from_str_radix(len, 16).ok())
+        .ok_or_else(|| anyhow!("invalid pkt-line length"))?;
+    if len == 0 {
+        return Ok(None);
+    }
+    let mut line = vec![0u8; len.saturating_sub(4)];
+    input.read_exact(&mut line)?;
+    let line = String::from_utf8(line).map_err(|e| anyhow!("pkt-line not utf8: {}", e))?;
+    Ok(Some(line.trim_end_matches('\n').to_string()))
+}
+
+/// Write a pkt-line, or a flush packet for `None`
+fn write_pkt(output: &mut impl Write, line: Option<&str>) -> Result<()> {
+    match line {
+        Some(line) => writeln!(output, "{:04x}{}", line.len() + 5, line)?,
+        None => output.write_all(b"0000")?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &str = "1111111111111111111111111111111111111111";
+    const NEW: &str = "2222222222222222222222222222222222222222";
+
+    fn pkts(lines: &[Option<&str>]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for line in lines {
+            write_pkt(&mut buf, *line).unwrap();
+        }
+        buf
+    }
+
+    #[test]
+    fn test_pkt_framing() {
+        let buf = pkts(&[Some("version=1"), None, Some("")]);
+        // The length covers the 4 length bytes and the newline
+        assert_eq!(buf, b"000eversion=1\n00000005\n");
+
+        let mut input = buf.as_slice();
+        assert_eq!(read_pkt(&mut input).unwrap().as_deref(), Some("version=1"));
+        assert_eq!(read_pkt(&mut input).unwrap(), None);
+        assert_eq!(read_pkt(&mut input).unwrap().as_deref(), Some(""));
+        assert!(read_pkt(&mut input).is_err());
+
+        // Lines without a trailing newline, as git may send them
+        let mut input = b"0009hello".as_slice();
+        assert_eq!(read_pkt(&mut input).unwrap().as_deref(), Some("hello"));
+
+        assert!(read_pkt(&mut b"zzzz".as_slice()).is_err());
+        assert!(read_pkt(&mut b"0010short".as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_proc_receive() {
+        let input = pkts(&[
+            Some("version=1\0atomic"),
+            None,
+            Some(&format!("{} {} refs/heads/main", OLD, NEW)),
+            Some(&format!("{} {} refs/heads/dev", NEW, ZERO_SHA)),
+            None,
+        ]);
+        let mut output = Vec::new();
+        run_proc_receive(&mut input.as_slice(), &mut output, |updates| {
+            assert_eq!(updates.len(), 2);
+            assert_eq!(
+                (
+                    updates[0].old.as_str(),
+                    updates[0].new.as_str(),
+                    updates[0].name.as_str()
+                ),
+                (OLD, NEW, "refs/heads/main")
+            );
+            assert_eq!(updates[1].new, ZERO_SHA);
+            Ok(vec![None, Some("fetch first".to_string())])
+        })
+        .unwrap();
+
+        let expected = pkts(&[
+            Some("version=1"),
+            None,
+            Some("ok refs/heads/main"),
+            Some("ng refs/heads/dev fetch first"),
+            None,
+        ]);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_proc_receive_invalid_command() {
+        let input = pkts(&[Some("version=1"), None, Some("refs/heads/main"), None]);
+        let result = run_proc_receive(&mut input.as_slice(), &mut Vec::new(), |_| {
+            panic!("nothing to receive")
+        });
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,487 @@
+use anyhow::{anyhow, Context, Result};
+use aws_sdk_s3::Client;
+use futures::stream::{self, StreamExt};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env::current_dir,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, info};
+
+use crate::cache::BundleCache;
+use crate::git_s3::{
+    bundle_metadata, download, encrypt_for_upload, write_layout, FetchRef, GitRef, GitS3Settings,
+    Layout, RefClass, RemoteRef, RemoteRefs,
+};
+use crate::{git, gpg, s3};
+
+/// Length of the SHA-1 checksum at the end of a pack
+const PACK_CHECKSUM_LEN: usize = 20;
+
+/// A ref in the manifest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestRef {
+    pub name: String,
+    pub sha: String,
+    /// Pack holding the objects that were new when the ref was pushed
+    pub pack: String,
+    /// For annotated tags, the commit the tag object points to.
+    pub peeled: Option<String>,
+}
+
+/// A pack in the content-addressed store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestPack {
+    /// Checksum of the pack, as in git's own pack names
+    pub name: String,
+    /// The objects the pack was created for. Having them locally means having
+    /// every object of the pack that a ref can reach through it.
+    pub tips: Vec<String>,
+    /// Packs holding the history that was left out of this pack
+    pub deps: Vec<String>,
+}
+
+/// The refs of a remote using the pack layout, and the packs they need.
+///
+/// Stored encrypted in `<prefix>/manifest`, one entry per line:
+/// `ref <name> <sha> <pack> [<peeled>]`, `pack <name> <tip>[,<tip> …] [<dep> …]`
+/// and `garbage <pack> <since>`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub refs: BTreeMap<String, ManifestRef>,
+    pub packs: BTreeMap<String, ManifestPack>,
+    /// Packs no ref needs anymore, and since when as a Unix timestamp in seconds.
+    /// `gc` deletes them once the grace period has passed.
+    pub garbage: BTreeMap<String, i64>,
+}
+
+impl Manifest {
+    pub fn parse(contents: &str) -> Result<Manifest> {
+        let mut manifest = Manifest::default();
+        for line in contents.lines() {
+            let mut parts = line.split_ascii_whitespace();
+            match parts.next() {
+                None => {}
+                Some(comment) if comment.starts_with('#') => {}
+                Some("ref") => {
+                    let mut field = || {
+                        parts
+                            .next()
+                            .map(|s| s.to_string())
+                            .ok_or_else(|| anyhow!("invalid manifest line: {}", line))
+                    };
+                    let r = ManifestRef {
+                        name: field()?,
+                        sha: field()?,
+                        pack: field()?,
+                        peeled: field().ok(),
+                    };
+                    manifest.refs.insert(r.name.clone(), r);
+                }
+                Some("pack") => {
+                    let (Some(name), Some(tip)) = (parts.next(), parts.next()) else {
+                        return Err(anyhow!("invalid manifest line: {}", line));
+                    };
+                    let pack = ManifestPack {
+                        name: name.to_string(),
+                        tips: tip.split(',').map(|tip| tip.to_string()).collect(),
+                        deps: parts.map(|dep| dep.to_string()).collect(),
+                    };
+                    manifest.packs.insert(pack.name.clone(), pack);
+                }
+                Some("garbage") => {
+                    let (Some(name), Some(since)) = (parts.next(), parts.next()) else {
+                        return Err(anyhow!("invalid manifest line: {}", line));
+                    };
+                    let since = since
+                        .parse()
+                        .map_err(|_| anyhow!("invalid manifest line: {}", line))?;
+                    manifest.garbage.insert(name.to_string(), since);
+                }
+                Some(_) => return Err(anyhow!("invalid manifest line: {}", line)),
+            }
+        }
+        Ok(manifest)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::from("# git-remote-s3 manifest\n");
+        for r in self.refs.values() {
+            text += &format!("ref {} {} {}", r.name, r.sha, r.pack);
+            if let Some(peeled) = &r.peeled {
+                text += &format!(" {}", peeled);
+            }
+            text += "\n";
+        }
+        for pack in self.packs.values() {
+            text += &format!("pack {} {}", pack.name, pack.tips.join(","));
+            for dep in &pack.deps {
+                text += &format!(" {}", dep);
+            }
+            text += "\n";
+        }
+        for (name, since) in &self.garbage {
+            text += &format!("garbage {} {}\n", name, since);
+        }
+        text
+    }
+
+    /// The packs needed to get the objects reachable from the tips of `pack`: the
+    /// pack itself and, recursively, the packs it depends on. Packs whose tips
+    /// are all `present` are skipped along with their dependencies.
+    pub fn needed_packs(
+        &self,
+        pack: &str,
+        present: &mut impl FnMut(&str) -> Result<bool>,
+    ) -> Result<Vec<&ManifestPack>> {
+        let mut needed = Vec::new();
+        let mut seen = HashSet::new();
+        let mut queue = vec![pack];
+        while let Some(name) = queue.pop() {
+            if !seen.insert(name) {
+                continue;
+            }
+            let pack = self
+                .packs
+                .get(name)
+                .ok_or_else(|| anyhow!("pack missing from manifest: {}", name))?;
+            let mut all_present = true;
+            for tip in &pack.tips {
+                if !present(tip)? {
+                    all_present = false;
+                    break;
+                }
+            }
+            if all_present {
+                continue;
+            }
+            needed.push(pack);
+            queue.extend(pack.deps.iter().map(|dep| dep.as_str()));
+        }
+        Ok(needed)
+    }
+}
+
+pub fn manifest_path(prefix: &str) -> String {
+    format!("{}/manifest", prefix)
+}
+
+pub fn pack_path(prefix: &str, name: &str) -> String {
+    format!("{}/packs/{}.pack", prefix, name)
+}
+
+/// Reads the manifest, `None` if nothing was pushed yet.
+pub async fn read_manifest(s3: &Client, settings: &GitS3Settings) -> Result<Option<Manifest>> {
+    Ok(read_manifest_etag(s3, settings)
+        .await?
+        .map(|(manifest, _)| manifest))
+}
+
+/// Read the manifest along with the ETag of the version read.
+pub async fn read_manifest_etag(
+    s3: &Client,
+    settings: &GitS3Settings,
+) -> Result<Option<(Manifest, String)>> {
+    let tmp_dir = tempfile::tempdir()?;
+    let manifest_file = tmp_dir.path().join("manifest");
+    let enc_file = tmp_dir.path().join("manifest_enc");
+
+    let o = s3::Key {
+        bucket: settings.bucket().to_owned(),
+        key: manifest_path(settings.key()),
+    };
+    let Some(etag) = s3::try_get_etag(s3, &enc_file, &o, settings.sse()?).await? else {
+        return Ok(None);
+    };
+    gpg::decrypt(&enc_file, &manifest_file)?;
+
+    let contents = std::fs::read_to_string(&manifest_file)?;
+    Ok(Some((Manifest::parse(&contents)?, etag)))
+}
+
+pub async fn write_manifest(
+    s3: &Client,
+    settings: &GitS3Settings,
+    manifest: &Manifest,
+) -> Result<()> {
+    let tmp_dir = tempfile::tempdir()?;
+    let manifest_file = tmp_dir.path().join("manifest");
+    let enc_file = tmp_dir.path().join("manifest_enc");
+
+    std::fs::write(&manifest_file, manifest.to_text())?;
+    gpg::encrypt(&settings.gpg_recipients()?, &manifest_file, &enc_file)?;
+
+    let o = s3::Key {
+        bucket: settings.bucket().to_owned(),
+        key: manifest_path(settings.key()),
+    };
+    s3::put(
+        s3,
+        &enc_file,
+        &o,
+        settings.sse()?,
+        &s3::Attributes::default(),
+    )
+    .await
+}
+
+/// The current ETag of the manifest, `None` if nothing was pushed yet. Checked
+/// against the ETag of the manifest a push read, right before writing it back.
+async fn manifest_etag(s3: &Client, settings: &GitS3Settings) -> Result<Option<String>> {
+    let o = s3::Key {
+        bucket: settings.bucket().to_owned(),
+        key: manifest_path(settings.key()),
+    };
+    s3::etag(s3, &o, settings.sse()?).await
+}
+
+/// Write the manifest unless it changed since its ETag was read. Without
+/// conditional writes, this narrows the window in which a concurrent push could
+/// be lost.
+async fn replace_manifest(
+    s3: &Client,
+    settings: &GitS3Settings,
+    manifest: &Manifest,
+    etag: &Option<String>,
+) -> Result<()> {
+    if manifest_etag(s3, settings).await? != *etag {
+        return Err(anyhow!(
+            "the remote was pushed to concurrently, fetch and push again"
+        ));
+    }
+    write_manifest(s3, settings, manifest).await
+}
+
+/// The refs of the manifest. Each ref has a single head.
+pub async fn list_refs(
+    s3: &Client,
+    settings: &GitS3Settings,
+) -> Result<HashMap<String, RemoteRefs>> {
+    let manifest = read_manifest(s3, settings).await?.unwrap_or_default();
+
+    Ok(manifest
+        .refs
+        .into_values()
+        .map(|r| {
+            let mut refs = RemoteRefs::new();
+            refs.add_ref(RemoteRef {
+                updated: 0,
+                generation: 0,
+                reference: GitRef {
+                    name: r.name.clone(),
+                    sha: r.sha,
+                },
+                storage_class: None,
+                peeled: r.peeled,
+                basis: Vec::new(),
+                shallow_depth: None,
+                blob_packs: false,
+            });
+            (r.name, refs)
+        })
+        .collect())
+}
+
+/// Push a ref as a pack of the objects the remote doesn't have yet, and record it
+/// in the manifest.
+pub async fn push(s3: &Client, settings: &GitS3Settings, r: &GitRef) -> Result<RemoteRef> {
+    let current_dir = current_dir()?;
+    let (existing, etag) = read_manifest_etag(s3, settings).await?.unzip();
+    let first_push = existing.is_none();
+    let mut manifest = existing.unwrap_or_default();
+
+    let pack = match manifest.refs.values().find(|m| m.sha == r.sha) {
+        // Another ref already points there, its pack has everything
+        Some(m) => m.pack.clone(),
+        None => push_pack(s3, settings, &mut manifest, r, &current_dir).await?,
+    };
+
+    let peeled = git::rev_parse(&format!("{}^{{}}", r.sha), &current_dir)?;
+    let peeled = Some(peeled).filter(|peeled| *peeled != r.sha);
+    manifest.refs.insert(
+        r.name.clone(),
+        ManifestRef {
+            name: r.name.clone(),
+            sha: r.sha.clone(),
+            pack,
+            peeled: peeled.clone(),
+        },
+    );
+    // Written first, so that a manifest is never read as part of a bundle remote.
+    // A remote with a layout and without a manifest is empty.
+    if first_push {
+        write_layout(s3, settings, Layout::Packs).await?;
+    }
+    replace_manifest(s3, settings, &manifest, &etag).await?;
+
+    Ok(RemoteRef {
+        updated: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as i128)
+            .unwrap_or_default(),
+        generation: 0,
+        reference: r.clone(),
+        storage_class: None,
+        peeled,
+        basis: Vec::new(),
+        shallow_depth: None,
+        blob_packs: false,
+    })
+}
+
+/// Upload a pack of the objects reachable from the ref but from none of the refs
+/// in the manifest, returning its name.
+async fn push_pack(
+    s3: &Client,
+    settings: &GitS3Settings,
+    manifest: &mut Manifest,
+    r: &GitRef,
+    current_dir: &Path,
+) -> Result<String> {
+    let tmp_dir = tempfile::tempdir()?;
+    let pack_file = tmp_dir.path().join("pack");
+
+    let mut revs = vec![r.sha.clone()];
+    let mut deps = Vec::new();
+    for m in manifest.refs.values() {
+        if git::object_exists(&m.sha, current_dir)? {
+            revs.push(format!("^{}", m.sha));
+            if !deps.contains(&m.pack) {
+                deps.push(m.pack.clone());
+            }
+        }
+    }
+    git::pack_objects_revs(&pack_file, &revs, current_dir)?;
+    let name = pack_checksum(&pack_file)?;
+    // A pack marked as garbage may be deleted by gc, upload it again
+    if manifest.packs.contains_key(&name) && manifest.garbage.remove(&name).is_none() {
+        debug!(?name, "Pack already stored");
+        return Ok(name);
+    }
+
+    info!(?r, ?name, ?deps, "Uploading pack");
+    let attrs = settings.attributes(RefClass::of(&r.name), bundle_metadata(r, current_dir))?;
+    upload_pack(s3, settings, &pack_file, &name, &attrs).await?;
+
+    manifest.packs.insert(
+        name.clone(),
+        ManifestPack {
+            name: name.clone(),
+            tips: vec![r.sha.clone()],
+            deps,
+        },
+    );
+    Ok(name)
+}
+
+/// Encrypt and upload a pack to the content-addressed store.
+pub async fn upload_pack(
+    s3: &Client,
+    settings: &GitS3Settings,
+    pack_file: &Path,
+    name: &str,
+    attrs: &s3::Attributes,
+) -> Result<()> {
+    let tmp_dir = tempfile::tempdir()?;
+    let enc_file = tmp_dir.path().join("pack_enc");
+    encrypt_for_upload(settings, pack_file, &enc_file)?;
+
+    let o = s3::Key {
+        bucket: settings.bucket().to_owned(),
+        key: pack_path(settings.key(), name),
+    };
+    s3::put(s3, &enc_file, &o, settings.sse()?, attrs).await
+}
+
+/// Name of a pack, as git names it: the hex checksum at the end of the pack.
+pub fn pack_checksum(pack: &Path) -> Result<String> {
+    let mut f = File::open(pack).with_context(|| format!("Failed to open {}", pack.display()))?;
+    let mut checksum = [0u8; PACK_CHECKSUM_LEN];
+    f.seek(SeekFrom::End(-(PACK_CHECKSUM_LEN as i64)))?;
+    f.read_exact(&mut checksum)?;
+    Ok(checksum.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Remove refs from the manifest. Their packs stay until garbage collected, as
+/// other refs may need them.
+pub async fn delete<'a>(
+    s3: &Client,
+    settings: &GitS3Settings,
+    remote_refs: impl IntoIterator<Item = &'a RemoteRef>,
+) -> Result<()> {
+    let Some((mut manifest, etag)) = read_manifest_etag(s3, settings).await? else {
+        return Ok(());
+    };
+
+    for remote_ref in remote_refs {
+        let r = &remote_ref.reference;
+        if manifest.refs.get(&r.name).is_some_and(|m| m.sha == r.sha) {
+            info!(?r, "Removing from manifest");
+            manifest.refs.remove(&r.name);
+        }
+    }
+    replace_manifest(s3, settings, &manifest, &Some(etag)).await
+}
+
+/// Fetch a batch of refs by downloading the packs they need that aren't present
+/// locally. The packs aren't thin, so they are indexed in any order.
+pub async fn fetch(s3: &Client, settings: &GitS3Settings, refs: &[FetchRef]) -> Result<()> {
+    let manifest = read_manifest(s3, settings)
+        .await?
+        .ok_or_else(|| anyhow!("remote has no manifest"))?;
+    let current_dir = current_dir()?;
+
+    let mut present = |tip: &str| git::object_exists(tip, &current_dir);
+    let mut needed: Vec<&ManifestPack> = Vec::new();
+    for r in refs {
+        let m = manifest
+            .refs
+            .values()
+            .find(|m| m.sha == r.reference.sha)
+            .ok_or_else(|| anyhow!("{} is not in the manifest", r.reference.sha))?;
+        for pack in manifest.needed_packs(&m.pack, &mut present)? {
+            if !needed.iter().any(|p| p.name == pack.name) {
+                needed.push(pack);
+            }
+        }
+    }
+    let jobs = settings.fetch_jobs()?;
+    info!(count = needed.len(), jobs, "Fetching packs from S3");
+
+    let tmp_dir = tempfile::tempdir()?;
+    let cache = BundleCache::open_or_skip(&current_dir);
+    let cache = cache.as_ref();
+    let mut downloads = stream::iter(needed.iter().enumerate())
+        .map(|(i, pack)| {
+            let pack_file = tmp_dir.path().join(format!("pack_{}", i));
+            let enc_file = tmp_dir.path().join(format!("pack_enc_{}", i));
+            let o = s3::Key {
+                bucket: settings.bucket().to_owned(),
+                key: pack_path(settings.key(), &pack.name),
+            };
+            async move {
+                download(s3, settings, cache, &o, &enc_file, &pack_file).await?;
+                Ok::<_, anyhow::Error>((pack, pack_file))
+            }
+        })
+        .buffered(jobs);
+
+    while let Some(download) = downloads.next().await {
+        let (pack, pack_file) = download?;
+        // Packs are named by their checksum
+        let checksum = pack_checksum(&pack_file)?;
+        if checksum != pack.name {
+            return Err(anyhow!(
+                "pack {} is corrupt: its checksum is {}",
+                pack_path(settings.key(), &pack.name),
+                checksum
+            ));
+        }
+        info!(?pack.name, "Indexing pack");
+        git::index_pack(&pack_file, false, &current_dir)?;
+    }
+    Ok(())
+}
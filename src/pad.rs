@@ -0,0 +1,119 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+use tracing::{debug, instrument};
+
+/// Marker written at the very end of a padded file.
+const MAGIC: &[u8; 8] = b"GITS3PAD";
+
+/// Trailer layout: original length (u64, little endian) followed by `MAGIC`.
+const TRAILER_LEN: u64 = 16;
+
+/// Padding scheme applied to objects before they are encrypted, so that object
+/// sizes on S3 don't reveal how large each change was. The trailer recording
+/// the original length is encrypted along with the content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Padding {
+    /// Pad to the next power of two.
+    PowerOfTwo,
+    /// Pad to the next multiple of the given number of bytes.
+    Quantum(u64),
+}
+
+impl FromStr for Padding {
+    type Err = anyhow::Error;
+
+    /// Parses `pow2`, or a quantum size such as `4096`, `64k`, `1m` or `1g`.
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim().to_ascii_lowercase();
+        if s == "pow2" {
+            return Ok(Padding::PowerOfTwo);
+        }
+
+        let quantum = parse_size(&s)
+            .filter(|n| *n > 0)
+            .ok_or_else(|| anyhow!("invalid padding: {}", s))?;
+
+        Ok(Padding::Quantum(quantum))
+    }
+}
+
+/// Parse a size in bytes with an optional `k`, `m` or `g` suffix, e.g. `64k`.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim().to_ascii_lowercase();
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'g')) => (&s[..i], 1 << 30),
+        _ => (s.as_str(), 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+impl Padding {
+    /// Size of a file of `len` bytes once padded, including the trailer.
+    pub fn padded_len(&self, len: u64) -> u64 {
+        let min_len = len + TRAILER_LEN;
+        match self {
+            Padding::PowerOfTwo => min_len.next_power_of_two(),
+            Padding::Quantum(q) => min_len.div_ceil(*q) * q,
+        }
+    }
+}
+
+/// Pad a file in place according to the padding scheme. The file must then be
+/// encrypted without compression, which would shrink the padding away again.
+#[instrument]
+pub fn pad(f: &Path, padding: &Padding) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(f)
+        .with_context(|| format!("Failed to open file for padding: {}", f.display()))?;
+
+    let len = file.metadata()?.len();
+    let padded_len = padding.padded_len(len);
+    debug!(len, padded_len, "Padding file");
+
+    // Extending the file fills the gap with zeros
+    file.set_len(padded_len - TRAILER_LEN)?;
+    file.seek(SeekFrom::End(0))?;
+    file.write_all(&len.to_le_bytes())?;
+    file.write_all(MAGIC)?;
+
+    Ok(())
+}
+
+/// Strip the padding from a decrypted file in place. Files without a padding
+/// trailer are left untouched, so objects uploaded without padding can still be
+/// read.
+#[instrument]
+pub fn unpad(f: &Path) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(f)
+        .with_context(|| format!("Failed to open file for unpadding: {}", f.display()))?;
+
+    let len = file.metadata()?.len();
+    if len < TRAILER_LEN {
+        return Ok(());
+    }
+
+    let mut trailer = [0u8; TRAILER_LEN as usize];
+    file.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+    file.read_exact(&mut trailer)?;
+
+    let (orig_len, magic) = trailer.split_at(8);
+    let orig_len = u64::from_le_bytes(orig_len.try_into()?);
+    if magic != MAGIC || orig_len > len - TRAILER_LEN {
+        debug!("No padding trailer found");
+        return Ok(());
+    }
+
+    debug!(len, orig_len, "Removing padding");
+    file.set_len(orig_len)?;
+
+    Ok(())
+}
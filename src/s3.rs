@@ -1,11 +1,29 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::path::Path;
 use std::time::Duration;
-use tracing::instrument;
+use tracing::{debug, instrument};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use aws_config::{meta::region::RegionProviderChain, retry::RetryConfig, timeout::TimeoutConfig};
-use aws_sdk_s3::{config::Builder as S3ConfigBuilder, primitives::ByteStream, Client};
+use aws_sdk_s3::{
+    config::Builder as S3ConfigBuilder,
+    error::SdkError,
+    primitives::ByteStream,
+    types::{
+        ChecksumAlgorithm, ChecksumMode, MetadataDirective, ServerSideEncryption, StorageClass,
+        TaggingDirective,
+    },
+    Client,
+};
 use aws_types::region::Region;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use md5::{Digest, Md5};
+use sha2::Sha256;
+
+/// User metadata holding the SHA-256 of an object as uploaded, checked again on
+/// download, in case the S3 checksum was lost or never stored, e.g. by a proxy
+const SHA256_METADATA: &str = "sha256";
 
 #[derive(Debug)]
 pub struct Key {
@@ -13,41 +31,303 @@ pub struct Key {
     pub key: String,
 }
 
-/// Get an object from S3 and write it to a local file
+/// Attributes set on uploaded objects
+#[derive(Debug, Clone, Default)]
+pub struct Attributes {
+    /// Storage class, e.g. `STANDARD_IA`, `INTELLIGENT_TIERING` or `GLACIER_IR`
+    pub storage_class: Option<String>,
+    /// Object tags, URL query encoded, e.g. `team=infra&env=prod`
+    pub tags: Option<String>,
+    /// User metadata, stored as `x-amz-meta-<name>` headers
+    pub metadata: HashMap<String, String>,
+}
+
+/// Server-side encryption applied to objects on put, copy and get
+#[derive(Clone, Default)]
+pub enum Sse {
+    #[default]
+    None,
+    /// SSE-S3, keys managed by S3
+    S3,
+    /// SSE-KMS, with an optional key id and encryption context (a JSON object)
+    Kms {
+        key_id: Option<String>,
+        context: Option<String>,
+    },
+    /// SSE-C, with a 256-bit customer provided key
+    Customer { key: Vec<u8> },
+}
+
+// Hand written so the customer key never ends up in the logs
+impl fmt::Debug for Sse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sse::None => write!(f, "None"),
+            Sse::S3 => write!(f, "S3"),
+            Sse::Kms { key_id, context } => f
+                .debug_struct("Kms")
+                .field("key_id", key_id)
+                .field("context", context)
+                .finish(),
+            Sse::Customer { .. } => write!(f, "Customer"),
+        }
+    }
+}
+
+impl Sse {
+    /// Load an SSE-C key from a file, either as 32 raw bytes or base64 encoded.
+    pub fn customer_from_file(f: &Path) -> Result<Sse> {
+        let contents = std::fs::read(f)
+            .with_context(|| format!("Failed to read SSE-C key file: {}", f.display()))?;
+
+        let key = if contents.len() == 32 {
+            contents
+        } else {
+            BASE64
+                .decode(contents.trim_ascii())
+                .with_context(|| format!("SSE-C key is not base64: {}", f.display()))?
+        };
+        if key.len() != 32 {
+            return Err(anyhow!("SSE-C key must be 256 bits: {}", f.display()));
+        }
+
+        Ok(Sse::Customer { key })
+    }
+
+    fn algorithm(&self) -> Option<ServerSideEncryption> {
+        match self {
+            Sse::S3 => Some(ServerSideEncryption::Aes256),
+            Sse::Kms { .. } => Some(ServerSideEncryption::AwsKms),
+            _ => None,
+        }
+    }
+
+    fn kms_key_id(&self) -> Option<String> {
+        match self {
+            Sse::Kms { key_id, .. } => key_id.clone(),
+            _ => None,
+        }
+    }
+
+    fn kms_context(&self) -> Option<String> {
+        match self {
+            Sse::Kms { context, .. } => context.as_ref().map(|c| BASE64.encode(c)),
+            _ => None,
+        }
+    }
+
+    fn customer_algorithm(&self) -> Option<String> {
+        match self {
+            Sse::Customer { .. } => Some("AES256".to_string()),
+            _ => None,
+        }
+    }
+
+    fn customer_key(&self) -> Option<String> {
+        match self {
+            Sse::Customer { key } => Some(BASE64.encode(key)),
+            _ => None,
+        }
+    }
+
+    fn customer_key_md5(&self) -> Option<String> {
+        match self {
+            Sse::Customer { key } => Some(BASE64.encode(Md5::digest(key))),
+            _ => None,
+        }
+    }
+}
+
+/// Get an object from S3 and write it to a local file. Objects stored with a
+/// checksum are validated against it while they are read.
 #[instrument(skip(s3))]
-pub async fn get(s3: &Client, f: &Path, o: &Key) -> Result<()> {
+pub async fn get(s3: &Client, f: &Path, o: &Key, sse: &Sse) -> Result<()> {
     let req = s3
         .get_object()
         .bucket(&o.bucket)
         .key(&o.key)
+        .checksum_mode(ChecksumMode::Enabled)
+        .set_sse_customer_algorithm(sse.customer_algorithm())
+        .set_sse_customer_key(sse.customer_key())
+        .set_sse_customer_key_md5(sse.customer_key_md5())
         .send()
         .await
         .with_context(|| format!("Failed to get object s3://{}/{}", o.bucket, o.key))?;
 
+    let metadata = req.metadata;
     let body = req.body;
     let bytes = body
         .collect()
         .await
-        .context("Failed to collect object bytes from stream")?;
+        .with_context(|| format!("Failed to read object s3://{}/{}", o.bucket, o.key))?
+        .into_bytes();
+    check_sha256(o, metadata.as_ref(), &bytes)?;
 
-    std::fs::write(f, bytes.into_bytes())
+    std::fs::write(f, bytes)
         .with_context(|| format!("Failed to write object to file: {}", f.display()))?;
 
     Ok(())
 }
 
-/// Put a local file to S3
+/// Get the ETag of an object without downloading it, `None` if it doesn't exist
+#[instrument(skip(s3))]
+pub async fn etag(s3: &Client, o: &Key, sse: &Sse) -> Result<Option<String>> {
+    let result = s3
+        .head_object()
+        .bucket(&o.bucket)
+        .key(&o.key)
+        .set_sse_customer_algorithm(sse.customer_algorithm())
+        .set_sse_customer_key(sse.customer_key())
+        .set_sse_customer_key_md5(sse.customer_key_md5())
+        .send()
+        .await;
+
+    let resp = match result {
+        Err(SdkError::ServiceError(e)) if e.err().is_not_found() => return Ok(None),
+        r => r.with_context(|| format!("Failed to head object s3://{}/{}", o.bucket, o.key))?,
+    };
+    Ok(resp.e_tag)
+}
+
+/// Get the user metadata of an object, or of one of its versions, without
+/// downloading it
 #[instrument(skip(s3))]
-pub async fn put(s3: &Client, f: &Path, o: &Key) -> Result<()> {
+pub async fn metadata(
+    s3: &Client,
+    o: &Key,
+    version_id: Option<&str>,
+    sse: &Sse,
+) -> Result<HashMap<String, String>> {
+    let resp = s3
+        .head_object()
+        .bucket(&o.bucket)
+        .key(&o.key)
+        .set_version_id(version_id.map(String::from))
+        .set_sse_customer_algorithm(sse.customer_algorithm())
+        .set_sse_customer_key(sse.customer_key())
+        .set_sse_customer_key_md5(sse.customer_key_md5())
+        .send()
+        .await
+        .with_context(|| format!("Failed to head object s3://{}/{}", o.bucket, o.key))?;
+
+    Ok(resp.metadata.unwrap_or_default())
+}
+
+/// Get an object from S3 if it exists, returning whether it was found
+#[instrument(skip(s3))]
+pub async fn try_get(s3: &Client, f: &Path, o: &Key, sse: &Sse) -> Result<bool> {
+    Ok(try_get_etag(s3, f, o, sse).await?.is_some())
+}
+
+/// Get an object from S3 if it exists, returning the ETag of what was read. Unlike
+/// a separate `etag` call, it can't belong to a later version of the object.
+#[instrument(skip(s3))]
+pub async fn try_get_etag(s3: &Client, f: &Path, o: &Key, sse: &Sse) -> Result<Option<String>> {
+    let result = s3
+        .get_object()
+        .bucket(&o.bucket)
+        .key(&o.key)
+        .checksum_mode(ChecksumMode::Enabled)
+        .set_sse_customer_algorithm(sse.customer_algorithm())
+        .set_sse_customer_key(sse.customer_key())
+        .set_sse_customer_key_md5(sse.customer_key_md5())
+        .send()
+        .await;
+
+    let req = match result {
+        Err(SdkError::ServiceError(e)) if e.err().is_no_such_key() => return Ok(None),
+        r => r.with_context(|| format!("Failed to get object s3://{}/{}", o.bucket, o.key))?,
+    };
+
+    let bytes = req
+        .body
+        .collect()
+        .await
+        .with_context(|| format!("Failed to read object s3://{}/{}", o.bucket, o.key))?
+        .into_bytes();
+    check_sha256(o, req.metadata.as_ref(), &bytes)?;
+
+    std::fs::write(f, bytes)
+        .with_context(|| format!("Failed to write object to file: {}", f.display()))?;
+
+    Ok(Some(req.e_tag.unwrap_or_default()))
+}
+
+/// Hex encoded SHA-256 of some bytes
+fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Check downloaded bytes against the SHA-256 recorded in the object metadata by
+/// `put`. Objects uploaded before checksums were recorded have none.
+fn check_sha256(o: &Key, metadata: Option<&HashMap<String, String>>, bytes: &[u8]) -> Result<()> {
+    let Some(expected) = metadata.and_then(|m| m.get(SHA256_METADATA)) else {
+        return Ok(());
+    };
+
+    let actual = sha256_hex(bytes);
+    if actual != *expected {
+        return Err(anyhow!(
+            "checksum mismatch for s3://{}/{}: expected sha256 {}, got {}",
+            o.bucket,
+            o.key,
+            expected,
+            actual
+        ));
+    }
+    Ok(())
+}
+
+/// Put a local file to S3. The SHA-256 of the file is sent along so that S3
+/// rejects a corrupted upload, and recorded in the metadata to be checked again
+/// on download.
+#[instrument(skip(s3))]
+pub async fn put(s3: &Client, f: &Path, o: &Key, sse: &Sse, attrs: &Attributes) -> Result<()> {
     let contents =
         std::fs::read(f).with_context(|| format!("Failed to read file: {}", f.display()))?;
 
+    let digest = Sha256::digest(&contents);
+    let mut metadata = attrs.metadata.clone();
+    metadata.insert(SHA256_METADATA.to_string(), sha256_hex(&contents));
+
     let body = ByteStream::from(contents);
 
     s3.put_object()
         .bucket(&o.bucket)
         .key(&o.key)
         .body(body)
+        .checksum_sha256(BASE64.encode(digest))
+        .set_storage_class(attrs.storage_class.as_deref().map(StorageClass::from))
+        .set_tagging(attrs.tags.clone())
+        .set_metadata(Some(metadata))
+        .set_server_side_encryption(sse.algorithm())
+        .set_ssekms_key_id(sse.kms_key_id())
+        .set_ssekms_encryption_context(sse.kms_context())
+        .set_sse_customer_algorithm(sse.customer_algorithm())
+        .set_sse_customer_key(sse.customer_key())
+        .set_sse_customer_key_md5(sse.customer_key_md5())
+        .send()
+        .await
+        .with_context(|| format!("Failed to put object to s3://{}/{}", o.bucket, o.key))?;
+
+    Ok(())
+}
+
+/// Put an empty object to S3
+#[instrument(skip(s3))]
+pub async fn touch(s3: &Client, o: &Key, sse: &Sse) -> Result<()> {
+    s3.put_object()
+        .bucket(&o.bucket)
+        .key(&o.key)
+        .set_server_side_encryption(sse.algorithm())
+        .set_ssekms_key_id(sse.kms_key_id())
+        .set_ssekms_encryption_context(sse.kms_context())
+        .set_sse_customer_algorithm(sse.customer_algorithm())
+        .set_sse_customer_key(sse.customer_key())
+        .set_sse_customer_key_md5(sse.customer_key_md5())
         .send()
         .await
         .with_context(|| format!("Failed to put object to s3://{}/{}", o.bucket, o.key))?;
@@ -70,12 +350,22 @@ pub async fn del(s3: &Client, o: &Key) -> Result<()> {
 
 /// Rename an object in S3
 #[instrument(skip(s3))]
-pub async fn rename(s3: &Client, from: &Key, to: &Key) -> Result<()> {
-    // Copy the object
+pub async fn rename(s3: &Client, from: &Key, to: &Key, sse: &Sse) -> Result<()> {
+    // Copy the object, keeping the same encryption for the copy
     s3.copy_object()
         .copy_source(format!("{}/{}", from.bucket, from.key))
         .bucket(to.bucket.as_str())
         .key(to.key.as_str())
+        .checksum_algorithm(ChecksumAlgorithm::Sha256)
+        .set_server_side_encryption(sse.algorithm())
+        .set_ssekms_key_id(sse.kms_key_id())
+        .set_ssekms_encryption_context(sse.kms_context())
+        .set_sse_customer_algorithm(sse.customer_algorithm())
+        .set_sse_customer_key(sse.customer_key())
+        .set_sse_customer_key_md5(sse.customer_key_md5())
+        .set_copy_source_sse_customer_algorithm(sse.customer_algorithm())
+        .set_copy_source_sse_customer_key(sse.customer_key())
+        .set_copy_source_sse_customer_key_md5(sse.customer_key_md5())
         .send()
         .await
         .with_context(|| {
@@ -91,6 +381,196 @@ pub async fn rename(s3: &Client, from: &Key, to: &Key) -> Result<()> {
     Ok(())
 }
 
+/// Copy an object onto itself with new attributes. Its metadata is replaced by
+/// `attrs.metadata`, its tags only if `attrs` has some. Without a storage class,
+/// the copy is stored as `STANDARD`.
+#[instrument(skip(s3))]
+pub async fn copy_in_place(s3: &Client, o: &Key, sse: &Sse, attrs: &Attributes) -> Result<()> {
+    s3.copy_object()
+        .copy_source(format!("{}/{}", o.bucket, o.key))
+        .bucket(&o.bucket)
+        .key(&o.key)
+        .set_storage_class(attrs.storage_class.as_deref().map(StorageClass::from))
+        .metadata_directive(MetadataDirective::Replace)
+        .set_metadata(Some(attrs.metadata.clone()))
+        .set_tagging_directive(attrs.tags.as_ref().map(|_| TaggingDirective::Replace))
+        .set_tagging(attrs.tags.clone())
+        .checksum_algorithm(ChecksumAlgorithm::Sha256)
+        .set_server_side_encryption(sse.algorithm())
+        .set_ssekms_key_id(sse.kms_key_id())
+        .set_ssekms_encryption_context(sse.kms_context())
+        .set_sse_customer_algorithm(sse.customer_algorithm())
+        .set_sse_customer_key(sse.customer_key())
+        .set_sse_customer_key_md5(sse.customer_key_md5())
+        .set_copy_source_sse_customer_algorithm(sse.customer_algorithm())
+        .set_copy_source_sse_customer_key(sse.customer_key())
+        .set_copy_source_sse_customer_key_md5(sse.customer_key_md5())
+        .send()
+        .await
+        .with_context(|| format!("Failed to copy s3://{}/{} in place", o.bucket, o.key))?;
+
+    Ok(())
+}
+
+/// An object under a prefix, as listed
+#[derive(Debug)]
+pub struct ObjectInfo {
+    pub key: String,
+    /// Last modified timestamp, in nanoseconds since epoch
+    pub last_modified: i128,
+    pub size: i64,
+    pub storage_class: Option<String>,
+}
+
+/// List all objects under a prefix
+#[instrument(skip(s3))]
+pub async fn list(s3: &Client, bucket: &str, prefix: &str) -> Result<Vec<ObjectInfo>> {
+    debug!(bucket, prefix, "Listing objects");
+    let mut objects = Vec::new();
+    let mut continuation_token = None;
+
+    loop {
+        let result = s3
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(prefix)
+            .set_continuation_token(continuation_token)
+            .send()
+            .await
+            .with_context(|| format!("Failed to list objects s3://{}/{}", bucket, prefix))?;
+
+        objects.extend(result.contents().unwrap_or_default().iter().map(|obj| {
+            ObjectInfo {
+                key: obj.key().unwrap_or_default().to_string(),
+                last_modified: obj
+                    .last_modified()
+                    .map(|dt| dt.as_nanos())
+                    .unwrap_or_default(),
+                size: obj.size(),
+                storage_class: obj.storage_class().map(|c| c.as_str().to_string()),
+            }
+        }));
+
+        if !result.is_truncated() {
+            break;
+        }
+        continuation_token = result.next_continuation_token().map(String::from);
+    }
+
+    Ok(objects)
+}
+
+/// A version of an object in a versioned bucket
+#[derive(Debug)]
+pub struct ObjectVersion {
+    pub key: String,
+    pub version_id: String,
+    /// Last modified timestamp, in nanoseconds since epoch
+    pub last_modified: i128,
+    pub size: i64,
+    pub is_latest: bool,
+    pub is_delete_marker: bool,
+}
+
+/// List all versions and delete markers of the objects under a prefix
+#[instrument(skip(s3))]
+pub async fn list_versions(s3: &Client, bucket: &str, prefix: &str) -> Result<Vec<ObjectVersion>> {
+    let mut versions = Vec::new();
+    let mut key_marker = None;
+    let mut version_id_marker = None;
+
+    loop {
+        let result = s3
+            .list_object_versions()
+            .bucket(bucket)
+            .prefix(prefix)
+            .set_key_marker(key_marker)
+            .set_version_id_marker(version_id_marker)
+            .send()
+            .await
+            .with_context(|| {
+                format!("Failed to list object versions s3://{}/{}", bucket, prefix)
+            })?;
+
+        versions.extend(result.versions().unwrap_or_default().iter().map(|v| {
+            ObjectVersion {
+                key: v.key().unwrap_or_default().to_string(),
+                version_id: v.version_id().unwrap_or_default().to_string(),
+                last_modified: v
+                    .last_modified()
+                    .map(|dt| dt.as_nanos())
+                    .unwrap_or_default(),
+                size: v.size(),
+                is_latest: v.is_latest(),
+                is_delete_marker: false,
+            }
+        }));
+        versions.extend(result.delete_markers().unwrap_or_default().iter().map(|m| {
+            ObjectVersion {
+                key: m.key().unwrap_or_default().to_string(),
+                version_id: m.version_id().unwrap_or_default().to_string(),
+                last_modified: m
+                    .last_modified()
+                    .map(|dt| dt.as_nanos())
+                    .unwrap_or_default(),
+                size: 0,
+                is_latest: m.is_latest(),
+                is_delete_marker: true,
+            }
+        }));
+
+        if !result.is_truncated() {
+            break;
+        }
+        key_marker = result.next_key_marker().map(String::from);
+        version_id_marker = result.next_version_id_marker().map(String::from);
+    }
+
+    Ok(versions)
+}
+
+/// Make an older version of an object the current version, by copying it onto itself.
+/// The version's metadata is kept, unless replaced by `metadata`.
+#[instrument(skip(s3))]
+pub async fn restore_version(
+    s3: &Client,
+    o: &Key,
+    version_id: &str,
+    sse: &Sse,
+    metadata: Option<HashMap<String, String>>,
+    storage_class: Option<&str>,
+) -> Result<()> {
+    let directive = metadata.as_ref().map(|_| MetadataDirective::Replace);
+    s3.copy_object()
+        .copy_source(format!("{}/{}?versionId={}", o.bucket, o.key, version_id))
+        .bucket(&o.bucket)
+        .key(&o.key)
+        .set_metadata_directive(directive)
+        .set_metadata(metadata)
+        // A copy is stored as STANDARD unless told otherwise
+        .set_storage_class(storage_class.map(StorageClass::from))
+        .checksum_algorithm(ChecksumAlgorithm::Sha256)
+        .set_server_side_encryption(sse.algorithm())
+        .set_ssekms_key_id(sse.kms_key_id())
+        .set_ssekms_encryption_context(sse.kms_context())
+        .set_sse_customer_algorithm(sse.customer_algorithm())
+        .set_sse_customer_key(sse.customer_key())
+        .set_sse_customer_key_md5(sse.customer_key_md5())
+        .set_copy_source_sse_customer_algorithm(sse.customer_algorithm())
+        .set_copy_source_sse_customer_key(sse.customer_key())
+        .set_copy_source_sse_customer_key_md5(sse.customer_key_md5())
+        .send()
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to restore version {} of s3://{}/{}",
+                version_id, o.bucket, o.key
+            )
+        })?;
+
+    Ok(())
+}
+
 /// Create an S3 client with custom configuration
 pub async fn create_client(region: Option<String>, endpoint: Option<String>) -> Result<Client> {
     let region_provider = RegionProviderChain::first_try(region.map(Region::new))
@@ -0,0 +1,505 @@
+use anyhow::Result;
+use aws_sdk_s3::Client;
+use futures::{stream, StreamExt};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{hash_map::RandomState, HashSet},
+    env::current_dir,
+    fs::File,
+    hash::BuildHasher,
+    io,
+    path::{Path, PathBuf},
+};
+use tracing::{debug, info};
+
+use crate::git::{self, BundleCheck};
+use crate::git_s3::{layout, list_refs, GitRef, GitS3Settings, Layout, RefClass};
+use crate::{fastexport, gpg, packs, pad, s3};
+
+/// What is wrong with an object of the remote, if anything
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    /// Listed by the manifest, but not on S3
+    Missing,
+    /// Couldn't be downloaded
+    Unreadable,
+    /// Couldn't be decrypted
+    Undecryptable,
+    /// Not a valid bundle, pack or stream once decrypted
+    Corrupt,
+    /// A valid bundle, pack or stream, but not of the commit its key names
+    TipMismatch,
+    /// A thin bundle whose prerequisites no other object provides
+    MissingPrerequisites,
+    /// A ref whose history isn't entirely on the remote
+    Incomplete,
+    /// Not needed by any ref
+    Orphaned,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Missing => "missing",
+            Status::Unreadable => "unreadable",
+            Status::Undecryptable => "undecryptable",
+            Status::Corrupt => "corrupt",
+            Status::TipMismatch => "tip-mismatch",
+            Status::MissingPrerequisites => "missing-prerequisites",
+            Status::Incomplete => "incomplete",
+            Status::Orphaned => "orphaned",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Finding {
+    pub status: Status,
+    pub key: String,
+    pub detail: String,
+}
+
+impl Finding {
+    fn new(status: Status, key: &str, detail: impl Into<String>) -> Finding {
+        Finding {
+            status,
+            key: key.to_string(),
+            detail: detail.into(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyOptions {
+    /// Verify this many objects picked at random, instead of all of them
+    pub sample: Option<usize>,
+}
+
+#[derive(Debug)]
+enum Check {
+    /// A bundle of the given ref
+    Bundle(GitRef),
+    /// A pack holding the given tips, named by its checksum with the pack layout
+    Pack {
+        name: Option<String>,
+        tips: Vec<String>,
+    },
+    /// A fast-export stream named by its SHA-256, leaving the refs it updated at
+    /// the given tips
+    Stream { name: String, tips: Vec<String> },
+}
+
+#[derive(Debug)]
+struct Object {
+    key: String,
+    check: Check,
+    /// Objects are checked in increasing order, so that the prerequisites of thin
+    /// bundles are in place before them
+    order: u8,
+}
+
+/// Download, decrypt and check the objects of the remote. Bundles are verified
+/// and unbundled into a scratch repository, in the order a clone would fetch them,
+/// so that their prerequisites must be satisfied by the remote itself. Packs are
+/// indexed, and streams replayed in the order they were pushed. Once everything is
+/// in, each ref's history is checked for completeness.
+///
+/// With a sample, the completeness check is skipped and prerequisites may also be
+/// found in the local repository, if run from one.
+pub async fn verify(
+    s3: &Client,
+    settings: &GitS3Settings,
+    options: &VerifyOptions,
+) -> Result<Vec<Finding>> {
+    let tmp_dir = tempfile::tempdir()?;
+    let scratch = tmp_dir.path().join("verify_repo");
+    git::init_bare(&scratch)?;
+    if options.sample.is_some() {
+        if let Err(e) = git::add_alternate(&current_dir()?, &scratch) {
+            debug!(
+                ?e,
+                "Not in a repository, prerequisites must be in the sample"
+            );
+        }
+    }
+
+    let mut findings = Vec::new();
+    let (mut objects, tips) = match layout(s3, settings).await? {
+        Layout::Bundles => list_bundles(s3, settings, &mut findings).await?,
+        Layout::Packs => list_packs(s3, settings, &mut findings).await?,
+        Layout::FastExport => list_streams(s3, settings, &mut findings).await?,
+    };
+    if let Some(n) = options.sample {
+        let state = RandomState::new();
+        objects.sort_by_cached_key(|o| state.hash_one(&o.key));
+        objects.truncate(n);
+    }
+    objects.sort_by_key(|o| o.order);
+    info!(count = objects.len(), "Verifying objects");
+
+    let jobs = settings.fetch_jobs()?;
+    let mut downloads = stream::iter(objects.iter().enumerate())
+        .map(|(i, o)| {
+            let file = tmp_dir.path().join(format!("verify_{}", i));
+            let enc_file = tmp_dir.path().join(format!("verify_enc_{}", i));
+            async move { (o, download(s3, settings, &o.key, &enc_file, &file).await) }
+        })
+        .buffered(jobs);
+
+    while let Some((o, downloaded)) = downloads.next().await {
+        let finding = match downloaded {
+            Ok(file) => check(o, &file, &scratch)?,
+            Err(finding) => finding,
+        };
+        debug!(?finding, "Verified object");
+        findings.push(finding);
+    }
+
+    if options.sample.is_none() {
+        // Refs whose own bundle is broken were reported already
+        let broken: HashSet<String> = findings
+            .iter()
+            .filter(|f| f.status != Status::Ok)
+            .map(|f| f.key.clone())
+            .collect();
+        for (key, r) in tips {
+            if broken.contains(&key) {
+                continue;
+            }
+            if let Some(finding) = check_complete(&key, &r, &scratch)? {
+                findings.push(finding);
+            }
+        }
+    }
+
+    Ok(findings)
+}
+
+/// The objects of a bundle remote, and the key and latest head of each ref.
+/// Objects under `refs/` that no head needs are reported as orphaned.
+async fn list_bundles(
+    s3: &Client,
+    settings: &GitS3Settings,
+    findings: &mut Vec<Finding>,
+) -> Result<(Vec<Object>, Vec<(String, GitRef)>)> {
+    let prefix = settings.key();
+    let listing = s3::list(s3, settings.bucket(), &format!("{}/refs/", prefix)).await?;
+    let refs = list_refs(s3, settings).await?;
+
+    let mut objects = Vec::new();
+    let mut tips = Vec::new();
+    let mut needed = HashSet::new();
+    for remote_refs in refs.values() {
+        let latest = &remote_refs.latest_ref().reference;
+        tips.push((latest.bundle_path(prefix), latest.clone()));
+
+        for head in remote_refs.all_refs() {
+            let r = &head.reference;
+            let thin = RefClass::of(&r.name) == RefClass::Tags;
+            for key in head.object_keys(prefix) {
+                needed.insert(key.clone());
+                let (check, order) = if key.ends_with(".bundle") {
+                    (Check::Bundle(r.clone()), if thin { 1 } else { 0 })
+                } else if key.contains(".shallow.") {
+                    (Check::Bundle(r.clone()), 2)
+                } else if key.ends_with(".trees") {
+                    let tips = vec![r.sha.clone()];
+                    (Check::Pack { name: None, tips }, 2)
+                } else if key.ends_with(".blobs") {
+                    let tips = Vec::new();
+                    (Check::Pack { name: None, tips }, 2)
+                } else {
+                    // Empty peel markers
+                    continue;
+                };
+                objects.push(Object { key, check, order });
+            }
+        }
+    }
+
+    for o in listing {
+        if !needed.contains(&o.key) {
+            findings.push(Finding::new(
+                Status::Orphaned,
+                &o.key,
+                "not needed by any ref",
+            ));
+        }
+    }
+    Ok((objects, tips))
+}
+
+/// The packs of the manifest, and the manifest key with each of its refs. Packs
+/// the manifest doesn't know are reported as orphaned, and packs it lists that
+/// aren't on S3 as missing. Packs marked as garbage are left to `gc`.
+async fn list_packs(
+    s3: &Client,
+    settings: &GitS3Settings,
+    findings: &mut Vec<Finding>,
+) -> Result<(Vec<Object>, Vec<(String, GitRef)>)> {
+    let prefix = settings.key();
+    let manifest_key = packs::manifest_path(prefix);
+    let manifest = match packs::read_manifest(s3, settings).await {
+        Ok(manifest) => manifest.unwrap_or_default(),
+        Err(e) => {
+            findings.push(Finding::new(
+                Status::Undecryptable,
+                &manifest_key,
+                e.to_string(),
+            ));
+            return Ok((Vec::new(), Vec::new()));
+        }
+    };
+    let listing = s3::list(s3, settings.bucket(), &format!("{}/packs/", prefix)).await?;
+    let stored: HashSet<&str> = listing.iter().map(|o| o.key.as_str()).collect();
+
+    let mut objects = Vec::new();
+    for pack in manifest.packs.values() {
+        if manifest.garbage.contains_key(&pack.name) {
+            continue;
+        }
+        let key = packs::pack_path(prefix, &pack.name);
+        if !stored.contains(key.as_str()) {
+            findings.push(Finding::new(
+                Status::Missing,
+                &key,
+                "listed by the manifest",
+            ));
+            continue;
+        }
+        let check = Check::Pack {
+            name: Some(pack.name.clone()),
+            tips: pack.tips.clone(),
+        };
+        objects.push(Object {
+            key,
+            check,
+            order: 0,
+        });
+    }
+
+    for o in &listing {
+        let known = o
+            .key
+            .rsplit('/')
+            .next()
+            .and_then(|name| name.strip_suffix(".pack"))
+            .is_some_and(|name| manifest.packs.contains_key(name));
+        if !known {
+            findings.push(Finding::new(
+                Status::Orphaned,
+                &o.key,
+                "not in the manifest",
+            ));
+        }
+    }
+
+    let tips = manifest
+        .refs
+        .values()
+        .map(|m| {
+            let r = GitRef {
+                name: m.name.clone(),
+                sha: m.sha.clone(),
+            };
+            (manifest_key.clone(), r)
+        })
+        .collect();
+    Ok((objects, tips))
+}
+
+/// The streams of the manifest, in the order they were pushed, and the manifest
+/// key with each of its refs. Streams are replayed into the scratch repository,
+/// their marks files are only checked for presence. Anything else under
+/// `fast-export/` is reported as orphaned.
+async fn list_streams(
+    s3: &Client,
+    settings: &GitS3Settings,
+    findings: &mut Vec<Finding>,
+) -> Result<(Vec<Object>, Vec<(String, GitRef)>)> {
+    let prefix = settings.key();
+    let manifest_key = fastexport::manifest_path(prefix);
+    let manifest = match fastexport::read_manifest(s3, settings).await {
+        Ok(manifest) => manifest.unwrap_or_default(),
+        Err(e) => {
+            findings.push(Finding::new(
+                Status::Undecryptable,
+                &manifest_key,
+                e.to_string(),
+            ));
+            return Ok((Vec::new(), Vec::new()));
+        }
+    };
+    let listing = s3::list(s3, settings.bucket(), &format!("{}/fast-export/", prefix)).await?;
+    let stored: HashSet<&str> = listing.iter().map(|o| o.key.as_str()).collect();
+
+    let mut objects = Vec::new();
+    let mut needed = HashSet::from([manifest_key.clone()]);
+    for stream in &manifest.streams {
+        let key = fastexport::stream_path(prefix, &stream.name);
+        let marks_key = fastexport::marks_path(prefix, &stream.name);
+        for key in [&key, &marks_key] {
+            needed.insert(key.clone());
+            if !stored.contains(key.as_str()) {
+                findings.push(Finding::new(Status::Missing, key, "listed by the manifest"));
+            }
+        }
+        if !stored.contains(key.as_str()) {
+            continue;
+        }
+        let check = Check::Stream {
+            name: stream.name.clone(),
+            tips: stream.tips.clone(),
+        };
+        objects.push(Object {
+            key,
+            check,
+            order: 0,
+        });
+    }
+
+    for o in &listing {
+        if !needed.contains(&o.key) {
+            findings.push(Finding::new(
+                Status::Orphaned,
+                &o.key,
+                "not in the manifest",
+            ));
+        }
+    }
+
+    let tips = manifest
+        .refs
+        .iter()
+        .map(|(name, sha)| {
+            let r = GitRef {
+                name: name.clone(),
+                sha: sha.clone(),
+            };
+            (manifest_key.clone(), r)
+        })
+        .collect();
+    Ok((objects, tips))
+}
+
+/// Download and decrypt an object, bypassing the bundle cache so that what is
+/// checked is what is on S3.
+async fn download(
+    s3: &Client,
+    settings: &GitS3Settings,
+    key: &str,
+    enc_file: &Path,
+    file: &Path,
+) -> std::result::Result<PathBuf, Finding> {
+    let o = s3::Key {
+        bucket: settings.bucket().to_owned(),
+        key: key.to_string(),
+    };
+    let sse = settings
+        .sse()
+        .map_err(|e| Finding::new(Status::Unreadable, key, e.to_string()))?;
+    s3::get(s3, enc_file, &o, sse)
+        .await
+        .map_err(|e| Finding::new(Status::Unreadable, key, e.to_string()))?;
+
+    let (enc_file, out_file) = (enc_file.to_owned(), file.to_owned());
+    tokio::task::spawn_blocking(move || gpg::decrypt(&enc_file, &out_file))
+        .await
+        .map_err(|e| Finding::new(Status::Undecryptable, key, e.to_string()))?
+        .map_err(|e| Finding::new(Status::Undecryptable, key, e.to_string()))?;
+    pad::unpad(file).map_err(|e| Finding::new(Status::Corrupt, key, e.to_string()))?;
+    Ok(file.to_owned())
+}
+
+/// Check a decrypted object and add its objects to the scratch repository
+fn check(o: &Object, file: &Path, scratch: &Path) -> Result<Finding> {
+    let key = o.key.as_str();
+    match &o.check {
+        Check::Bundle(r) => {
+            match git::bundle_verify(file, scratch)? {
+                BundleCheck::Valid => {}
+                BundleCheck::MissingPrerequisites(shas) => {
+                    let detail = shas.join(",");
+                    return Ok(Finding::new(Status::MissingPrerequisites, key, detail));
+                }
+                BundleCheck::Invalid(reason) => {
+                    return Ok(Finding::new(Status::Corrupt, key, reason));
+                }
+            }
+
+            let heads = git::bundle_list_heads(file, scratch)?;
+            if !heads
+                .iter()
+                .any(|(sha, name)| *sha == r.sha && *name == r.name)
+            {
+                let detail = heads
+                    .iter()
+                    .map(|(sha, name)| format!("{} {}", name, sha))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                return Ok(Finding::new(Status::TipMismatch, key, detail));
+            }
+
+            if let Err(e) = git::bundle_unbundle(file, "", scratch) {
+                return Ok(Finding::new(Status::Corrupt, key, e.to_string()));
+            }
+        }
+        Check::Pack { name, tips } => {
+            if let Some(name) = name {
+                let checksum = packs::pack_checksum(file)?;
+                if checksum != *name {
+                    let detail = format!("checksum {}", checksum);
+                    return Ok(Finding::new(Status::Corrupt, key, detail));
+                }
+            }
+            let indexed = match git::index_pack(file, false, scratch) {
+                Ok(indexed) => indexed,
+                Err(e) => return Ok(Finding::new(Status::Corrupt, key, e.to_string())),
+            };
+            let contents: HashSet<String> =
+                git::pack_contents(&indexed, scratch)?.into_iter().collect();
+            for tip in tips {
+                if !contents.contains(tip) {
+                    let detail = format!("{} not in the pack", tip);
+                    return Ok(Finding::new(Status::TipMismatch, key, detail));
+                }
+            }
+        }
+        Check::Stream { name, tips } => {
+            let mut hasher = Sha256::new();
+            io::copy(&mut File::open(file)?, &mut hasher)?;
+            let checksum = format!("{:x}", hasher.finalize());
+            if checksum != *name {
+                let detail = format!("sha256 {}", checksum);
+                return Ok(Finding::new(Status::Corrupt, key, detail));
+            }
+            if let Err(e) = git::fast_import(file, None, scratch) {
+                return Ok(Finding::new(Status::Corrupt, key, e.to_string()));
+            }
+            for tip in tips {
+                if !git::object_exists(tip, scratch)? {
+                    let detail = format!("{} not in the stream", tip);
+                    return Ok(Finding::new(Status::TipMismatch, key, detail));
+                }
+            }
+        }
+    }
+    Ok(Finding::new(Status::Ok, key, ""))
+}
+
+/// Check that the whole history of a ref made it into the scratch repository
+fn check_complete(key: &str, r: &GitRef, scratch: &Path) -> Result<Option<Finding>> {
+    if !git::object_exists(&r.sha, scratch)? {
+        // Either the object itself or, for a tag, the commit it points to
+        let detail = format!("{} {} can't be resolved", r.name, r.sha);
+        return Ok(Some(Finding::new(Status::Incomplete, key, detail)));
+    }
+    let missing = git::missing_objects(&r.sha, scratch)?;
+    if !missing.is_empty() {
+        let detail = format!("{} {} objects missing", r.name, missing.len());
+        return Ok(Some(Finding::new(Status::Incomplete, key, detail)));
+    }
+    Ok(None)
+}
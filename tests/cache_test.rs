@@ -0,0 +1,93 @@
+use anyhow::Result;
+use std::fs;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::init_test_logging;
+
+use git_remote_s3::cache::BundleCache;
+use git_remote_s3::s3::Key;
+
+fn key(name: &str) -> Key {
+    Key {
+        bucket: "bucket".to_string(),
+        key: format!("prefix/refs/heads/{}/0123456.bundle", name),
+    }
+}
+
+#[test]
+fn test_cache_get_put() -> Result<()> {
+    init_test_logging();
+
+    let dir = TempDir::new()?;
+    let cache = BundleCache::new(dir.path().join("cache"), 1 << 20);
+    let src = dir.path().join("src");
+    let dst = dir.path().join("dst");
+    fs::write(&src, "encrypted bundle")?;
+
+    assert!(!cache.get(&key("main"), "\"etag1\"", &dst)?);
+
+    cache.put(&key("main"), "\"etag1\"", &src)?;
+    assert!(cache.get(&key("main"), "\"etag1\"", &dst)?);
+    assert_eq!(fs::read_to_string(&dst)?, "encrypted bundle");
+
+    // A rewritten object has a new ETag and must not hit the old entry
+    assert!(!cache.get(&key("main"), "\"etag2\"", &dst)?);
+    assert!(!cache.get(&key("other"), "\"etag1\"", &dst)?);
+
+    assert_eq!(cache.clear()?, 16);
+    assert!(cache.entries()?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_cache_evicts_least_recently_used() -> Result<()> {
+    init_test_logging();
+
+    let dir = TempDir::new()?;
+    let cache = BundleCache::new(dir.path().join("cache"), 250);
+    let src = dir.path().join("src");
+    let dst = dir.path().join("dst");
+    fs::write(&src, [0u8; 100])?;
+
+    cache.put(&key("a"), "etag", &src)?;
+    thread::sleep(Duration::from_millis(20));
+    cache.put(&key("b"), "etag", &src)?;
+    thread::sleep(Duration::from_millis(20));
+    // Using "a" makes "b" the least recently used entry
+    assert!(cache.get(&key("a"), "etag", &dst)?);
+    thread::sleep(Duration::from_millis(20));
+    cache.put(&key("c"), "etag", &src)?;
+
+    assert_eq!(cache.entries()?.len(), 2);
+    assert!(cache.get(&key("a"), "etag", &dst)?);
+    assert!(!cache.get(&key("b"), "etag", &dst)?);
+    assert!(cache.get(&key("c"), "etag", &dst)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_cache_open_or_skip_invalid_size() -> Result<()> {
+    init_test_logging();
+
+    let dir = TempDir::new()?;
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .current_dir(dir.path())
+            .output()
+    };
+    git(&["init", "--quiet"])?;
+    git(&["config", "s3.cacheSize", "lots"])?;
+
+    // A fetch goes without the cache rather than failing
+    assert!(BundleCache::open(dir.path()).is_err());
+    assert!(BundleCache::open_or_skip(dir.path()).is_none());
+
+    Ok(())
+}
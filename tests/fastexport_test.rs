@@ -0,0 +1,146 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeSet;
+
+mod common;
+use common::init_test_logging;
+
+use git_remote_s3::fastexport::{
+    parse_marks, rewrite_stream, Manifest, ManifestStream, StreamSummary,
+};
+
+const STREAM: &str = "feature done
+blob
+mark :1
+data 12
+mark :2
+done
+
+reset refs/heads/main
+commit refs/heads/main
+mark :3
+author T <t@example.com> 1700000000 +0000
+committer T <t@example.com> 1700000000 +0000
+data 4
+c3
+
+from :2
+merge 0123456789abcdef0123456789abcdef01234567
+M 100644 :1 a file
+N :1 :3
+tag v1
+from :3
+tagger T <t@example.com> 1700000000 +0000
+data 3
+v1
+done
+left over
+";
+
+fn rewrite(
+    stream: &str,
+    mark: &mut impl FnMut(u64, bool) -> Result<String>,
+) -> Result<(String, StreamSummary)> {
+    let mut output = Vec::new();
+    let summary = rewrite_stream(&mut stream.as_bytes(), &mut output, mark)?;
+    Ok((String::from_utf8(output)?, summary))
+}
+
+#[test]
+fn test_rewrite_stream() -> Result<()> {
+    init_test_logging();
+
+    // Marks the stream defines are renumbered, the others replaced by shas
+    let (output, summary) = rewrite(STREAM, &mut |n, defined| match (n, defined) {
+        (_, true) => Ok(format!(":{}", n + 10)),
+        (2, false) => Ok("c2".to_string()),
+        _ => Err(anyhow!("unknown mark {}", n)),
+    })?;
+
+    // data contents are copied as they are, even when they look like commands
+    assert!(output.starts_with("blob\nmark :11\ndata 12\nmark :2\ndone\n\n"));
+    assert!(output.contains("from c2\nmerge 0123456789abcdef0123456789abcdef01234567\n"));
+    assert!(output.contains("M 100644 :11 a file\nN :11 :13\n"));
+    assert!(output.contains("tag v1\nfrom :13\n"));
+    assert!(output.ends_with("data 3\nv1\n"));
+    assert!(!output.contains("left over"));
+
+    assert!(summary.done);
+    assert_eq!(
+        summary.refs,
+        BTreeSet::from(["refs/heads/main".to_string(), "refs/tags/v1".to_string()])
+    );
+    assert_eq!(summary.marks, BTreeSet::from([1, 3]));
+
+    // The mapping's errors are returned
+    assert!(rewrite(STREAM, &mut |n, defined| if defined {
+        Ok(format!(":{}", n))
+    } else {
+        Err(anyhow!("unknown mark {}", n))
+    })
+    .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_rewrite_stream_incomplete() -> Result<()> {
+    init_test_logging();
+
+    let identity = &mut |n, _| Ok(format!(":{}", n));
+
+    let (output, summary) = rewrite("reset refs/tags/v1\nfrom 0000\n", identity)?;
+    assert_eq!(output, "reset refs/tags/v1\nfrom 0000\n");
+    assert!(!summary.done);
+
+    assert!(rewrite("blob\ndata 10\nshort", identity).is_err());
+    assert!(rewrite("blob\ndata <<EOF\nx\nEOF\n", identity).is_err());
+    assert!(rewrite("commit refs/heads/main\nmark 3\n", identity).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_marks() -> Result<()> {
+    init_test_logging();
+
+    let marks = parse_marks(":1 c1\n:20 c20\n\n")?;
+    assert_eq!(marks.len(), 2);
+    assert_eq!(marks[&1], "c1");
+    assert_eq!(marks[&20], "c20");
+
+    assert!(parse_marks("1 c1\n").is_err());
+    assert!(parse_marks(":x c1\n").is_err());
+    assert!(parse_marks(":1\n").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_manifest_roundtrip() -> Result<()> {
+    init_test_logging();
+
+    let mut manifest = Manifest::default();
+    for (name, tips) in [("s1", vec!["c1"]), ("s2", vec!["c2", "t1"])] {
+        manifest.streams.push(ManifestStream {
+            name: name.to_string(),
+            tips: tips.into_iter().map(|tip| tip.to_string()).collect(),
+        });
+    }
+    manifest
+        .refs
+        .insert("refs/heads/main".to_string(), "c2".to_string());
+    manifest
+        .refs
+        .insert("refs/tags/v1".to_string(), "t1".to_string());
+
+    let text = manifest.to_text();
+    assert!(text.contains("stream s1 c1\nstream s2 c2,t1\n"));
+    assert!(text.contains("ref refs/tags/v1 t1\n"));
+    assert_eq!(Manifest::parse(&text)?, manifest);
+
+    assert!(Manifest::parse("stream\n").is_err());
+    assert!(Manifest::parse("ref refs/heads/main\n").is_err());
+    assert!(Manifest::parse("pack p1 c1\n").is_err());
+
+    Ok(())
+}
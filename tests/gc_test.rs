@@ -0,0 +1,24 @@
+use std::time::Duration;
+
+mod common;
+use common::init_test_logging;
+
+use git_remote_s3::gc::parse_duration;
+
+#[test]
+fn test_parse_duration() {
+    init_test_logging();
+
+    assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+    assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+    assert_eq!(parse_duration("15m"), Some(Duration::from_secs(15 * 60)));
+    assert_eq!(parse_duration("12h"), Some(Duration::from_secs(12 * 3600)));
+    assert_eq!(parse_duration("7d"), Some(Duration::from_secs(7 * 86400)));
+    assert_eq!(parse_duration("0"), Some(Duration::ZERO));
+
+    assert_eq!(parse_duration(""), None);
+    assert_eq!(parse_duration("d"), None);
+    assert_eq!(parse_duration("1w"), None);
+    assert_eq!(parse_duration("-1h"), None);
+    assert_eq!(parse_duration("999999999999999999d"), None);
+}
@@ -0,0 +1,106 @@
+use anyhow::Result;
+use aws_sdk_s3::{primitives::ByteStream, Client};
+use futures::stream::{self, StreamExt, TryStreamExt};
+
+mod common;
+use common::init_test_logging;
+
+use git_remote_s3::git_s3::{list_refs, GitS3Settings};
+use git_remote_s3::s3;
+
+const TEST_REGION: &str = "us-east-1";
+const TEST_ENDPOINT: &str = "http://localhost:9001";
+const TEST_ACCESS_KEY: &str = "test";
+const TEST_SECRET_KEY: &str = "test1234";
+const TEST_BUCKET: &str = "git-remote-s3";
+
+async fn ensure_test_bucket(s3: &Client) -> Result<()> {
+    match s3.create_bucket().bucket(TEST_BUCKET).send().await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            if e.to_string().contains("BucketAlreadyOwnedByYou") {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("Failed to create bucket: {}", e))
+            }
+        }
+    }
+}
+
+async fn put_empty(s3: &Client, key: String) -> Result<()> {
+    s3.put_object()
+        .bucket(TEST_BUCKET)
+        .key(key)
+        .body(ByteStream::from_static(b""))
+        .send()
+        .await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list_refs_beyond_one_page() -> Result<()> {
+    init_test_logging();
+
+    std::env::set_var("AWS_ACCESS_KEY_ID", TEST_ACCESS_KEY);
+    std::env::set_var("AWS_SECRET_ACCESS_KEY", TEST_SECRET_KEY);
+
+    let s3 = s3::create_client(
+        Some(TEST_REGION.to_string()),
+        Some(TEST_ENDPOINT.to_string()),
+    )
+    .await?;
+    ensure_test_bucket(&s3).await?;
+
+    let prefix = "list-refs-pages";
+    for object in s3::list(&s3, TEST_BUCKET, prefix).await? {
+        s3.delete_object()
+            .bucket(TEST_BUCKET)
+            .key(object.key)
+            .send()
+            .await?;
+    }
+
+    // Reflogs sort before the refs and use up the first page on their own
+    let branches = 600;
+    let keys = (0..branches).flat_map(|i| {
+        [
+            format!("{}/logs/refs/heads/b{:04}", prefix, i),
+            format!("{}/refs/heads/b{:04}/{:040x}.bundle", prefix, i, i),
+        ]
+    });
+    stream::iter(keys)
+        .map(|key| put_empty(&s3, key))
+        .buffer_unordered(32)
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    let settings = GitS3Settings::new(
+        "origin".to_string(),
+        format!("s3://{}/{}", TEST_BUCKET, prefix),
+    );
+    let refs = list_refs(&s3, &settings).await?;
+    assert_eq!(refs.len(), branches);
+    let last = &refs["refs/heads/b0599"].latest_ref().reference;
+    assert_eq!(last.sha, format!("{:040x}", 599));
+
+    Ok(())
+}
+
+#[test]
+fn test_invalid_urls() {
+    init_test_logging();
+
+    assert!(GitS3Settings::validate_url("s3://bucket/prefix").is_ok());
+    assert!(GitS3Settings::validate_url("s3://bucket/a/b").is_ok());
+    for url in [
+        "s3://bucket",
+        "s3://bucket/",
+        "s3:///prefix",
+        "https://bucket/prefix",
+    ] {
+        assert!(GitS3Settings::validate_url(url).is_err(), "{}", url);
+    }
+
+    // An error rather than a panic when the bucket is first used
+    assert!(GitS3Settings::from_remote("s3://bucket").is_err());
+}
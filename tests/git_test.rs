@@ -47,17 +47,17 @@ fn create_commits(repo_dir: &Path, num_commits: usize) -> Result<Vec<String>> {
 
         Command::new("git")
             .args(["add", &file_name])
-            .current_dir(&repo_dir)
+            .current_dir(repo_dir)
             .output()?;
 
         Command::new("git")
             .args(["commit", "-m", &format!("test commit {}", i)])
-            .current_dir(&repo_dir)
+            .current_dir(repo_dir)
             .output()?;
 
         let output = Command::new("git")
             .args(["rev-parse", "HEAD"])
-            .current_dir(&repo_dir)
+            .current_dir(repo_dir)
             .output()?;
         let commit_sha = String::from_utf8(output.stdout)?.trim().to_string();
         commit_shas.push(commit_sha);
@@ -67,7 +67,7 @@ fn create_commits(repo_dir: &Path, num_commits: usize) -> Result<Vec<String>> {
 }
 
 fn create_commit(repo_dir: &Path) -> Result<String> {
-    let commit_shas = create_commits(&repo_dir, 1)?;
+    let commit_shas = create_commits(repo_dir, 1)?;
     Ok(commit_shas.into_iter().next().unwrap())
 }
 
@@ -77,7 +77,7 @@ fn test_git_rev_parse() -> Result<()> {
 
     let repo_dir = init_git_repo()?;
 
-    let head = create_commit(&repo_dir.path())?;
+    let head = create_commit(repo_dir.path())?;
 
     assert!(!head.is_empty());
     assert_eq!(head.len(), 40); // SHA-1 hash is 40 characters
@@ -85,6 +85,24 @@ fn test_git_rev_parse() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_git_object_exists() -> Result<()> {
+    init_test_logging();
+
+    let repo_dir = init_git_repo()?;
+    let repo_path = repo_dir.path();
+
+    let head = create_commit(repo_path)?;
+
+    assert!(git::object_exists(&head, repo_path)?);
+    assert!(!git::object_exists(
+        "0123456789abcdef0123456789abcdef01234567",
+        repo_path
+    )?);
+
+    Ok(())
+}
+
 #[test]
 fn test_git_is_ancestor() -> Result<()> {
     init_test_logging();
@@ -92,29 +110,17 @@ fn test_git_is_ancestor() -> Result<()> {
     let repo_dir = init_git_repo()?;
     let repo_path = repo_dir.path();
 
-    let commits = create_commits(&repo_path, 2)?;
+    let commits = create_commits(repo_path, 2)?;
     let first_commit = &commits[0];
     let second_commit = &commits[1];
 
     // Test ancestry using our git module
-    assert!(git::is_ancestor(&first_commit, &second_commit, &repo_path)?);
-    assert!(!git::is_ancestor(
-        &second_commit,
-        &first_commit,
-        &repo_path
-    )?);
+    assert!(git::is_ancestor(first_commit, second_commit, repo_path)?);
+    assert!(!git::is_ancestor(second_commit, first_commit, repo_path)?);
 
     // Test with non-existent commits
-    assert!(!git::is_ancestor(
-        "non-existent",
-        &second_commit,
-        &repo_path
-    )?);
-    assert!(!git::is_ancestor(
-        &first_commit,
-        "non-existent",
-        &repo_path
-    )?);
+    assert!(!git::is_ancestor("non-existent", second_commit, repo_path)?);
+    assert!(!git::is_ancestor(first_commit, "non-existent", repo_path)?);
 
     Ok(())
 }
@@ -127,9 +133,9 @@ fn test_git_bundle() -> Result<()> {
     let source_path = source_dir.path();
 
     // Create bundle
-    create_commit(&source_path)?;
+    create_commit(source_path)?;
     let bundle_file = source_dir.path().join("test.bundle");
-    git::bundle_create(bundle_file.as_path(), "HEAD", &source_path)?;
+    git::bundle_create(bundle_file.as_path(), "HEAD", &[], source_path)?;
 
     // Verify bundle contents
     let output = Command::new("git")
@@ -137,7 +143,151 @@ fn test_git_bundle() -> Result<()> {
         .output()?;
     assert!(output.status.success());
 
-    git::bundle_unbundle(bundle_file.as_path(), "", &source_path)?;
+    git::bundle_unbundle(bundle_file.as_path(), "", source_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_git_bundle_thin() -> Result<()> {
+    init_test_logging();
+
+    let source_dir = init_git_repo()?;
+    let source_path = source_dir.path();
+    let commits = create_commits(source_path, 2)?;
+
+    // Bundle only the second commit, with the first as a prerequisite
+    let bundle_file = source_dir.path().join("thin.bundle");
+    git::bundle_create(
+        bundle_file.as_path(),
+        "HEAD",
+        &[commits[0].clone()],
+        source_path,
+    )?;
+
+    // The source repository has the prerequisite, an empty one doesn't
+    let output = Command::new("git")
+        .args(["bundle", "verify", bundle_file.to_str().unwrap()])
+        .current_dir(source_path)
+        .output()?;
+    assert!(output.status.success());
+
+    let empty_dir = init_git_repo()?;
+    assert!(git::bundle_unbundle(bundle_file.as_path(), "", empty_dir.path()).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_git_bundle_verify() -> Result<()> {
+    init_test_logging();
+
+    let source_dir = init_git_repo()?;
+    let source_path = source_dir.path();
+    let commits = create_commits(source_path, 2)?;
+
+    let bundle_file = source_path.join("thin.bundle");
+    git::bundle_create(&bundle_file, "HEAD", &[commits[0].clone()], source_path)?;
+    assert_eq!(
+        git::bundle_list_heads(&bundle_file, source_path)?,
+        [(commits[1].clone(), "HEAD".to_string())]
+    );
+
+    assert_eq!(
+        git::bundle_verify(&bundle_file, source_path)?,
+        git::BundleCheck::Valid
+    );
+    let empty_dir = init_git_repo()?;
+    assert_eq!(
+        git::bundle_verify(&bundle_file, empty_dir.path())?,
+        git::BundleCheck::MissingPrerequisites(vec![commits[0].clone()])
+    );
+
+    let not_a_bundle = source_path.join("junk.bundle");
+    fs::write(&not_a_bundle, "junk")?;
+    assert!(matches!(
+        git::bundle_verify(&not_a_bundle, source_path)?,
+        git::BundleCheck::Invalid(_)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_git_merge_tree() -> Result<()> {
+    init_test_logging();
+
+    let repo_dir = init_git_repo()?;
+    let repo_path = repo_dir.path();
+    let base = create_commit(repo_path)?;
+    let run = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .current_dir(repo_path)
+            .output()
+    };
+
+    // Two branches adding different files merge cleanly
+    run(&["checkout", "-q", "-b", "side"])?;
+    fs::write(repo_path.join("side.txt"), "side")?;
+    run(&["add", "side.txt"])?;
+    run(&["commit", "-q", "-m", "side"])?;
+    let side = git::rev_parse("HEAD", repo_path)?;
+    run(&["checkout", "-q", &base])?;
+    fs::write(repo_path.join("main.txt"), "main")?;
+    run(&["add", "main.txt"])?;
+    run(&["commit", "-q", "-m", "main"])?;
+    let main = git::rev_parse("HEAD", repo_path)?;
+
+    let tree = git::merge_tree(&main, &side, repo_path)?.expect("clean merge");
+    let merge = git::commit_tree(
+        &tree,
+        &[main.clone(), side.clone()],
+        "Merge side",
+        repo_path,
+    )?;
+    assert!(git::is_ancestor(&main, &merge, repo_path)?);
+    assert!(git::is_ancestor(&side, &merge, repo_path)?);
+    assert_eq!(git::commit_summary(&merge, repo_path)?, "Merge side");
+
+    // Changing the same file differently conflicts
+    fs::write(repo_path.join("side.txt"), "main")?;
+    run(&["add", "side.txt"])?;
+    run(&["commit", "-q", "-m", "conflict"])?;
+    let conflict = git::rev_parse("HEAD", repo_path)?;
+    assert_eq!(git::merge_tree(&conflict, &side, repo_path)?, None);
+
+    Ok(())
+}
+
+#[test]
+fn test_git_promisor_packs() -> Result<()> {
+    init_test_logging();
+
+    let source_dir = init_git_repo()?;
+    let source_path = source_dir.path();
+    let head = create_commit(source_path)?;
+    let blobs = git::tree_blobs(&head, source_path)?;
+    assert_eq!(blobs.len(), 1);
+
+    let trees_pack = source_path.join("trees.pack");
+    let blobs_pack = source_path.join("blobs.pack");
+    git::pack_objects_filtered(&trees_pack, &head, "blob:none", source_path)?;
+    git::pack_objects(&blobs_pack, &blobs, source_path)?;
+
+    // Commits and trees only, the blob is promised
+    let partial_dir = init_git_repo()?;
+    let partial_path = partial_dir.path();
+    let name = git::index_pack(&trees_pack, true, partial_path)?;
+    assert!(git::pack_contents(&name, partial_path)?.contains(&head));
+    assert!(git::object_exists(&head, partial_path)?);
+    assert!(!git::object_exists(&blobs[0], partial_path)?);
+    assert_eq!(git::tree_blobs(&head, partial_path)?, blobs);
+    assert_eq!(git::missing_objects(&head, partial_path)?, blobs);
+
+    git::index_pack(&blobs_pack, true, partial_path)?;
+    assert!(git::object_exists(&blobs[0], partial_path)?);
+    assert!(git::missing_objects(&head, partial_path)?.is_empty());
 
     Ok(())
 }
@@ -7,6 +7,7 @@ mod common;
 use common::init_test_logging;
 
 use git_remote_s3::gpg;
+use git_remote_s3::pad::{self, Padding};
 
 #[test]
 fn test_gpg_no_recipients() -> Result<()> {
@@ -74,6 +75,39 @@ fn test_gpg_missing_file() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_gpg_padded_sizes() -> Result<()> {
+    init_test_logging();
+
+    let recipients = vec![get_test_gpg_key()?];
+    let padding = Padding::Quantum(4096);
+
+    // Inputs of different sizes encrypt to the same size once padded
+    let mut sizes = Vec::new();
+    for len in [1000, 3000] {
+        let content: Vec<u8> = (0..len).map(|i| (i * 7 % 251) as u8).collect();
+        let mut input_file = NamedTempFile::new()?;
+        input_file.write_all(&content)?;
+        let encrypted_file = NamedTempFile::new()?;
+        let decrypted_file = NamedTempFile::new()?;
+
+        pad::pad(input_file.path(), &padding)?;
+        gpg::encrypt_uncompressed(&recipients, input_file.path(), encrypted_file.path())?;
+        let encrypted = fs::read(encrypted_file.path())?;
+        assert!(!encrypted.ends_with(b"GITS3PAD"));
+        sizes.push(encrypted.len());
+
+        gpg::decrypt(encrypted_file.path(), decrypted_file.path())?;
+        pad::unpad(decrypted_file.path())?;
+        assert_eq!(fs::read(decrypted_file.path())?, content);
+    }
+    // Up to a few bytes apart: the encrypted session key is an integer without
+    // leading zero bytes. Unpadded, they would be 2000 bytes apart.
+    assert!(sizes[0].abs_diff(sizes[1]) < 16, "{:?}", sizes);
+
+    Ok(())
+}
+
 // Helper function to get a test GPG key
 fn get_test_gpg_key() -> Result<String> {
     let output = std::process::Command::new("gpg")
@@ -85,7 +119,7 @@ fn get_test_gpg_key() -> Result<String> {
     // Parse the output to get the first key ID
     for line in output.lines() {
         let fields: Vec<&str> = line.split(':').collect();
-        if fields.get(0) == Some(&"pub") {
+        if fields.first() == Some(&"pub") {
             if let Some(key_id) = fields.get(4) {
                 return Ok(key_id.to_string());
             }
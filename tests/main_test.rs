@@ -1,14 +1,16 @@
 use anyhow::Result;
 use assert_cmd::assert::OutputAssertExt;
 use assert_cmd::cargo::cargo_bin;
+use aws_sdk_s3::types::{BucketVersioningStatus, VersioningConfiguration};
 use aws_sdk_s3::Client;
 use git_remote_s3::s3;
+use std::collections::HashMap;
 use std::env;
 use std::fs;
+use std::io::{BufRead, BufReader, Write};
 use std::path::Path;
-use std::process::Command;
+use std::process::{Command, Stdio};
 use tempfile::TempDir;
-use tokio;
 use tracing::{debug, info};
 
 mod common;
@@ -52,11 +54,17 @@ async fn create_test_client() -> Result<Client> {
     s3::create_client(Some("us-east-1".to_string()), Some(S3_ENDPOINT.to_string())).await
 }
 
-async fn delete_object(client: &Client, bucket: &str, filename: &str) -> Result<()> {
+async fn delete_object(
+    client: &Client,
+    bucket: &str,
+    filename: &str,
+    version_id: Option<&str>,
+) -> Result<()> {
     client
         .delete_object()
         .bucket(bucket)
         .key(filename)
+        .set_version_id(version_id.map(String::from))
         .send()
         .await?;
     Ok(())
@@ -68,7 +76,7 @@ async fn list_keys_in_bucket(client: &Client, bucket: &str) -> Result<Vec<String
             .contents()
             .unwrap_or_default()
             .iter()
-            .filter_map(|obj| Some(obj.key().unwrap_or_default().to_string()))
+            .map(|obj| obj.key().unwrap_or_default().to_string())
             .collect()),
         Err(e) => Err(e.into()),
     }
@@ -85,9 +93,20 @@ async fn delete_bucket(client: &Client, bucket: &str) -> Result<()> {
 }
 
 async fn delete_bucket_recurse(client: &Client, bucket: &str) -> Result<()> {
-    let keys = list_keys_in_bucket(client, bucket).await?;
-    for key in keys {
-        delete_object(client, bucket, &key).await?;
+    // Versioned buckets keep every version and delete marker, which all have to go
+    let output = client.list_object_versions().bucket(bucket).send().await?;
+    let versions = output
+        .versions()
+        .unwrap_or_default()
+        .iter()
+        .map(|v| (v.key(), v.version_id()));
+    let markers = output
+        .delete_markers()
+        .unwrap_or_default()
+        .iter()
+        .map(|m| (m.key(), m.version_id()));
+    for (key, version_id) in versions.chain(markers) {
+        delete_object(client, bucket, key.unwrap_or_default(), version_id).await?;
     }
     delete_bucket(client, bucket).await?;
     Ok(())
@@ -189,7 +208,7 @@ async fn integration() -> Result<()> {
     let sha2l = git_rev_long(&repo2);
     git(&repo2, "push origin").assert().failure();
     git(&repo2, "push -f origin").assert().success();
-    // assert that there are 2 refs on s3 (the original was kept)
+    // assert that only the newest head is listed, the original is kept on s3
     let ls_remote_output = git(&repo1, "ls-remote origin")
         .assert()
         .success()
@@ -198,9 +217,23 @@ async fn integration() -> Result<()> {
         .clone();
     let ls_remote_str = String::from_utf8_lossy(&ls_remote_output);
     assert!(ls_remote_str.contains(&format!("{}\trefs/heads/main", sha2l)));
-    assert!(ls_remote_str.contains(&format!("{}\trefs/heads/main__{}", sha1l, sha1)));
+    assert!(!ls_remote_str.contains("refs/heads/main__"));
 
     git(&repo1, "pull -r origin main").assert().success();
+    // assert that the original is fetched into the hidden namespace only
+    let private_output = git(&repo1, "for-each-ref refs/s3/origin")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let private_str = String::from_utf8_lossy(&private_output);
+    assert!(private_str.contains(&format!("{} commit\trefs/s3/origin/heads/main\n", sha2l)));
+    assert!(private_str.contains(&format!(
+        "{} commit\trefs/s3/origin/stale/heads/main__{}\n",
+        sha1l, sha1
+    )));
+    git(&repo1, "branch -r").assert().stdout("  origin/main\n");
     git(
         &repo1,
         format!("log --oneline --decorate=short -n 1 {}", sha2).as_str(),
@@ -217,7 +250,7 @@ async fn integration() -> Result<()> {
         .clone();
     let ls_remote_str = String::from_utf8_lossy(&ls_remote_output);
     assert!(ls_remote_str.contains(&format!("{}\trefs/heads/main", sha2l)));
-    assert!(ls_remote_str.contains(&format!("{}\trefs/heads/main__{}", sha1l, sha1)));
+    assert!(!ls_remote_str.contains("refs/heads/main__"));
 
     // Cleanup
     delete_bucket_recurse(&client, bucket).await?;
@@ -225,3 +258,1069 @@ async fn integration() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn thin_tag_basis_survives_prune() -> Result<()> {
+    let client = create_test_client().await?;
+    let bucket = "git-remote-s3-thin-tags";
+
+    let _ = delete_bucket_recurse(&client, bucket).await;
+    create_bucket(&client, bucket).await?;
+
+    let test_dir = setup()?;
+    let repo1 = test_dir.path().join("repo1");
+    let repo2 = test_dir.path().join("repo2");
+    fs::create_dir(&repo1).unwrap();
+
+    git(&repo1, "init").assert().success();
+    git(&repo1, "config user.email test@example.com")
+        .assert()
+        .success();
+    git(&repo1, "config user.name Test").assert().success();
+    git(&repo1, "branch -M main").assert().success();
+    git(&repo1, "commit --allow-empty -am c1")
+        .assert()
+        .success();
+    git(&repo1, &format!("remote add origin s3://{}/test", bucket))
+        .assert()
+        .success();
+    git(&repo1, "push origin main").assert().success();
+
+    info!("test: push a thin tag on the branch head, and the head to another branch");
+    git(&repo1, "commit --allow-empty -am c2")
+        .assert()
+        .success();
+    git(&repo1, "push origin main").assert().success();
+    let tagged = git_rev_long(&repo1);
+    git(&repo1, "tag -a v1 -m v1").assert().success();
+    git(&repo1, "push origin v1").assert().success();
+    git(&repo1, "branch dev").assert().success();
+    git(&repo1, "push origin dev").assert().success();
+
+    info!("test: force push unrelated history and prune");
+    git(&repo1, "checkout --orphan rewrite").assert().success();
+    git(&repo1, "commit --allow-empty -am r1")
+        .assert()
+        .success();
+    git(&repo1, "branch -f main rewrite").assert().success();
+    git(&repo1, "checkout main").assert().success();
+    git(&repo1, "push -f origin main").assert().success();
+    // dev includes the old head, but the tag bundle was built on it
+    let prune_output = git(&repo1, "s3 prune origin")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let prune_str = String::from_utf8_lossy(&prune_output);
+    assert!(prune_str.contains(&format!(
+        "kept refs/heads/main {}: basis of refs/tags/v1",
+        &tagged[..7]
+    )));
+
+    info!("test: fetch the tag into a clone of the rewritten branch only");
+    git(
+        test_dir.path(),
+        &format!(
+            "clone --single-branch --no-tags -b main s3://{}/test repo2",
+            bucket
+        ),
+    )
+    .assert()
+    .success();
+    git(&repo2, "fetch origin tag v1").assert().success();
+    git(&repo2, "rev-parse v1^{commit}")
+        .assert()
+        .stdout(format!("{}\n", tagged));
+
+    delete_bucket_recurse(&client, bucket).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn fast_export_layout() -> Result<()> {
+    let client = create_test_client().await?;
+    let bucket = "git-remote-s3-fast-export";
+
+    let _ = delete_bucket_recurse(&client, bucket).await;
+    create_bucket(&client, bucket).await?;
+
+    let test_dir = setup()?;
+    let repo1 = test_dir.path().join("repo1");
+    let repo2 = test_dir.path().join("repo2");
+    fs::create_dir(&repo1).unwrap();
+
+    info!("test: push to a new fast-export remote");
+    git(&repo1, "init").assert().success();
+    git(&repo1, "config user.email test@example.com")
+        .assert()
+        .success();
+    git(&repo1, "config user.name Test").assert().success();
+    git(&repo1, "branch -M main").assert().success();
+    git(&repo1, "commit --allow-empty -am c1")
+        .assert()
+        .success();
+    git(&repo1, &format!("remote add origin s3://{}/test", bucket))
+        .assert()
+        .success();
+    git(&repo1, "config remote.origin.layout fast-export")
+        .assert()
+        .success();
+    git(&repo1, "push origin main").assert().success();
+    let keys = list_keys_in_bucket(&client, bucket).await?;
+    assert!(keys.contains(&"test/LAYOUT".to_string()));
+    assert!(keys.contains(&"test/fast-export/manifest".to_string()));
+    assert!(!keys.iter().any(|key| key.ends_with(".bundle")));
+
+    info!("test: clone it");
+    git(
+        test_dir.path(),
+        &format!("clone s3://{}/test repo2", bucket),
+    )
+    .assert()
+    .success();
+    git(&repo2, "config user.email test@example.com")
+        .assert()
+        .success();
+    git(&repo2, "config user.name Test2").assert().success();
+    assert_eq!(git_rev_long(&repo2), git_rev_long(&repo1));
+
+    info!("test: push a tag and fetch it");
+    git(&repo1, "tag -a v1 -m v1").assert().success();
+    git(&repo1, "push origin v1").assert().success();
+    git(&repo2, "fetch origin tag v1").assert().success();
+    git(&repo2, "rev-parse v1^{commit}")
+        .assert()
+        .stdout(format!("{}\n", git_rev_long(&repo1)));
+
+    info!("test: a push that isn't a fast-forward is rejected");
+    git(&repo2, "commit --allow-empty -am c2")
+        .assert()
+        .success();
+    git(&repo2, "push origin main").assert().success();
+    let pushed = git_rev_long(&repo2);
+    git(&repo1, "commit --allow-empty -am c2b")
+        .assert()
+        .success();
+    git(&repo1, "push origin main").assert().failure();
+    // A + on the refspec doesn't reach the helper, only --force does
+    git(&repo1, "push origin +main").assert().failure();
+    let ls_remote_output = git(&repo1, "ls-remote origin")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let ls_remote_str = String::from_utf8_lossy(&ls_remote_output);
+    assert!(ls_remote_str.contains(&format!("{}\trefs/heads/main", pushed)));
+    git(&repo1, "push --force origin main").assert().success();
+    git(&repo1, "ls-remote origin refs/heads/main")
+        .assert()
+        .stdout(format!("{}\trefs/heads/main\n", git_rev_long(&repo1)));
+
+    delete_bucket_recurse(&client, bucket).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn shallow_fetches_keep_boundary() -> Result<()> {
+    let client = create_test_client().await?;
+    let bucket = "git-remote-s3-shallow";
+
+    let _ = delete_bucket_recurse(&client, bucket).await;
+    create_bucket(&client, bucket).await?;
+
+    let test_dir = setup()?;
+    let repo1 = test_dir.path().join("repo1");
+    let repo2 = test_dir.path().join("repo2");
+    fs::create_dir(&repo1).unwrap();
+
+    git(&repo1, "init").assert().success();
+    git(&repo1, "config user.email test@example.com")
+        .assert()
+        .success();
+    git(&repo1, "config user.name Test").assert().success();
+    git(&repo1, "branch -M main").assert().success();
+    git(&repo1, &format!("remote add origin s3://{}/test", bucket))
+        .assert()
+        .success();
+    git(&repo1, "config remote.origin.shallowDepth 1")
+        .assert()
+        .success();
+    for message in ["c1", "c2", "c3"] {
+        git(&repo1, &format!("commit --allow-empty -am {}", message))
+            .assert()
+            .success();
+    }
+    git(&repo1, "branch other HEAD~1").assert().success();
+    git(&repo1, "checkout other").assert().success();
+    git(&repo1, "commit --allow-empty -am d1")
+        .assert()
+        .success();
+    git(&repo1, "checkout main").assert().success();
+    git(&repo1, "push origin main other").assert().success();
+
+    info!("test: a second shallow fetch keeps the boundary of the first");
+    git(
+        test_dir.path(),
+        &format!("clone --depth 1 -b main s3://{}/test repo2", bucket),
+    )
+    .assert()
+    .success();
+    git(&repo2, "fetch --depth 1 origin other")
+        .assert()
+        .success();
+    git(&repo2, "fsck").assert().success();
+    let shallow = fs::read_to_string(repo2.join(".git/shallow"))?;
+    assert!(shallow.contains(&git_rev_long(&repo1)));
+
+    info!("test: deepen-since is rejected");
+    git(
+        test_dir.path(),
+        &format!(
+            "clone --shallow-since=2000-01-01 s3://{}/test repo3",
+            bucket
+        ),
+    )
+    .assert()
+    .failure();
+
+    delete_bucket_recurse(&client, bucket).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn gc_bundles() -> Result<()> {
+    let client = create_test_client().await?;
+    let bucket = "git-remote-s3-gc-bundles";
+
+    let _ = delete_bucket_recurse(&client, bucket).await;
+    create_bucket(&client, bucket).await?;
+
+    let test_dir = setup()?;
+    let repo1 = test_dir.path().join("repo1");
+    fs::create_dir(&repo1).unwrap();
+
+    git(&repo1, "init").assert().success();
+    git(&repo1, "config user.email test@example.com")
+        .assert()
+        .success();
+    git(&repo1, "config user.name Test").assert().success();
+    git(&repo1, "branch -M main").assert().success();
+    git(&repo1, &format!("remote add origin s3://{}/test", bucket))
+        .assert()
+        .success();
+
+    info!("test: a head the branch includes is superseded");
+    git(&repo1, "commit --allow-empty -am c1")
+        .assert()
+        .success();
+    git(&repo1, "push origin main").assert().success();
+    let superseded = git_rev_long(&repo1);
+
+    info!("test: a head a thin tag was built on is kept");
+    git(&repo1, "commit --allow-empty -am c2")
+        .assert()
+        .success();
+    git(&repo1, "push origin main").assert().success();
+    let basis = git_rev_long(&repo1);
+    git(&repo1, "tag -a v1 -m v1").assert().success();
+    git(&repo1, "push origin v1").assert().success();
+    git(&repo1, "commit --allow-empty -am c3")
+        .assert()
+        .success();
+    git(&repo1, "push origin main").assert().success();
+
+    info!("test: a head that diverged is kept");
+    git(&repo1, "commit --allow-empty -am c4")
+        .assert()
+        .success();
+    git(&repo1, "push origin main").assert().success();
+    let divergent = git_rev_long(&repo1);
+    git(&repo1, "reset --hard HEAD~1").assert().success();
+    git(&repo1, "commit --allow-empty -am c4b")
+        .assert()
+        .success();
+    git(&repo1, "push -f origin main").assert().success();
+
+    let gc_output = git(&repo1, "s3 gc origin --grace-period=0")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let gc_str = String::from_utf8_lossy(&gc_output);
+    assert!(gc_str.contains(&format!(
+        "kept refs/heads/main {}: basis of refs/tags/v1",
+        &basis[..7]
+    )));
+    assert!(gc_str.contains(&format!(
+        "kept refs/heads/main {}: not included by any branch",
+        &divergent[..7]
+    )));
+
+    let keys = list_keys_in_bucket(&client, bucket).await?;
+    let bundle = |sha: &str| format!("test/refs/heads/main/{}.bundle", sha);
+    assert!(!keys.contains(&bundle(&superseded)));
+    assert!(keys.contains(&bundle(&basis)));
+    assert!(keys.contains(&bundle(&divergent)));
+
+    delete_bucket_recurse(&client, bucket).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn gc_packs() -> Result<()> {
+    let client = create_test_client().await?;
+    let bucket = "git-remote-s3-gc-packs";
+
+    let _ = delete_bucket_recurse(&client, bucket).await;
+    create_bucket(&client, bucket).await?;
+
+    let test_dir = setup()?;
+    let repo1 = test_dir.path().join("repo1");
+    let repo2 = test_dir.path().join("repo2");
+    fs::create_dir(&repo1).unwrap();
+
+    git(&repo1, "init").assert().success();
+    git(&repo1, "config user.email test@example.com")
+        .assert()
+        .success();
+    git(&repo1, "config user.name Test").assert().success();
+    git(&repo1, "branch -M main").assert().success();
+    git(&repo1, &format!("remote add origin s3://{}/test", bucket))
+        .assert()
+        .success();
+    git(&repo1, "config remote.origin.layout packs")
+        .assert()
+        .success();
+    for message in ["c1", "c2", "c3"] {
+        git(&repo1, &format!("commit --allow-empty -am {}", message))
+            .assert()
+            .success();
+        git(&repo1, "push origin main").assert().success();
+    }
+    let packs = |keys: &[String]| {
+        keys.iter()
+            .filter(|key| key.starts_with("test/packs/"))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let pushed = packs(&list_keys_in_bucket(&client, bucket).await?);
+    assert_eq!(pushed.len(), 3);
+
+    info!("test: gc consolidates the packs and keeps the old ones for the grace period");
+    let gc_output = git(&repo1, "s3 gc origin")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let gc_str = String::from_utf8_lossy(&gc_output);
+    assert!(gc_str.contains("consolidated 3 packs into"));
+    for key in &pushed {
+        assert!(gc_str.contains(&format!("pending {}", key)));
+    }
+    assert_eq!(packs(&list_keys_in_bucket(&client, bucket).await?).len(), 4);
+
+    info!("test: a later gc deletes them once the grace period has passed");
+    let gc_output = git(&repo1, "s3 gc origin --grace-period=0")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let gc_str = String::from_utf8_lossy(&gc_output);
+    for key in &pushed {
+        assert!(gc_str.contains(&format!("deleted {}", key)));
+    }
+    let remaining = packs(&list_keys_in_bucket(&client, bucket).await?);
+    assert_eq!(remaining.len(), 1);
+    assert!(!pushed.contains(&remaining[0]));
+
+    info!("test: the consolidated pack clones");
+    git(
+        test_dir.path(),
+        &format!("clone s3://{}/test repo2", bucket),
+    )
+    .assert()
+    .success();
+    assert_eq!(git_rev_long(&repo2), git_rev_long(&repo1));
+
+    delete_bucket_recurse(&client, bucket).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn stale_heads_of_fetched_refs_only() -> Result<()> {
+    let client = create_test_client().await?;
+    let bucket = "git-remote-s3-stale-heads";
+
+    let _ = delete_bucket_recurse(&client, bucket).await;
+    create_bucket(&client, bucket).await?;
+
+    let test_dir = setup()?;
+    let repo1 = test_dir.path().join("repo1");
+    let repo2 = test_dir.path().join("repo2");
+    let repo3 = test_dir.path().join("repo3");
+    fs::create_dir(&repo1).unwrap();
+
+    git(&repo1, "init").assert().success();
+    git(&repo1, "config user.email test@example.com")
+        .assert()
+        .success();
+    git(&repo1, "config user.name Test").assert().success();
+    git(&repo1, "branch -M main").assert().success();
+    git(&repo1, &format!("remote add origin s3://{}/test", bucket))
+        .assert()
+        .success();
+    git(&repo1, "commit --allow-empty -am c1")
+        .assert()
+        .success();
+    git(&repo1, "push origin main").assert().success();
+    git(&repo1, "checkout -b dev").assert().success();
+    git(&repo1, "commit --allow-empty -am d1")
+        .assert()
+        .success();
+    git(&repo1, "push origin dev").assert().success();
+    let stale = git_rev_long(&repo1);
+    let stale_ref = format!("refs/s3/origin/stale/heads/dev__{}", &stale[..7]);
+    git(&repo1, "commit --amend --allow-empty -m d2")
+        .assert()
+        .success();
+    git(&repo1, "push -f origin dev").assert().success();
+
+    info!("test: fetching another ref doesn't download the stale heads of dev");
+    git(
+        test_dir.path(),
+        &format!("clone --single-branch -b main s3://{}/test repo2", bucket),
+    )
+    .assert()
+    .success();
+    git(&repo2, &format!("cat-file -e {}", stale))
+        .assert()
+        .failure();
+    git(&repo2, &format!("show-ref --verify {}", stale_ref))
+        .assert()
+        .failure();
+
+    info!("test: fetching dev downloads them");
+    git(&repo2, "fetch origin dev").assert().success();
+    git(&repo2, &format!("show-ref --verify {}", stale_ref))
+        .assert()
+        .success();
+
+    info!("test: a shallow fetch of dev doesn't");
+    git(
+        test_dir.path(),
+        &format!("clone --depth 1 -b dev s3://{}/test repo3", bucket),
+    )
+    .assert()
+    .success();
+    git(&repo3, &format!("cat-file -e {}", stale))
+        .assert()
+        .failure();
+
+    delete_bucket_recurse(&client, bucket).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn history_and_restore() -> Result<()> {
+    let client = create_test_client().await?;
+    let bucket = "git-remote-s3-history";
+
+    let _ = delete_bucket_recurse(&client, bucket).await;
+    create_bucket(&client, bucket).await?;
+    client
+        .put_bucket_versioning()
+        .bucket(bucket)
+        .versioning_configuration(
+            VersioningConfiguration::builder()
+                .status(BucketVersioningStatus::Enabled)
+                .build(),
+        )
+        .send()
+        .await?;
+
+    let test_dir = setup()?;
+    let repo1 = test_dir.path().join("repo1");
+    fs::create_dir(&repo1).unwrap();
+    let url = format!("s3://{}/test", bucket);
+
+    git(&repo1, "init").assert().success();
+    git(&repo1, "config user.email test@example.com")
+        .assert()
+        .success();
+    git(&repo1, "config user.name Test").assert().success();
+    git(&repo1, "branch -M main").assert().success();
+    git(&repo1, &format!("remote add origin {}", url))
+        .assert()
+        .success();
+    git(
+        &repo1,
+        "config remote.origin.headsStorageClass REDUCED_REDUNDANCY",
+    )
+    .assert()
+    .success();
+    git(&repo1, "commit --allow-empty -am c1")
+        .assert()
+        .success();
+    git(&repo1, "push origin main").assert().success();
+    let sha1 = git_rev_long(&repo1);
+    git(&repo1, "commit --allow-empty -am c2")
+        .assert()
+        .success();
+    git(&repo1, "push origin main").assert().success();
+    git(&repo1, "s3 prune origin").assert().success();
+
+    info!("test: history lists the deleted head");
+    let history_output = git(&repo1, &format!("s3 history {} refs/heads/main", url))
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let history_str = String::from_utf8_lossy(&history_output);
+    assert!(history_str.starts_with("refs/heads/main\n"));
+    assert!(history_str
+        .lines()
+        .any(|line| line.contains(&sha1) && line.ends_with(" deleted")));
+
+    info!("test: restore by url uses the settings of the remote with that url");
+    git(
+        &repo1,
+        &format!("s3 restore {} refs/heads/main {}", url, sha1),
+    )
+    .assert()
+    .success();
+    let ls_remote_output = git(&repo1, "ls-remote origin")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let ls_remote_str = String::from_utf8_lossy(&ls_remote_output);
+    assert!(ls_remote_str.contains(&format!("{}\trefs/heads/main", sha1)));
+
+    let output = client.list_objects_v2().bucket(bucket).send().await?;
+    let restored = output
+        .contents()
+        .unwrap_or_default()
+        .iter()
+        .find(|obj| obj.key() == Some(&format!("test/refs/heads/main/{}.bundle", sha1)))
+        .expect("restored bundle");
+    assert_eq!(
+        restored.storage_class().map(|c| c.as_str()),
+        Some("REDUCED_REDUNDANCY")
+    );
+
+    delete_bucket_recurse(&client, bucket).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_mirror() -> Result<()> {
+    let client = create_test_client().await?;
+    let bucket = "git-remote-s3-local-mirror";
+
+    let _ = delete_bucket_recurse(&client, bucket).await;
+    create_bucket(&client, bucket).await?;
+
+    let test_dir = setup()?;
+    let repo1 = test_dir.path().join("repo1");
+    let repo2 = test_dir.path().join("repo2");
+    fs::create_dir(&repo1).unwrap();
+
+    info!("test: push through a local mirror");
+    git(&repo1, "init").assert().success();
+    git(&repo1, "config user.email test@example.com")
+        .assert()
+        .success();
+    git(&repo1, "config user.name Test").assert().success();
+    git(&repo1, "branch -M main").assert().success();
+    git(&repo1, &format!("remote add origin s3://{}/test", bucket))
+        .assert()
+        .success();
+    git(&repo1, "config remote.origin.localMirror true")
+        .assert()
+        .success();
+    git(&repo1, "commit --allow-empty -am c1")
+        .assert()
+        .success();
+    git(&repo1, "push origin main").assert().success();
+    let pushed = git_rev_long(&repo1);
+    let mirror_head = || {
+        let out = git(
+            &repo1,
+            "--git-dir .git/s3/mirrors/origin rev-parse refs/heads/main",
+        )
+        .output()
+        .unwrap();
+        String::from_utf8(out.stdout).unwrap().trim().to_string()
+    };
+    assert_eq!(mirror_head(), pushed);
+    let keys = list_keys_in_bucket(&client, bucket).await?;
+    assert!(keys
+        .iter()
+        .any(|key| key.starts_with("test/refs/heads/main/")));
+
+    info!("test: clone and fetch through a local mirror");
+    git(
+        test_dir.path(),
+        &format!(
+            "clone -c remote.origin.localMirror=true s3://{}/test repo2",
+            bucket
+        ),
+    )
+    .assert()
+    .success();
+    assert_eq!(git_rev_long(&repo2), pushed);
+    git(&repo1, "commit --allow-empty -am c2")
+        .assert()
+        .success();
+    git(&repo1, "push origin main").assert().success();
+    let pushed = git_rev_long(&repo1);
+    git(&repo2, "fetch origin").assert().success();
+    git(&repo2, "rev-parse origin/main")
+        .assert()
+        .stdout(format!("{}\n", pushed));
+
+    info!("test: a push S3 refuses is rolled back in the mirror");
+    git(&repo1, "config remote.origin.gpgRecipients nobody@invalid")
+        .assert()
+        .success();
+    git(&repo1, "commit --allow-empty -am c3")
+        .assert()
+        .success();
+    git(&repo1, "push origin main").assert().failure();
+    assert_eq!(mirror_head(), pushed);
+    git(&repo1, "ls-remote origin refs/heads/main")
+        .assert()
+        .stdout(format!("{}\trefs/heads/main\n", pushed));
+
+    info!("test: the push goes through once S3 takes it");
+    git(&repo1, "config --unset remote.origin.gpgRecipients")
+        .assert()
+        .success();
+    git(&repo1, "push origin main").assert().success();
+    assert_eq!(mirror_head(), git_rev_long(&repo1));
+
+    delete_bucket_recurse(&client, bucket).await?;
+    Ok(())
+}
+
+/// Full listings of a remote's bucket, counted in the helper's debug log
+fn full_listings(bucket: &str) -> usize {
+    let log = fs::read_to_string("/tmp/git-remote-s3.log").unwrap_or_default();
+    let listing = format!("bucket=\"{}\" prefix=\"test\"", bucket);
+    log.lines().filter(|line| line.contains(&listing)).count()
+}
+
+#[tokio::test]
+async fn push_rechecks_heads() -> Result<()> {
+    let client = create_test_client().await?;
+    let bucket = "git-remote-s3-push-recheck";
+
+    let _ = delete_bucket_recurse(&client, bucket).await;
+    create_bucket(&client, bucket).await?;
+
+    let test_dir = setup()?;
+    let repo1 = test_dir.path().join("repo1");
+    let repo2 = test_dir.path().join("repo2");
+    fs::create_dir(&repo1).unwrap();
+
+    git(&repo1, "init").assert().success();
+    git(&repo1, "config user.email test@example.com")
+        .assert()
+        .success();
+    git(&repo1, "config user.name Test").assert().success();
+    git(&repo1, "branch -M main").assert().success();
+    git(&repo1, &format!("remote add origin s3://{}/test", bucket))
+        .assert()
+        .success();
+    git(&repo1, "commit --allow-empty -am c1")
+        .assert()
+        .success();
+    git(&repo1, "branch a").assert().success();
+    git(&repo1, "branch b").assert().success();
+
+    info!("test: a push of several refs lists the bucket once");
+    let before = full_listings(bucket);
+    git(&repo1, "push origin main a b")
+        .env("RUST_LOG", "git_remote_s3=debug")
+        .assert()
+        .success();
+    assert_eq!(full_listings(bucket), before + 1);
+
+    info!("test: a ref pushed from elsewhere after list is refused");
+    let mut helper = Command::new(cargo_bin("git-remote-s3"))
+        .args(["origin", &format!("s3://{}/test", bucket)])
+        .current_dir(&repo1)
+        .env("GIT_DIR", repo1.join(".git"))
+        .env("S3_ENDPOINT", S3_ENDPOINT)
+        .env("AWS_ACCESS_KEY_ID", S3_ACCESS_KEY)
+        .env("AWS_SECRET_ACCESS_KEY", S3_SECRET_KEY)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = helper.stdin.take().unwrap();
+    let mut stdout = BufReader::new(helper.stdout.take().unwrap());
+    let mut read_until_blank = || {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            stdout.read_line(&mut line).unwrap();
+            if line.trim().is_empty() {
+                return lines;
+            }
+            lines.push(line.trim().to_string());
+        }
+    };
+    writeln!(stdin, "list for-push")?;
+    read_until_blank();
+
+    git(
+        test_dir.path(),
+        &format!("clone -b b s3://{}/test repo2", bucket),
+    )
+    .assert()
+    .success();
+    git(&repo2, "config user.email test@example.com")
+        .assert()
+        .success();
+    git(&repo2, "config user.name Test2").assert().success();
+    git(&repo2, "commit --allow-empty -am d1")
+        .assert()
+        .success();
+    git(&repo2, "push origin b").assert().success();
+
+    for branch in ["a", "b"] {
+        git(&repo1, &format!("checkout {}", branch))
+            .assert()
+            .success();
+        git(&repo1, "commit --allow-empty -am c2")
+            .assert()
+            .success();
+    }
+    writeln!(stdin, "push refs/heads/a:refs/heads/a")?;
+    writeln!(stdin, "push refs/heads/b:refs/heads/b")?;
+    writeln!(stdin)?;
+    let status = read_until_blank();
+    assert_eq!(
+        status,
+        vec!["ok refs/heads/a", "error refs/heads/b fetch first"]
+    );
+    drop(stdin);
+    helper.wait()?;
+
+    git(&repo1, "ls-remote origin refs/heads/b")
+        .assert()
+        .stdout(format!("{}\trefs/heads/b\n", git_rev_long(&repo2)));
+
+    delete_bucket_recurse(&client, bucket).await?;
+    Ok(())
+}
+
+fn stdout_of(pwd: &Path, args: &str) -> String {
+    let output = git(pwd, args)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    String::from_utf8(output).unwrap()
+}
+
+#[tokio::test]
+async fn admin_commands() -> Result<()> {
+    let client = create_test_client().await?;
+    let bucket = "git-remote-s3-admin";
+
+    let _ = delete_bucket_recurse(&client, bucket).await;
+    create_bucket(&client, bucket).await?;
+
+    let test_dir = setup()?;
+    let repo1 = test_dir.path().join("repo1");
+    fs::create_dir(&repo1).unwrap();
+
+    git(&repo1, "init").assert().success();
+    git(&repo1, "config user.email test@example.com")
+        .assert()
+        .success();
+    git(&repo1, "config user.name Test").assert().success();
+    git(&repo1, "branch -M main").assert().success();
+    git(&repo1, &format!("remote add origin s3://{}/test", bucket))
+        .assert()
+        .success();
+    git(&repo1, "commit --allow-empty -am c1")
+        .assert()
+        .success();
+    git(&repo1, "push origin main").assert().success();
+    let stale = git_rev_long(&repo1);
+    git(&repo1, "commit --allow-empty -am c2")
+        .assert()
+        .success();
+    git(&repo1, "push origin main").assert().success();
+    let latest = git_rev_long(&repo1);
+
+    info!("test: ls and heads list every head, newest first");
+    let ls = stdout_of(&repo1, "s3 ls origin");
+    let lines: Vec<&str> = ls.lines().collect();
+    assert_eq!(lines[0], "refs/heads/main");
+    assert!(lines[1].starts_with(&format!("  * {} ", &latest[..7])));
+    assert!(lines[1].ends_with(TEST_EMAIL));
+    assert!(lines[2].starts_with(&format!("    {} ", &stale[..7])));
+    let heads = stdout_of(&repo1, "s3 heads origin refs/heads/main");
+    let lines: Vec<&str> = heads.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with(&latest) && lines[0].ends_with(" latest"));
+    assert!(lines[1].starts_with(&stale) && lines[1].ends_with(" stale"));
+    git(&repo1, "s3 heads origin refs/heads/missing")
+        .assert()
+        .failure();
+
+    info!("test: du groups the objects by ref");
+    let du = stdout_of(&repo1, "s3 du origin");
+    assert!(du.lines().any(|line| line.ends_with("  refs/heads/main")));
+    assert!(du.lines().last().unwrap().ends_with("  total"));
+
+    info!("test: prune deletes the stale head the branch includes");
+    let dry_run = stdout_of(&repo1, "s3 prune origin --dry-run");
+    assert!(dry_run.contains(&format!(
+        "would delete test/refs/heads/main/{}.bundle",
+        stale
+    )));
+    assert_eq!(
+        stdout_of(&repo1, "s3 heads origin refs/heads/main")
+            .lines()
+            .count(),
+        2
+    );
+    let prune = stdout_of(&repo1, "s3 prune origin");
+    assert!(prune.contains(&format!("deleted test/refs/heads/main/{}.bundle", stale)));
+    let heads = stdout_of(&repo1, "s3 heads origin refs/heads/main");
+    assert_eq!(heads.lines().count(), 1);
+    assert!(heads.starts_with(&latest));
+
+    info!("test: ls and heads show the refs of a fast-export remote");
+    git(&repo1, &format!("remote add fx s3://{}/fx", bucket))
+        .assert()
+        .success();
+    git(&repo1, "config remote.fx.layout fast-export")
+        .assert()
+        .success();
+    git(&repo1, "push fx main").assert().success();
+    let ls = stdout_of(&repo1, "s3 ls fx");
+    assert!(ls.starts_with("refs/heads/main\n"));
+    assert!(ls.contains(&format!("  * {} ", &latest[..7])));
+    let heads = stdout_of(&repo1, "s3 heads fx refs/heads/main");
+    assert!(heads.starts_with(&latest) && heads.trim_end().ends_with(" latest"));
+
+    delete_bucket_recurse(&client, bucket).await?;
+    Ok(())
+}
+
+/// The storage class, tags and user metadata of an object
+async fn object_attributes(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+) -> Result<(String, String, HashMap<String, String>)> {
+    let output = client
+        .list_objects_v2()
+        .bucket(bucket)
+        .prefix(key)
+        .send()
+        .await?;
+    let storage_class = output
+        .contents()
+        .unwrap_or_default()
+        .iter()
+        .find(|obj| obj.key() == Some(key))
+        .and_then(|obj| obj.storage_class())
+        .map(|c| c.as_str().to_string())
+        .unwrap_or_default();
+    let tagging = client
+        .get_object_tagging()
+        .bucket(bucket)
+        .key(key)
+        .send()
+        .await?;
+    let tags = tagging
+        .tag_set()
+        .unwrap_or_default()
+        .iter()
+        .map(|tag| {
+            format!(
+                "{}={}",
+                tag.key().unwrap_or_default(),
+                tag.value().unwrap_or_default()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    let head = client.head_object().bucket(bucket).key(key).send().await?;
+    Ok((storage_class, tags, head.metadata.unwrap_or_default()))
+}
+
+#[tokio::test]
+async fn ref_class_attributes() -> Result<()> {
+    let client = create_test_client().await?;
+    let bucket = "git-remote-s3-ref-classes";
+
+    let _ = delete_bucket_recurse(&client, bucket).await;
+    create_bucket(&client, bucket).await?;
+
+    let test_dir = setup()?;
+    let repo1 = test_dir.path().join("repo1");
+    fs::create_dir(&repo1).unwrap();
+
+    git(&repo1, "init").assert().success();
+    git(&repo1, "config user.email test@example.com")
+        .assert()
+        .success();
+    git(&repo1, "config user.name Test").assert().success();
+    git(&repo1, "branch -M main").assert().success();
+    git(&repo1, &format!("remote add origin s3://{}/test", bucket))
+        .assert()
+        .success();
+    for setting in [
+        "tagsStorageClass REDUCED_REDUNDANCY",
+        "staleStorageClass REDUCED_REDUNDANCY",
+        "objectTags team=infra",
+        "staleObjectTags team=infra&state=stale",
+        "objectMetadata owner=infra",
+        "tagsObjectMetadata owner=release",
+        "staleObjectMetadata state=stale",
+    ] {
+        git(&repo1, &format!("config remote.origin.{}", setting))
+            .assert()
+            .success();
+    }
+
+    info!("test: heads and tags are stored with the attributes of their class");
+    git(&repo1, "commit --allow-empty -am c1")
+        .assert()
+        .success();
+    git(&repo1, "push origin main").assert().success();
+    let stale = git_rev_long(&repo1);
+    git(&repo1, "tag -a v1 -m v1").assert().success();
+    git(&repo1, "push origin v1").assert().success();
+    let tag = String::from_utf8(git(&repo1, "rev-parse v1").output()?.stdout)?;
+
+    let head_key = |sha: &str| format!("test/refs/heads/main/{}.bundle", sha);
+    let (class, tags, metadata) = object_attributes(&client, bucket, &head_key(&stale)).await?;
+    assert_eq!(class, "STANDARD");
+    assert_eq!(tags, "team=infra");
+    assert_eq!(metadata.get("owner").map(String::as_str), Some("infra"));
+    assert_eq!(
+        metadata.get("ref").map(String::as_str),
+        Some("refs/heads/main")
+    );
+    let tag_key = format!("test/refs/tags/v1/{}.bundle", tag.trim());
+    let (class, tags, metadata) = object_attributes(&client, bucket, &tag_key).await?;
+    assert_eq!(class, "REDUCED_REDUNDANCY");
+    assert_eq!(tags, "team=infra");
+    assert_eq!(metadata.get("owner").map(String::as_str), Some("release"));
+
+    info!("test: a superseded head is demoted, and stays behind the new head");
+    git(&repo1, "commit --allow-empty -am c2")
+        .assert()
+        .success();
+    git(&repo1, "push origin main").assert().success();
+    let latest = git_rev_long(&repo1);
+
+    let (class, tags, metadata) = object_attributes(&client, bucket, &head_key(&stale)).await?;
+    assert_eq!(class, "REDUCED_REDUNDANCY");
+    assert!(tags.contains("state=stale") && tags.contains("team=infra"));
+    assert_eq!(metadata.get("state").map(String::as_str), Some("stale"));
+    assert_eq!(metadata.get("owner").map(String::as_str), Some("infra"));
+    assert_eq!(metadata.get("pusher").map(String::as_str), Some(TEST_EMAIL));
+    let (class, tags, metadata) = object_attributes(&client, bucket, &head_key(&latest)).await?;
+    assert_eq!(class, "STANDARD");
+    assert_eq!(tags, "team=infra");
+    assert!(!metadata.contains_key("state"));
+    git(&repo1, "ls-remote origin refs/heads/main")
+        .assert()
+        .stdout(format!("{}\trefs/heads/main\n", latest));
+
+    delete_bucket_recurse(&client, bucket).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn clone_default_branch() -> Result<()> {
+    let client = create_test_client().await?;
+    let bucket = "git-remote-s3-default-branch";
+
+    let _ = delete_bucket_recurse(&client, bucket).await;
+    create_bucket(&client, bucket).await?;
+
+    let test_dir = setup()?;
+    let repo1 = test_dir.path().join("repo1");
+    fs::create_dir(&repo1).unwrap();
+
+    git(&repo1, "init").assert().success();
+    git(&repo1, "config user.email test@example.com")
+        .assert()
+        .success();
+    git(&repo1, "config user.name Test").assert().success();
+    git(&repo1, "branch -M trunk").assert().success();
+    git(&repo1, "commit --allow-empty -am c1")
+        .assert()
+        .success();
+    git(&repo1, "branch main").assert().success();
+
+    info!("test: the first push of the local default branch stores HEAD");
+    git(&repo1, &format!("remote add origin s3://{}/stored", bucket))
+        .assert()
+        .success();
+    git(&repo1, "push origin main trunk").assert().success();
+    let keys = list_keys_in_bucket(&client, bucket).await?;
+    assert!(keys.contains(&"stored/HEAD".to_string()));
+    git(
+        test_dir.path(),
+        &format!("clone s3://{}/stored repo2", bucket),
+    )
+    .assert()
+    .success();
+    git(&test_dir.path().join("repo2"), "symbolic-ref HEAD")
+        .assert()
+        .stdout("refs/heads/trunk\n");
+
+    info!("test: without HEAD, clones fall back to main, then master");
+    git(
+        &repo1,
+        &format!("remote add fallback s3://{}/fallback", bucket),
+    )
+    .assert()
+    .success();
+    git(&repo1, "branch master").assert().success();
+    git(&repo1, "branch dev").assert().success();
+    git(&repo1, "push fallback master dev").assert().success();
+    let keys = list_keys_in_bucket(&client, bucket).await?;
+    assert!(!keys.contains(&"fallback/HEAD".to_string()));
+    git(
+        test_dir.path(),
+        &format!("clone s3://{}/fallback repo3", bucket),
+    )
+    .assert()
+    .success();
+    git(&test_dir.path().join("repo3"), "symbolic-ref HEAD")
+        .assert()
+        .stdout("refs/heads/master\n");
+    git(&repo1, "push fallback main").assert().success();
+    git(
+        test_dir.path(),
+        &format!("clone s3://{}/fallback repo4", bucket),
+    )
+    .assert()
+    .success();
+    git(&test_dir.path().join("repo4"), "symbolic-ref HEAD")
+        .assert()
+        .stdout("refs/heads/main\n");
+
+    delete_bucket_recurse(&client, bucket).await?;
+    Ok(())
+}
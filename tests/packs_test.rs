@@ -0,0 +1,94 @@
+use anyhow::Result;
+
+mod common;
+use common::init_test_logging;
+
+use git_remote_s3::packs::{Manifest, ManifestPack, ManifestRef};
+
+fn pack(name: &str, tip: &str, deps: &[&str]) -> ManifestPack {
+    ManifestPack {
+        name: name.to_string(),
+        tips: tip.split(',').map(|tip| tip.to_string()).collect(),
+        deps: deps.iter().map(|dep| dep.to_string()).collect(),
+    }
+}
+
+fn manifest() -> Manifest {
+    let mut manifest = Manifest::default();
+    for r in [
+        ManifestRef {
+            name: "refs/heads/main".to_string(),
+            sha: "c2".to_string(),
+            pack: "p2".to_string(),
+            peeled: None,
+        },
+        ManifestRef {
+            name: "refs/tags/v1".to_string(),
+            sha: "t1".to_string(),
+            pack: "p3".to_string(),
+            peeled: Some("c3".to_string()),
+        },
+    ] {
+        manifest.refs.insert(r.name.clone(), r);
+    }
+    for p in [
+        pack("p1", "c1", &[]),
+        pack("p2", "c2", &["p1"]),
+        pack("p3", "t1", &["p1", "p2"]),
+        pack("p4", "c2,t1", &[]),
+    ] {
+        manifest.packs.insert(p.name.clone(), p);
+    }
+    manifest.garbage.insert("p0".to_string(), 1701925200);
+    manifest
+}
+
+#[test]
+fn test_manifest_roundtrip() -> Result<()> {
+    init_test_logging();
+
+    let manifest = manifest();
+    let text = manifest.to_text();
+    assert!(text.contains("ref refs/tags/v1 t1 p3 c3\n"));
+    assert!(text.contains("pack p3 t1 p1 p2\n"));
+    assert!(text.contains("pack p4 c2,t1\n"));
+    assert!(text.contains("garbage p0 1701925200\n"));
+    assert_eq!(Manifest::parse(&text)?, manifest);
+
+    assert!(Manifest::parse("ref refs/heads/main c1\n").is_err());
+    assert!(Manifest::parse("garbage\n").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_manifest_needed_packs() -> Result<()> {
+    init_test_logging();
+
+    let manifest = manifest();
+    let names = |packs: Vec<&ManifestPack>| {
+        let mut names: Vec<_> = packs.into_iter().map(|p| p.name.clone()).collect();
+        names.sort();
+        names
+    };
+
+    // Nothing present locally: the whole chain, each pack once
+    let needed = manifest.needed_packs("p3", &mut |_| Ok(false))?;
+    assert_eq!(names(needed), ["p1", "p2", "p3"]);
+
+    // A present tip covers its pack and everything it depends on
+    let needed = manifest.needed_packs("p3", &mut |tip| Ok(tip == "c2"))?;
+    assert_eq!(names(needed), ["p1", "p3"]);
+    let needed = manifest.needed_packs("p2", &mut |tip| Ok(tip == "c2"))?;
+    assert!(needed.is_empty());
+
+    // A pack with several tips is only skipped when all of them are present
+    let needed = manifest.needed_packs("p4", &mut |tip| Ok(tip == "c2"))?;
+    assert_eq!(names(needed), ["p4"]);
+    let needed = manifest.needed_packs("p4", &mut |_| Ok(true))?;
+    assert!(needed.is_empty());
+
+    assert!(manifest.needed_packs("p5", &mut |_| Ok(false)).is_err());
+
+    Ok(())
+}
@@ -0,0 +1,55 @@
+use anyhow::Result;
+use std::fs;
+use std::io::Write;
+use tempfile::NamedTempFile;
+
+mod common;
+use common::init_test_logging;
+
+use git_remote_s3::pad::{self, Padding};
+
+#[test]
+fn test_padding_parse() -> Result<()> {
+    init_test_logging();
+
+    assert_eq!("pow2".parse::<Padding>()?, Padding::PowerOfTwo);
+    assert_eq!("4096".parse::<Padding>()?, Padding::Quantum(4096));
+    assert_eq!("64k".parse::<Padding>()?, Padding::Quantum(64 * 1024));
+    assert_eq!("1M".parse::<Padding>()?, Padding::Quantum(1024 * 1024));
+    assert!("0".parse::<Padding>().is_err());
+    assert!("lots".parse::<Padding>().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_pad_unpad() -> Result<()> {
+    init_test_logging();
+
+    for (padding, expected_len) in [(Padding::PowerOfTwo, 1024), (Padding::Quantum(100), 1100)] {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(&[42u8; 1000])?;
+
+        pad::pad(file.path(), &padding)?;
+        assert_eq!(fs::metadata(file.path())?.len(), expected_len);
+
+        pad::unpad(file.path())?;
+        assert_eq!(fs::read(file.path())?, vec![42u8; 1000]);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_unpad_without_trailer() -> Result<()> {
+    init_test_logging();
+
+    // Objects uploaded without padding must come back unchanged
+    let mut file = NamedTempFile::new()?;
+    write!(file, "unpadded content")?;
+
+    pad::unpad(file.path())?;
+    assert_eq!(fs::read_to_string(file.path())?, "unpadded content");
+
+    Ok(())
+}
@@ -7,7 +7,7 @@ use tempfile::NamedTempFile;
 mod common;
 use common::init_test_logging;
 
-use git_remote_s3::s3::{self, Key};
+use git_remote_s3::s3::{self, Attributes, Key, Sse};
 
 const TEST_REGION: &str = "us-east-1";
 const TEST_ENDPOINT: &str = "http://localhost:9001";
@@ -54,10 +54,17 @@ async fn test_s3_operations() -> Result<()> {
         bucket: TEST_BUCKET.to_string(),
         key: "test".to_string(),
     };
-    s3::put(&s3, input_file.path(), &key).await?;
+    s3::put(
+        &s3,
+        input_file.path(),
+        &key,
+        &Sse::None,
+        &Attributes::default(),
+    )
+    .await?;
 
     // Test get
-    s3::get(&s3, output_file.path(), &key).await?;
+    s3::get(&s3, output_file.path(), &key, &Sse::None).await?;
 
     // Verify content
     let content = fs::read_to_string(output_file.path())?;
@@ -68,3 +75,93 @@ async fn test_s3_operations() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_s3_checksums() -> Result<()> {
+    init_test_logging();
+
+    std::env::set_var("AWS_ACCESS_KEY_ID", TEST_ACCESS_KEY);
+    std::env::set_var("AWS_SECRET_ACCESS_KEY", TEST_SECRET_KEY);
+
+    let s3 = s3::create_client(
+        Some(TEST_REGION.to_string()),
+        Some(TEST_ENDPOINT.to_string()),
+    )
+    .await?;
+    ensure_test_bucket(&s3).await?;
+
+    let mut input_file = NamedTempFile::new()?;
+    write!(input_file, "test content")?;
+    let output_file = NamedTempFile::new()?;
+    let key = Key {
+        bucket: TEST_BUCKET.to_string(),
+        key: "test_checksums".to_string(),
+    };
+    s3::put(
+        &s3,
+        input_file.path(),
+        &key,
+        &Sse::None,
+        &Attributes::default(),
+    )
+    .await?;
+
+    // The SHA-256 of the content is recorded in the metadata
+    let head = s3
+        .head_object()
+        .bucket(&key.bucket)
+        .key(&key.key)
+        .send()
+        .await?;
+    let metadata = head.metadata.unwrap_or_default();
+    assert_eq!(
+        metadata.get("sha256").map(String::as_str),
+        Some("6ae8a75555209fd6c44157c0aed8016e763ff435a19cf186f76863140143ff72")
+    );
+    s3::get(&s3, output_file.path(), &key, &Sse::None).await?;
+
+    // Content that doesn't match its recorded checksum is rejected
+    s3.put_object()
+        .bucket(&key.bucket)
+        .key(&key.key)
+        .body("corrupted".as_bytes().to_vec().into())
+        .set_metadata(Some(metadata))
+        .send()
+        .await?;
+    let err = s3::get(&s3, output_file.path(), &key, &Sse::None)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+
+    s3::del(&s3, &key).await?;
+
+    Ok(())
+}
+
+#[test]
+fn test_sse_customer_key_from_file() -> Result<()> {
+    init_test_logging();
+
+    // Raw 32 byte key
+    let mut raw_key = NamedTempFile::new()?;
+    raw_key.write_all(&[7u8; 32])?;
+    assert!(matches!(
+        Sse::customer_from_file(raw_key.path())?,
+        Sse::Customer { key } if key == [7u8; 32]
+    ));
+
+    // Base64 encoded key, with a trailing newline
+    let mut b64_key = NamedTempFile::new()?;
+    writeln!(b64_key, "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=")?;
+    assert!(matches!(
+        Sse::customer_from_file(b64_key.path())?,
+        Sse::Customer { key } if key == [7u8; 32]
+    ));
+
+    // Wrong key length
+    let mut short_key = NamedTempFile::new()?;
+    write!(short_key, "c2hvcnQ=")?;
+    assert!(Sse::customer_from_file(short_key.path()).is_err());
+
+    Ok(())
+}